@@ -1,3 +1,7 @@
+mod token_cipher;
+
+pub use token_cipher::*;
+
 pub struct EncrytionHelper;
 
 impl EncrytionHelper {
@@ -0,0 +1,138 @@
+use std::fmt::{Display, Formatter};
+
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+};
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    MissingKey,
+    /// The key must be 32 bytes written as 64 hex characters.
+    InvalidKey,
+    Malformed,
+    /// Wrong key, or the ciphertext was tampered with.
+    Decrypt,
+}
+
+impl Display for EncryptionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncryptionError::MissingKey => f.write_str("TOKEN_ENCRYPTION_KEY is not set"),
+            EncryptionError::InvalidKey => f.write_str("TOKEN_ENCRYPTION_KEY must be 64 hex characters"),
+            EncryptionError::Malformed => f.write_str("malformed ciphertext"),
+            EncryptionError::Decrypt => f.write_str("failed to decrypt"),
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
+/// AES-256-GCM for secrets kept in the database, stored as hex `nonce || ciphertext`.
+pub struct TokenCipher {
+    cipher: Aes256Gcm,
+}
+
+impl TokenCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
+
+    /// Reads the key from `TOKEN_ENCRYPTION_KEY`.
+    pub fn from_env() -> Result<Self, EncryptionError> {
+        let key = std::env::var("TOKEN_ENCRYPTION_KEY").map_err(|_| EncryptionError::MissingKey)?;
+        let key: [u8; 32] = hex::decode(key.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or(EncryptionError::InvalidKey)?;
+        Ok(Self::new(&key))
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .expect("AES-GCM encryption of an in-memory buffer cannot fail");
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+        hex::encode(data)
+    }
+
+    pub fn decrypt(&self, data: &str) -> Result<String, EncryptionError> {
+        let data = hex::decode(data).map_err(|_| EncryptionError::Malformed)?;
+        if data.len() < NONCE_LEN {
+            return Err(EncryptionError::Malformed);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EncryptionError::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| EncryptionError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> TokenCipher {
+        TokenCipher::new(&[7; 32])
+    }
+
+    #[test]
+    fn round_trip() {
+        let cipher = cipher();
+        let encrypted = cipher.encrypt("refresh-token");
+        assert_ne!(encrypted, "refresh-token");
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), "refresh-token");
+        assert_eq!(cipher.decrypt(&cipher.encrypt("")).unwrap(), "");
+    }
+
+    #[test]
+    fn every_encryption_uses_a_fresh_nonce() {
+        let cipher = cipher();
+        assert_ne!(cipher.encrypt("token"), cipher.encrypt("token"));
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let cipher = cipher();
+        let mut data = hex::decode(cipher.encrypt("access-token")).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(matches!(
+            cipher.decrypt(&hex::encode(&data)),
+            Err(EncryptionError::Decrypt)
+        ));
+
+        let mut data = hex::decode(cipher.encrypt("access-token")).unwrap();
+        data[0] ^= 1;
+        assert!(matches!(
+            cipher.decrypt(&hex::encode(&data)),
+            Err(EncryptionError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let encrypted = cipher().encrypt("access-token");
+        assert!(matches!(
+            TokenCipher::new(&[8; 32]).decrypt(&encrypted),
+            Err(EncryptionError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn malformed_ciphertext_is_rejected() {
+        let cipher = cipher();
+        assert!(matches!(cipher.decrypt("not hex"), Err(EncryptionError::Malformed)));
+        assert!(matches!(cipher.decrypt("00ff"), Err(EncryptionError::Malformed)));
+    }
+}
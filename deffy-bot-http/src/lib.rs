@@ -19,7 +19,8 @@ pub async fn http_init() -> Result<(), anyhow::Error> {
 
 async fn start_http() -> Result<(), std::io::Error> {
     let app = Router::new().route("/", get(root))
-    .nest("/patreon/webhook", routes::patreon_webhook::routes().await);
+    .nest("/patreon/webhook", routes::patreon_webhook::routes().await)
+    .nest("/patreon/oauth", routes::patreon_oauth::routes().await);
 
     let addr_str = std::env::var("HTTP_ADDR").unwrap_or_else(|_| "127.0.0.1:10000".to_string());
 
@@ -1,2 +1,3 @@
 pub mod patreon_webhook;
+pub mod patreon_oauth;
 pub mod api;
\ No newline at end of file
@@ -0,0 +1,142 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    Router,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+};
+use deffy_bot_patreon_services::{
+    OAuthCallback, OAuthState, PatreonApi, PatreonError, PatreonOAuth, Scopes, UsedNonces,
+};
+use deffy_bot_utils::link_database::{PatreonLink, PatreonLinkDatabase};
+use deffy_bot_utils::token_database::TokenDatabase;
+use serde::Deserialize;
+
+/// How long the user has between opening the login link and coming back from Patreon.
+const STATE_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// How long a login link from `/link_patreon` can be opened, a leaked one stops working
+/// after this.
+const LOGIN_LINK_MAX_AGE: Duration = Duration::from_secs(15 * 60);
+
+struct OAuthConfig {
+    oauth: PatreonOAuth,
+    state_secret: String,
+    used_nonces: UsedNonces,
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    discord_id: u64,
+    /// Unix seconds the link was made at.
+    issued_at: i64,
+    sig: String,
+}
+
+pub async fn routes() -> Router {
+    let config = Arc::new(OAuthConfig {
+        oauth: PatreonOAuth::from_env(),
+        state_secret: std::env::var("OAUTH_STATE_SECRET").unwrap_or_default(),
+        used_nonces: UsedNonces::default(),
+    });
+
+    if config.oauth.client_id.is_empty() || config.state_secret.is_empty() {
+        tracing::warn!(
+            "PATREON_CLIENT_ID or OAUTH_STATE_SECRET is not set, Patreon account linking is disabled"
+        );
+    }
+
+    Router::new()
+        .route("/login", get(login))
+        .route("/callback", get(callback))
+        .with_state(config)
+}
+
+async fn login(State(config): State<Arc<OAuthConfig>>, Query(query): Query<LoginQuery>) -> Response {
+    if config.oauth.client_id.is_empty() || config.state_secret.is_empty() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Account linking is not configured").into_response();
+    }
+
+    let secret = config.state_secret.as_bytes();
+    if let Err(e) = OAuthState::verify_login(
+        secret,
+        query.discord_id,
+        query.issued_at,
+        &query.sig,
+        LOGIN_LINK_MAX_AGE,
+    ) {
+        tracing::warn!(discord_id = query.discord_id, "Rejected OAuth login link: {}", e);
+        return (StatusCode::FORBIDDEN, "This link is not valid or expired, run the command again")
+            .into_response();
+    }
+
+    let state = OAuthState::new(query.discord_id).encode(secret);
+    Redirect::to(&config.oauth.get_authorization_url(&Scopes::identity(), &state)).into_response()
+}
+
+async fn callback(
+    State(config): State<Arc<OAuthConfig>>,
+    Query(query): Query<OAuthCallback>,
+) -> Response {
+    let secret = config.state_secret.as_bytes();
+    let (code, state) = match query.validate(secret, STATE_MAX_AGE, &config.used_nonces) {
+        Ok(validated) => validated,
+        Err(PatreonError::AuthorizationDenied(reason)) => {
+            tracing::info!("Patreon OAuth denied: {}", reason);
+            return (StatusCode::BAD_REQUEST, "Patreon login was cancelled").into_response();
+        }
+        Err(e) => {
+            tracing::warn!("Rejected OAuth callback: {}", e);
+            return (StatusCode::BAD_REQUEST, "This login link expired or was already used, run the command again")
+                .into_response();
+        }
+    };
+
+    match link_account(&config.oauth, &code, state.discord_id).await {
+        Ok(link) => {
+            tracing::info!(
+                discord_id = link.discord_id,
+                patreon_user_id = link.patreon_user_id,
+                "Linked Patreon account"
+            );
+            (
+                StatusCode::OK,
+                format!(
+                    "✅ Linked Patreon account {} to your Discord account, you can close this tab.",
+                    link.patreon_full_name
+                ),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to link Patreon account: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Could not link your Patreon account, try again later")
+                .into_response()
+        }
+    }
+}
+
+async fn link_account(oauth: &PatreonOAuth, code: &str, discord_id: u64) -> Result<PatreonLink, anyhow::Error> {
+    let tokens = oauth.get_tokens(code).await?;
+
+    let api = PatreonApi {
+        access_token: tokens.access_token.clone(),
+        base_url: oauth.base_url.clone(),
+        agent: oauth.agent.clone(),
+        ..Default::default()
+    };
+    let (user, members) = api.identity_include_memberships().await?;
+
+    let link = PatreonLink::new(discord_id, &user, &members);
+    PatreonLinkDatabase::upsert_link(link.clone()).await?;
+
+    // The link itself doesn't need the tokens, so a missing encryption key only costs
+    // the features built on `get_valid_token`
+    if let Err(e) = TokenDatabase::store_tokens(discord_id, &tokens).await {
+        tracing::error!("Failed to store Patreon tokens for {}: {:?}", discord_id, e);
+    }
+
+    Ok(link)
+}
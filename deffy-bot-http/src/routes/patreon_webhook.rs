@@ -1,24 +1,49 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use axum::{body::Bytes, extract::State, http::{HeaderMap, StatusCode}, response::IntoResponse, routing::post, Router};
-use deffy_bot_patreon_services::{Event, Webhook};
-use deffy_bot_utils::{database::DatabaseManager, event::manager::EVENT_MANAGER};
-use deffy_bot_utils::event::manager::EventTypeData::PatreonMemberData;
+use deffy_bot_patreon_services::{SignatureAlgorithm, SignatureRejection, Webhook};
+use deffy_bot_utils::database::DatabaseManager;
+use deffy_bot_utils::webhook_database::{InboxDelivery, WebhookDeliveryDatabase, WebhookInboxDatabase};
+
+struct WebhookState {
+    webhook: Webhook,
+    rejections: Mutex<HashMap<SignatureRejection, u64>>,
+}
+
+impl WebhookState {
+    /// Counts the rejection and returns how many times this reason has been seen.
+    fn reject(&self, reason: SignatureRejection) -> u64 {
+        let mut rejections = self.rejections.lock().unwrap();
+        let count = rejections.entry(reason).or_default();
+        *count += 1;
+        *count
+    }
+}
 
 pub async fn routes() -> Router {
 
-    let webhook_secret = std::env::var("PATREON_WEBHOOK_SECRET".to_string())
+    // Comma separated so a new secret can be added before the old one is removed
+    let webhook_secret = std::env::var("PATREON_WEBHOOK_SECRET")
         .expect("PATREON_WEBHOOK_SECRET must be set in the environment variables");
-    
-    let webhook = Arc::new(Webhook {
-        webhook_secret: webhook_secret,
+
+    let algorithm = match std::env::var("PATREON_WEBHOOK_SIGNATURE_ALGORITHM") {
+        Ok(algorithm) if algorithm.eq_ignore_ascii_case("sha256") => SignatureAlgorithm::HmacSha256,
+        _ => SignatureAlgorithm::HmacMd5,
+    };
+
+    let webhook = Webhook::new(webhook_secret.split(',').map(str::trim)).with_algorithm(algorithm);
+
+    let state = Arc::new(WebhookState {
+        webhook,
+        rejections: Mutex::new(HashMap::new()),
     });
 
-    Router::new().route("/", post(root).with_state(webhook))
+    Router::new().route("/", post(root).with_state(state))
 }
 
 async fn root(
-    State(state): State<Arc<Webhook>>,
+    State(state): State<Arc<WebhookState>>,
     headers: HeaderMap,
     body: Bytes
 ) -> impl IntoResponse {
@@ -31,47 +56,64 @@ async fn root(
         .and_then(|v| v.to_str().ok())
         .unwrap_or("");
 
-    match (signature.is_empty(), trigger.is_empty()) {
-        (true, _) => return (StatusCode::BAD_REQUEST, "Missing signature header"),
-        (_, true) => return (StatusCode::BAD_REQUEST, "Missing event header"),
-        _ => {}
+    match state.webhook.verify_signature(&body, signature) {
+        Ok(index) => tracing::trace!("Signature is valid (secret #{})", index),
+        Err(reason) => {
+            let count = state.reject(reason);
+            tracing::warn!(reason = reason.as_str(), count, trigger, "Rejected Patreon webhook");
+            return match reason {
+                SignatureRejection::NoSecretConfigured => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"),
+                SignatureRejection::MissingSignature => (StatusCode::BAD_REQUEST, "Missing signature header"),
+                SignatureRejection::MalformedSignature | SignatureRejection::Mismatch => (StatusCode::UNAUTHORIZED, "Invalid signature"),
+            };
+        }
     }
 
-    match state.check_signature(&body, signature) {
-        Ok(true) => tracing::trace!("Signature is valid"),
-        Ok(false) => {
-            tracing::error!("Invalid signature: {}", signature);
-            return (StatusCode::UNAUTHORIZED, "Invalid signature");
-        }
-        Err(e) => {
-            tracing::error!("Error checking signature: {:?}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error");
-        }
+    if trigger.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Missing event header");
     }
 
-    DatabaseManager::force_update_patreon_data();
-
-    match state.parse_event(&body, trigger) {
-        Ok(event) => {
-            match event {
-                Event::CreateMember(member) => {
-                    EVENT_MANAGER.lock().await.emit(deffy_bot_utils::event::manager::EventType::PatreonWebhookUserCreated,PatreonMemberData(member)).await;
-                    
-                }
-                Event::UpdateMember(member) => {
-                    EVENT_MANAGER.lock().await.emit(deffy_bot_utils::event::manager::EventType::PatreonWebhookUserUpdated,PatreonMemberData(member)).await;
-                    
-                }
-                Event::DeleteMember(member) => {
-                    EVENT_MANAGER.lock().await.emit(deffy_bot_utils::event::manager::EventType::PatreonWebhookUserDeleted,PatreonMemberData(member)).await;
-                }
-                _ => tracing::trace!("ℹ️ Other event: {:?}", event),
-            }
-            (StatusCode::OK, "OK")
-        }
+    let event = match state.webhook.parse_event(&body, trigger) {
+        Ok(event) => event,
         Err(e) => {
+            DatabaseManager::force_update_patreon_data();
+
             tracing::error!("⚠️ Error parsing event: {:?}", e);
-            (StatusCode::BAD_REQUEST, "Invalid event")
+            return (StatusCode::BAD_REQUEST, "Invalid event");
+        }
+    };
+
+    // Without a database (debug runs) there is nowhere to queue the delivery
+    if !DatabaseManager::is_initialized() {
+        if let Err(e) = WebhookInboxDatabase::process(event, &mut Vec::new()).await {
+            tracing::error!("Failed to process webhook delivery: {:?}", e);
+        }
+        return (StatusCode::OK, "OK");
+    }
+
+    let fingerprint = event.fingerprint(&body);
+    match WebhookDeliveryDatabase::record_delivery(&fingerprint, event.trigger(), event.resource_id()).await {
+        Ok(true) => {}
+        Ok(false) => {
+            // Still 200, otherwise Patreon keeps retrying the same delivery
+            tracing::info!(trigger, resource_id = event.resource_id(), "Skipping duplicate Patreon webhook delivery");
+            return (StatusCode::OK, "Duplicate");
         }
+        Err(e) => tracing::error!("Failed to record webhook delivery, processing anyway: {:?}", e),
+    }
+
+    let delivery = InboxDelivery::new(
+        fingerprint.clone(),
+        trigger.to_string(),
+        String::from_utf8_lossy(&body).into_owned(),
+    );
+
+    if let Err(e) = WebhookInboxDatabase::enqueue(delivery).await {
+        tracing::error!("Failed to store webhook delivery: {:?}", e);
+        // Let Patreon's retry through the duplicate check
+        let _ = WebhookDeliveryDatabase::forget_delivery(&fingerprint).await;
+        return (StatusCode::SERVICE_UNAVAILABLE, "Try again later");
     }
+
+    (StatusCode::OK, "OK")
 }
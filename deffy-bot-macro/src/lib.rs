@@ -227,6 +227,10 @@ pub fn event_handle(attr: TokenStream, item: TokenStream) -> TokenStream {
             async fn handle(&self, data: deffy_bot_utils::event::manager::EventTypeData) -> Result<(), anyhow::Error> {
                 #func_name(data).await.map_err(|e| e.into())
             }
+
+            fn name(&self) -> &'static str {
+                concat!(module_path!(), "::", stringify!(#func_name))
+            }
         }
 
         impl EventInfo for #struct_name {
@@ -1,77 +1,264 @@
-use crate::{ApiError, PatreonError, PatreonResult};
+use crate::{
+    parse_retry_after, ApiError, CampaignDetails, CampaignSelector, PatreonError, PatreonResult,
+    RequestBudget, RetryPolicy, Transport,
+};
 use chrono::{DateTime, Utc};
+use futures::{Stream, TryStreamExt};
+use reqwest::Method;
 use serde_derive::{Deserialize, Serialize};
-use std::{env, sync::Arc};
+use std::{collections::HashMap, sync::Arc};
 use url::Url;
 
-static BASE_URI: &str = "https://www.patreon.com";
+pub(crate) static BASE_URI: &str = "https://www.patreon.com";
 
-#[derive(Debug, Default)]
+/// Appends `path` to the path of `base`, so a base url like `http://host/mock` keeps its prefix.
+pub(crate) fn join_path(base: &Url, path: &str) -> Url {
+    let mut url = base.clone();
+    let prefix = base.path().trim_end_matches('/');
+    url.set_path(&format!("{prefix}/{}", path.trim_start_matches('/')));
+    url
+}
+
+#[derive(Debug, Clone)]
 pub struct PatreonApi {
     pub access_token: String,
-    pub agent: Arc<reqwest::Client>,
+    /// Root every endpoint is resolved against, `https://www.patreon.com` by default.
+    pub base_url: Url,
+    /// Campaign the member endpoints read from, see `resolve_campaign`.
+    pub campaign_id: Option<String>,
+    pub agent: Arc<dyn Transport>,
+    pub retry_policy: RetryPolicy,
+    pub budget: Arc<RequestBudget>,
+}
+
+impl Default for PatreonApi {
+    fn default() -> Self {
+        Self {
+            access_token: String::new(),
+            base_url: Url::parse(BASE_URI).unwrap(),
+            campaign_id: None,
+            agent: Arc::new(reqwest::Client::new()),
+            retry_policy: RetryPolicy::default(),
+            budget: Arc::new(RequestBudget::default()),
+        }
+    }
 }
 
 impl PatreonApi {
-    pub async fn current_user(&self) -> PatreonResult<User> {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("/api/oauth2/api/current_user");
-        let request = self.agent.get(url);
-        self.call_data(request).await
+    pub fn new(access_token: impl Into<String>) -> Self {
+        Self {
+            access_token: access_token.into(),
+            ..Default::default()
+        }
     }
 
-    pub async fn all_members(&self) -> PatreonResult<Vec<Member>> {
-        let mut url = Url::parse(BASE_URI).unwrap();
+    pub fn with_base_url(mut self, base_url: &str) -> PatreonResult<Self> {
+        self.base_url = Url::parse(base_url)
+            .map_err(|e| PatreonError::Message(format!("invalid base url {base_url}: {e}")))?;
+        Ok(self)
+    }
 
-        let campaign_id = env::var("CAMPAIGN_ID").expect("Expected a token in the environment").to_string();
+    pub fn with_transport(mut self, agent: Arc<dyn Transport>) -> Self {
+        self.agent = agent;
+        self
+    }
 
-        url.set_path(format!("/api/oauth2/v2/campaigns/{campaign_id}/members").as_str());
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
 
-        url.query_pairs_mut()
-            .append_pair("fields[member]", "full_name,email,patron_status,last_charge_date,last_charge_status,lifetime_support_cents,next_charge_date,is_follower");
-        let request = self.agent.get(url);
+    pub fn with_budget(mut self, budget: RequestBudget) -> Self {
+        self.budget = Arc::new(budget);
+        self
+    }
 
-        let first: PatreonResult<(Vec<Member>, Link<String>)> = self.call_data_and_link(request).await;
+    pub fn with_campaign_id(mut self, campaign_id: impl Into<String>) -> Self {
+        self.campaign_id = Some(campaign_id.into());
+        self
+    }
 
-        let mut all_data = Vec::new();
-        let (members, mut link) = match first {
-            Ok(res) => res,
-            Err(e) => return Err(e),
-        };
+    fn endpoint(&self, path: &str) -> Url {
+        join_path(&self.base_url, path)
+    }
 
-        all_data.extend(members);
+    fn campaign_id(&self) -> PatreonResult<&str> {
+        self.campaign_id
+            .as_deref()
+            .ok_or(PatreonError::NoCampaignSelected)
+    }
 
-        while let Some(next_url) = link.next {
-            if next_url.is_empty() {
-                break;
+    /// Lists every campaign the token can access, with tiers and benefits.
+    pub async fn campaigns(&self) -> PatreonResult<Vec<CampaignDetails>> {
+        let mut campaigns = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next_cursor) = self.campaigns_page(cursor.as_deref()).await?;
+            campaigns.extend(page);
+            match next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(campaigns),
             }
+        }
+    }
 
-            //println!("Next page available at: {}", next_url);
+    async fn campaigns_page(
+        &self,
+        cursor: Option<&str>,
+    ) -> PatreonResult<(Vec<CampaignDetails>, Option<String>)> {
+        let mut url = self.endpoint("/api/oauth2/v2/campaigns");
+        url.query_pairs_mut()
+            .append_pair("include", "tiers,benefits")
+            .append_pair("fields[campaign]", "created_at,creation_name,discord_server_id,image_small_url,image_url,is_charged_immediately,is_monthly,is_nsfw,one_liner,patron_count,pay_per_name,pledge_url,published_at,summary,url,vanity")
+            .append_pair("fields[tier]", "title,amount_cents,description,discord_role_ids,published,patron_count,url")
+            .append_pair("fields[benefit]", "title,description,benefit_type,rule_type,is_deleted,is_published,tiers_count,created_at");
+        if let Some(cursor) = cursor {
+            url.query_pairs_mut().append_pair("page[cursor]", cursor);
+        }
+        let request = reqwest::Request::new(Method::GET, url);
 
-            // ส่งคำขอใหม่ด้วย next_url
-            let next_request = Url::parse(&next_url).unwrap();
-            let request = self.agent.get(next_request);
-            let result: PatreonResult<(Vec<Member>, Link<String>)> = self.call_data_and_link(request).await;
+        let response: DocResponseWithLink<Vec<Campaign>, Link<String>, PageMeta> =
+            self.call_data_and_link(request).await?;
+        let next_cursor = response
+            .meta
+            .and_then(|meta| meta.pagination.cursors.next)
+            .or_else(|| {
+                response
+                    .links
+                    .and_then(|link| link.next)
+                    .as_deref()
+                    .and_then(cursor_from_link)
+            })
+            .filter(|cursor| !cursor.is_empty());
+        let included = Included::parse(response.included);
 
-            match result {
-                Ok((new_members, new_link)) => {
-                    all_data.extend(new_members);
-                    link = new_link;
+        let campaigns = response
+            .data
+            .into_iter()
+            .map(|campaign| {
+                let tier_ids = campaign.related_ids("tiers");
+                let benefit_ids = campaign.related_ids("benefits");
+                let tiers = included
+                    .tiers
+                    .iter()
+                    .filter(|tier| tier_ids.contains(&tier.id.as_str()))
+                    .cloned()
+                    .collect();
+                let benefits = included
+                    .benefits
+                    .iter()
+                    .filter(|benefit| benefit_ids.contains(&benefit.id.as_str()))
+                    .cloned()
+                    .collect();
+                CampaignDetails {
+                    campaign,
+                    tiers,
+                    benefits,
                 }
-                Err(e) => return Err(e),
-            }
+            })
+            .collect();
+        Ok((campaigns, next_cursor))
+    }
+
+    /// Finds the campaign matching `selector` and uses it for the member endpoints from now on.
+    pub async fn resolve_campaign(
+        &mut self,
+        selector: &CampaignSelector,
+    ) -> PatreonResult<CampaignDetails> {
+        let campaigns = self.campaigns().await?;
+        let selected = selector.select(&campaigns)?.clone();
+        self.campaign_id = Some(selected.campaign.id.clone());
+        Ok(selected)
+    }
+
+    pub async fn current_user(&self) -> PatreonResult<User> {
+        let url = self.endpoint("/api/oauth2/api/current_user");
+        let request = reqwest::Request::new(Method::GET, url);
+        self.call_data(request).await
+    }
+
+    pub async fn all_members(&self) -> PatreonResult<Vec<Member>> {
+        self.members_stream(None)
+            .try_fold(Vec::new(), |mut all_data, page| async move {
+                all_data.extend(page.members);
+                Ok(all_data)
+            })
+            .await
+    }
+
+    /// Yields the campaign members one page at a time.
+    ///
+    /// Pass the `next_cursor` of the last page that was handled to pick up where a previous
+    /// stream stopped.
+    pub fn members_stream(
+        &self,
+        cursor: Option<String>,
+    ) -> impl Stream<Item = PatreonResult<MembersPage>> + '_ {
+        futures::stream::try_unfold(Some(cursor), move |state| async move {
+            let Some(cursor) = state else {
+                return Ok(None);
+            };
+            let page = self.members_page(cursor.as_deref()).await?;
+            let next = page.next_cursor.clone().map(Some);
+            Ok(Some((page, next)))
+        })
+    }
+
+    pub async fn members_page(&self, cursor: Option<&str>) -> PatreonResult<MembersPage> {
+        let campaign_id = self.campaign_id()?;
+
+        let mut url = self.endpoint(format!("/api/oauth2/v2/campaigns/{campaign_id}/members").as_str());
+
+        url.query_pairs_mut()
+            .append_pair("include", "currently_entitled_tiers,user")
+            .append_pair("fields[member]", "full_name,email,patron_status,last_charge_date,last_charge_status,lifetime_support_cents,next_charge_date,is_follower,campaign_lifetime_support_cents,currently_entitled_amount_cents,note,pledge_cadence,pledge_relationship_start,will_pay_amount_cents")
+            .append_pair("fields[tier]", "title,amount_cents,description,discord_role_ids,published,patron_count,url")
+            .append_pair("fields[user]", "first_name,last_name,full_name,vanity,email,image_url,thumb_url,created,url,social_connections");
+        if let Some(cursor) = cursor {
+            url.query_pairs_mut().append_pair("page[cursor]", cursor);
         }
+        let request = reqwest::Request::new(Method::GET, url);
+
+        let response: DocResponseWithLink<Vec<Member>, Link<String>, PageMeta> =
+            self.call_data_and_link(request).await?;
+        let meta = response.meta.unwrap_or_default();
+
+        // ส่งคำขอใหม่ด้วย cursor ของหน้าถัดไป
+        let next_cursor = meta
+            .pagination
+            .cursors
+            .next
+            .or_else(|| {
+                response
+                    .links
+                    .and_then(|link| link.next)
+                    .as_deref()
+                    .and_then(cursor_from_link)
+            })
+            .filter(|cursor| !cursor.is_empty());
 
-        PatreonResult::Ok(all_data)
+        let included = Included::parse(response.included);
+
+        Ok(MembersPage {
+            members: response.data,
+            tiers: included.tiers,
+            users: included.users,
+            next_cursor,
+            total: meta.pagination.total,
+        })
     }
 
     pub async fn identity(&self) -> PatreonResult<User> {
         self.call_data(self.identity_request(None)).await
     }
 
+    /// The token owner and their memberships; each member links its campaign under
+    /// `relationships.campaign`.
     pub async fn identity_include_memberships(&self) -> PatreonResult<(User, Vec<Member>)> {
-        self.call_data(self.identity_request(IdentityIncldue::Memberships))
-            .await
+        let response: DocResponseWithLink<User, Link<String>, PageMeta> = self
+            .call_data_and_link(self.identity_request(IdentityIncldue::Memberships))
+            .await?;
+        Ok((response.data, Included::parse(response.included).members))
     }
 
     pub async fn identity_include_campaign(&self) -> PatreonResult<(User, Vec<Campaign>)> {
@@ -82,22 +269,22 @@ impl PatreonApi {
     fn identity_request(
         &self,
         include: impl Into<Option<IdentityIncldue>>,
-    ) -> reqwest::RequestBuilder {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("api/oauth2/v2/identity");
+    ) -> reqwest::Request {
+        let mut url = self.endpoint("api/oauth2/v2/identity");
         let include = include.into();
         if let Some(include) = include {
             // url.query_pairs_mut()
             //     .append_pair("include", include.as_str());
             match include {
                 IdentityIncldue::Memberships => {
-
-                    let campaign_id = env::var("CAMPAIGN_ID").expect("Expected a token in the environment").to_string();
-
-                    url.set_path(format!("/api/oauth2/v2/campaigns/{campaign_id}/members").as_str());
-
                     url.query_pairs_mut()
-                        .append_pair("fields[member]", "full_name");
+                        .append_pair("include", "memberships,memberships.campaign");
+                    url.query_pairs_mut().append_pair(
+                    "fields[user]",
+                    "first_name,last_name,full_name,vanity,email,image_url,thumb_url,created,url,social_connections",
+                     );
+                    url.query_pairs_mut()
+                        .append_pair("fields[member]", "full_name,email,patron_status,last_charge_date,last_charge_status,lifetime_support_cents,currently_entitled_amount_cents,next_charge_date,is_follower");
                 }
                 IdentityIncldue::Campaign => {
                     url.query_pairs_mut()
@@ -112,52 +299,167 @@ impl PatreonApi {
                 }
             }
         }
-        self.agent.get(url)
+        reqwest::Request::new(Method::GET, url)
     }
 
-    pub async fn webhooks(&self) -> PatreonResult<String> {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("api/oauth2/v2/webhooks");
-        self.api_call(self.webhooks_request(None)).await
+    /// Webhooks registered by this client; each links its campaign under `relationships.campaign`.
+    pub async fn webhooks(&self) -> PatreonResult<Vec<PatreonWebhook>> {
+        self.call_data(self.webhooks_request(WebhookIncldue::Campaign))
+            .await
+    }
+
+    /// Registers `uri` for `triggers` on the selected campaign. The returned `secret` signs
+    /// every delivery.
+    pub async fn create_webhook(
+        &self,
+        uri: &str,
+        triggers: &[WebhookTrigger],
+    ) -> PatreonResult<PatreonWebhook> {
+        let campaign_id = self.campaign_id()?;
+        let body = serde_json::json!({
+            "data": {
+                "type": "webhook",
+                "attributes": {
+                    "triggers": triggers,
+                    "uri": uri,
+                },
+                "relationships": {
+                    "campaign": {
+                        "data": { "type": "campaign", "id": campaign_id },
+                    },
+                },
+            },
+        });
+        let url = self.endpoint("api/oauth2/v2/webhooks");
+        self.call_data(json_request(Method::POST, url, &body)).await
+    }
+
+    pub async fn update_webhook(
+        &self,
+        webhook_id: &str,
+        update: &WebhookUpdate,
+    ) -> PatreonResult<PatreonWebhook> {
+        let body = serde_json::json!({
+            "data": {
+                "type": "webhook",
+                "id": webhook_id,
+                "attributes": update,
+            },
+        });
+        let url = self.endpoint(format!("api/oauth2/v2/webhooks/{webhook_id}").as_str());
+        self.call_data(json_request(Method::PATCH, url, &body)).await
+    }
+
+    pub async fn update_webhook_triggers(
+        &self,
+        webhook_id: &str,
+        triggers: &[WebhookTrigger],
+    ) -> PatreonResult<PatreonWebhook> {
+        let update = WebhookUpdate {
+            triggers: Some(triggers.to_vec()),
+            ..Default::default()
+        };
+        self.update_webhook(webhook_id, &update).await
+    }
+
+    /// Resumes a webhook Patreon paused after too many failed deliveries; missed events are
+    /// sent again.
+    pub async fn unpause_webhook(&self, webhook_id: &str) -> PatreonResult<PatreonWebhook> {
+        let update = WebhookUpdate {
+            paused: Some(false),
+            ..Default::default()
+        };
+        self.update_webhook(webhook_id, &update).await
+    }
+
+    pub async fn delete_webhook(&self, webhook_id: &str) -> PatreonResult<()> {
+        let url = self.endpoint(format!("api/oauth2/v2/webhooks/{webhook_id}").as_str());
+        self.api_call(reqwest::Request::new(Method::DELETE, url))
+            .await?;
+        Ok(())
     }
 
     fn webhooks_request(
         &self,
         include: impl Into<Option<WebhookIncldue>>,
-    ) -> reqwest::RequestBuilder {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("api/oauth2/v2/webhooks");
+    ) -> reqwest::Request {
+        let mut url = self.endpoint("api/oauth2/v2/webhooks");
         let include = include.into();
         if let Some(include) = include {
             url.query_pairs_mut()
                 .append_pair("include", include.as_str());
         }
-        self.agent.get(url)
-    }
-
-    async fn api_call(&self, request: reqwest::RequestBuilder) -> PatreonResult<String> {
-        let request = request
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .header("User-Agent", "Patreon-rust")
-            .build()?;
-        tracing::debug!("REQUEST : {} : {}", request.method(), request.url());
-        let response = self.agent.execute(request).await?;
-        let status = response.status();
-        let text = response.text().await?;
-        tracing::debug!("RESPONSE : {status} : {text}");
-        if status.is_success() {
-            Ok(text)
-        } else {
-            Err(PatreonError::PatreonApi(
-                status,
-                serde_json::from_str::<ApiErrorResponse>(text.as_str())?.errors,
-            ))
+        url.query_pairs_mut().append_pair(
+            "fields[webhook]",
+            "last_attempted_at,num_consecutive_times_failed,paused,secret,triggers,uri",
+        );
+        reqwest::Request::new(Method::GET, url)
+    }
+
+    async fn api_call(&self, mut request: reqwest::Request) -> PatreonResult<String> {
+        let headers = request.headers_mut();
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            format!("Bearer {}", self.access_token)
+                .parse()
+                .map_err(|_| PatreonError::Message("Invalid access token".to_string()))?,
+        );
+        headers.insert(
+            reqwest::header::USER_AGENT,
+            reqwest::header::HeaderValue::from_static("Patreon-rust"),
+        );
+        let mut attempt = 0;
+        loop {
+            let retry = request.try_clone();
+            let idempotent = request.method() != Method::POST;
+            self.budget.acquire().await;
+            tracing::debug!("REQUEST : {} : {}", request.method(), request.url());
+            let response = match self.agent.execute(request).await {
+                Ok(response) => response,
+                Err(e) => match retry {
+                    Some(retry) if idempotent && attempt < self.retry_policy.max_retries => {
+                        let delay = self.retry_policy.delay(attempt, None).unwrap_or_default();
+                        tracing::warn!("Patreon request failed ({e}), retrying in {delay:?}");
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                        request = retry;
+                        continue;
+                    }
+                    _ => return Err(e),
+                },
+            };
+            let status = response.status;
+            let text = response.body;
+            tracing::debug!("RESPONSE : {status} : {text}");
+            if status.is_success() {
+                return Ok(text);
+            }
+
+            let retry_after = parse_retry_after(&response.headers);
+            if RetryPolicy::is_retryable(status, idempotent) {
+                let delay = self.retry_policy.delay(attempt, retry_after);
+                if let (Some(retry), Some(delay)) = (
+                    retry.filter(|_| attempt < self.retry_policy.max_retries),
+                    delay,
+                ) {
+                    tracing::warn!("Patreon answered {status}, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    request = retry;
+                    continue;
+                }
+            }
+
+            if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Err(PatreonError::RateLimited { retry_after });
+            }
+            return Err(PatreonError::PatreonApi(status, api_errors(status, text)));
         }
     }
 
     async fn call_data<T: for<'de> serde::Deserialize<'de>>(
         &self,
-        request: reqwest::RequestBuilder,
+        request: reqwest::Request,
     ) -> PatreonResult<T> {
         let json = self.api_call(request).await?;
 
@@ -166,16 +468,15 @@ impl PatreonApi {
     }
 
     async fn call_data_and_link<D: for<'de> serde::Deserialize<'de>,
-    L: for<'de> serde::Deserialize<'de> + Default>(
+    L: for<'de> serde::Deserialize<'de> + Default,
+    M: for<'de> serde::Deserialize<'de> + Default>(
         &self,
-        request: reqwest::RequestBuilder,
-    ) -> PatreonResult<(D,L)> {
+        request: reqwest::Request,
+    ) -> PatreonResult<DocResponseWithLink<D, L, M>> {
         let json = self.api_call(request).await?;
 
         //println!("{}",json.as_str());
-        let response = serde_json::from_str::<DocResponseWithLink<D, L>>(json.as_str())?;
-
-        Ok((response.data, response.links.unwrap_or_default()))
+        Ok(serde_json::from_str::<DocResponseWithLink<D, L, M>>(json.as_str())?)
     }
 
     async fn call_data_and_include<
@@ -183,7 +484,7 @@ impl PatreonApi {
         I: for<'de> serde::Deserialize<'de> + Default,
     >(
         &self,
-        request: reqwest::RequestBuilder,
+        request: reqwest::Request,
     ) -> PatreonResult<(D, Vec<I>)> {
         let json = self.api_call(request).await?;
         let response = serde_json::from_str::<DocResponseInclude<D, I>>(json.as_str())?;
@@ -206,10 +507,14 @@ where
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub(crate) struct DocResponseWithLink<D,L> {
+pub(crate) struct DocResponseWithLink<D, L, M> {
     data: D,
     #[serde(default)]
     links: Option<L>,
+    #[serde(default)]
+    meta: Option<M>,
+    #[serde(default)]
+    included: Vec<serde_json::Value>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -225,19 +530,195 @@ pub struct  Link<L> {
     pub next: Option<L>
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PageMeta {
+    #[serde(default)]
+    pub pagination: Pagination,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pagination {
+    #[serde(default)]
+    pub cursors: Cursors,
+    pub total: Option<i64>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cursors {
+    pub next: Option<String>,
+}
+
+/// One page of `members_stream`.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct MembersPage {
+    pub members: Vec<Member>,
+    /// Tiers referenced by `currently_entitled_tiers` of this page's members.
+    pub tiers: Vec<Tier>,
+    /// Patreon users linked to this page's members.
+    pub users: Vec<User>,
+    /// Cursor of the following page, `None` on the last one.
+    pub next_cursor: Option<String>,
+    pub total: Option<i64>,
+}
+
+impl MembersPage {
+    pub fn entitled_tiers(&self, member: &Member) -> Vec<&Tier> {
+        let ids = member.entitled_tier_ids();
+        self.tiers
+            .iter()
+            .filter(|tier| ids.contains(&tier.id.as_str()))
+            .collect()
+    }
+
+    pub fn user(&self, member: &Member) -> Option<&User> {
+        let id = member.user_id()?;
+        self.users.iter().find(|user| user.id == id)
+    }
+}
+
+/// Typed view of a compound document's `included` array.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Included {
+    pub tiers: Vec<Tier>,
+    pub users: Vec<User>,
+    pub benefits: Vec<Benefit>,
+    pub members: Vec<Member>,
+}
+
+impl Included {
+    /// Documents of unknown types, or that don't parse, are logged and skipped so one odd
+    /// document doesn't cost the whole page.
+    pub(crate) fn parse(included: Vec<serde_json::Value>) -> Self {
+        let mut parsed = Self::default();
+        for document in included {
+            let document_type = document
+                .get("type")
+                .and_then(|t| t.as_str())
+                .unwrap_or_default()
+                .to_string();
+            let result = match document_type.as_str() {
+                "tier" => serde_json::from_value(document).map(|tier| parsed.tiers.push(tier)),
+                "user" => serde_json::from_value(document).map(|user| parsed.users.push(user)),
+                "benefit" => {
+                    serde_json::from_value(document).map(|benefit| parsed.benefits.push(benefit))
+                }
+                "member" => {
+                    serde_json::from_value(document).map(|member| parsed.members.push(member))
+                }
+                _ => {
+                    tracing::debug!("Skipping included document of type {document_type:?}");
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                tracing::warn!("Skipping included {document_type} that doesn't parse: {e}");
+            }
+        }
+        parsed
+    }
+}
+
+/// The `errors` of an error response. A body that isn't a JSON:API error document, like a
+/// proxy's HTML page, is kept as the `detail` of a single error so the cause isn't lost.
+fn api_errors(status: reqwest::StatusCode, body: String) -> Vec<ApiError> {
+    match serde_json::from_str::<ApiErrorResponse>(&body) {
+        Ok(response) => response.errors,
+        Err(_) => vec![ApiError {
+            code: None,
+            code_name: String::new(),
+            detail: body,
+            id: String::new(),
+            status: status.as_str().to_string(),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+        }],
+    }
+}
+
+fn json_request(method: Method, url: Url, body: &serde_json::Value) -> reqwest::Request {
+    let mut request = reqwest::Request::new(method, url);
+    request.headers_mut().insert(
+        reqwest::header::CONTENT_TYPE,
+        reqwest::header::HeaderValue::from_static("application/json"),
+    );
+    *request.body_mut() = Some(body.to_string().into());
+    request
+}
+
+fn cursor_from_link(link: &str) -> Option<String> {
+    Url::parse(link)
+        .ok()?
+        .query_pairs()
+        .find(|(key, _)| key == "page[cursor]")
+        .map(|(_, value)| value.into_owned())
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ApiDocument<A> {
     #[serde(rename = "type")]
     pub document_type: String,
     pub id: String,
     pub attributes: A,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub relationships: HashMap<String, Relationship>,
+}
+
+impl<A> ApiDocument<A> {
+    /// Ids of the documents linked under `relationships.<name>`.
+    pub fn related_ids(&self, name: &str) -> Vec<&str> {
+        match self.relationships.get(name).map(|r| &r.data) {
+            Some(RelationshipData::One(resource)) => vec![resource.id.as_str()],
+            Some(RelationshipData::Many(resources)) => {
+                resources.iter().map(|r| r.id.as_str()).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Member {
+    pub fn entitled_tier_ids(&self) -> Vec<&str> {
+        self.related_ids("currently_entitled_tiers")
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        self.related_ids("user").first().copied()
+    }
+
+    pub fn campaign_id(&self) -> Option<&str> {
+        self.related_ids("campaign").first().copied()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Relationship {
+    #[serde(default)]
+    pub data: RelationshipData,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RelationshipData {
+    Many(Vec<ResourceRef>),
+    One(ResourceRef),
+    #[default]
+    None,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResourceRef {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub id: String,
 }
 
 pub type User = ApiDocument<UserAttributes>;
 pub type Member = ApiDocument<MemberAttributes>;
 pub type Campaign = ApiDocument<CampaignAttributes>;
+pub type Tier = ApiDocument<TierAttributes>;
+pub type Benefit = ApiDocument<BenefitAttributes>;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct UserAttributes {
     pub first_name: String,
     pub last_name: String,
@@ -253,12 +734,44 @@ pub struct UserAttributes {
     pub facebook: Option<String>,
     pub created: DateTime<Utc>,
     pub url: String,
+    pub social_connections: Option<SocialConnections>,
+}
+
+impl UserAttributes {
+    /// Discord user id the patron connected on Patreon, if any.
+    pub fn discord_id(&self) -> Option<u64> {
+        self.social_connections
+            .as_ref()?
+            .discord
+            .as_ref()?
+            .user_id
+            .parse()
+            .ok()
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SocialConnections {
+    #[serde(default)]
+    pub discord: Option<SocialConnection>,
+    #[serde(flatten)]
+    pub others: HashMap<String, Option<SocialConnection>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SocialConnection {
+    pub user_id: String,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct MemberAttributes {
-    // pub campaign_lifetime_support_cents: i64,
-    // pub currently_entitled_amount_cents: i64,
+    pub campaign_lifetime_support_cents: i64,
+    pub currently_entitled_amount_cents: i64,
     pub email: Option<String>,
     pub full_name: String,
     pub is_follower: bool,
@@ -266,14 +779,44 @@ pub struct MemberAttributes {
     pub last_charge_status: Option<LastChrgeStatus>,
     pub lifetime_support_cents: i64,
     pub next_charge_date: Option<DateTime<Utc>>,
-    // pub note: String,
+    pub note: Option<String>,
     pub patron_status: Option<PatronStatus>,
-    // pub pledge_cadence: Option<i64>,
-    // pub pledge_relationship_start: DateTime<Utc>,
-    // pub will_pay_amount_cents: i64,
+    pub pledge_cadence: Option<i64>,
+    pub pledge_relationship_start: Option<DateTime<Utc>>,
+    pub will_pay_amount_cents: i64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TierAttributes {
+    pub amount_cents: i64,
+    pub created_at: Option<DateTime<Utc>>,
+    pub description: Option<String>,
+    pub discord_role_ids: Option<Vec<String>>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub image_url: Option<String>,
+    pub patron_count: Option<i64>,
+    pub published: bool,
+    pub published_at: Option<DateTime<Utc>>,
+    pub title: String,
+    pub url: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BenefitAttributes {
+    pub benefit_type: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub description: Option<String>,
+    pub is_deleted: bool,
+    pub is_published: bool,
+    pub rule_type: Option<String>,
+    pub tiers_count: Option<i64>,
+    pub title: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CampaignAttributes {
     pub created_at: DateTime<Utc>,
     pub creation_name: String,
@@ -406,6 +949,13 @@ enum_str!(PatronStatus {
 
 pub type Pledge = ApiDocument<PledgeAttributes>;
 
+impl Pledge {
+    /// Patreon user id of the patron who made the pledge.
+    pub fn patron_id(&self) -> Option<&str> {
+        self.related_ids("patron").first().copied()
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PledgeAttributes {
     pub amount_cents: i64,
@@ -420,3 +970,323 @@ enum_str!(WebhookIncldue {
     Client("client"),
     Campaign("campaign"),
 });
+
+enum_str!(WebhookTrigger {
+    MembersCreate("members:create"),
+    MembersUpdate("members:update"),
+    MembersDelete("members:delete"),
+    MembersPledgeCreate("members:pledge:create"),
+    MembersPledgeUpdate("members:pledge:update"),
+    MembersPledgeDelete("members:pledge:delete"),
+    PostsPublish("posts:publish"),
+    PostsUpdate("posts:update"),
+    PostsDelete("posts:delete"),
+});
+
+impl WebhookTrigger {
+    /// Every member and pledge trigger the bot handles.
+    pub fn member_triggers() -> Vec<WebhookTrigger> {
+        vec![
+            WebhookTrigger::MembersCreate,
+            WebhookTrigger::MembersUpdate,
+            WebhookTrigger::MembersDelete,
+            WebhookTrigger::MembersPledgeCreate,
+            WebhookTrigger::MembersPledgeUpdate,
+            WebhookTrigger::MembersPledgeDelete,
+        ]
+    }
+}
+
+pub type PatreonWebhook = ApiDocument<WebhookAttributes>;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookAttributes {
+    pub last_attempted_at: Option<DateTime<Utc>>,
+    pub num_consecutive_times_failed: i64,
+    pub paused: bool,
+    pub secret: String,
+    pub triggers: Vec<WebhookTrigger>,
+    pub uri: String,
+}
+
+/// Attributes to change with `PatreonApi::update_webhook`; `None` leaves a field as is.
+#[derive(Default, Debug, Clone, PartialEq, Serialize)]
+pub struct WebhookUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub triggers: Option<Vec<WebhookTrigger>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paused: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HttpResponse, MockTransport};
+    use reqwest::header::HeaderMap;
+    use reqwest::StatusCode;
+
+    fn member(id: &str, email: &str) -> serde_json::Value {
+        serde_json::json!({
+            "type": "member",
+            "id": id,
+            "attributes": { "email": email, "patron_status": "active_patron" },
+        })
+    }
+
+    #[test]
+    fn endpoint_keeps_the_base_path() {
+        let api = PatreonApi::new("token").with_base_url("http://mock.local/patreon/").unwrap();
+        assert_eq!(
+            api.endpoint("/api/oauth2/v2/identity").as_str(),
+            "http://mock.local/patreon/api/oauth2/v2/identity"
+        );
+        assert_eq!(
+            api.endpoint("api/oauth2/v2/webhooks").as_str(),
+            "http://mock.local/patreon/api/oauth2/v2/webhooks"
+        );
+        assert_eq!(
+            PatreonApi::new("token").endpoint("api/oauth2/v2/identity").as_str(),
+            "https://www.patreon.com/api/oauth2/v2/identity"
+        );
+    }
+
+    #[tokio::test]
+    async fn all_members_follows_cursors_through_the_transport() {
+        let first = serde_json::json!({
+            "data": [member("m1", "a@example.com")],
+            "meta": { "pagination": { "cursors": { "next": "c2" }, "total": 2 } },
+        });
+        let last = serde_json::json!({
+            "data": [member("m2", "b@example.com")],
+            "meta": { "pagination": { "cursors": { "next": null }, "total": 2 } },
+        });
+        let transport = Arc::new(
+            MockTransport::default()
+                .respond(StatusCode::OK, &first.to_string())
+                .respond(StatusCode::OK, &last.to_string()),
+        );
+        let api = PatreonApi::new("token")
+            .with_base_url("http://mock.local/patreon")
+            .unwrap()
+            .with_transport(transport.clone())
+            .with_retry_policy(RetryPolicy::none())
+            .with_campaign_id("42");
+
+        let members = api.all_members().await.unwrap();
+
+        let emails: Vec<_> = members.iter().map(|m| m.attributes.email.as_deref()).collect();
+        assert_eq!(emails, [Some("a@example.com"), Some("b@example.com")]);
+
+        let requested = transport.requested();
+        assert_eq!(requested.len(), 2);
+        assert!(requested[0].starts_with("http://mock.local/patreon/api/oauth2/v2/campaigns/42/members?"));
+        assert!(!requested[0].contains("page%5Bcursor%5D"));
+        assert!(requested[1].ends_with("&page%5Bcursor%5D=c2"));
+    }
+
+    fn campaign(id: &str, vanity: &str, creation_name: &str, discord_server_id: Option<&str>) -> serde_json::Value {
+        serde_json::json!({
+            "type": "campaign",
+            "id": id,
+            "attributes": {
+                "vanity": vanity,
+                "creation_name": creation_name,
+                "discord_server_id": discord_server_id,
+            },
+        })
+    }
+
+    fn details(campaign: serde_json::Value) -> CampaignDetails {
+        CampaignDetails {
+            campaign: serde_json::from_value(campaign).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn campaigns_follow_the_next_link() {
+        let first = serde_json::json!({
+            "data": [campaign("1", "first", "", None)],
+            "links": { "next": "http://mock.local/api/oauth2/v2/campaigns?page%5Bcursor%5D=c2" },
+        });
+        let last = serde_json::json!({
+            "data": [campaign("2", "second", "", None)],
+        });
+        let transport = Arc::new(
+            MockTransport::default()
+                .respond(StatusCode::OK, &first.to_string())
+                .respond(StatusCode::OK, &last.to_string()),
+        );
+        let api = PatreonApi::new("token")
+            .with_base_url("http://mock.local")
+            .unwrap()
+            .with_transport(transport.clone())
+            .with_retry_policy(RetryPolicy::none());
+
+        let campaigns = api.campaigns().await.unwrap();
+
+        let ids: Vec<_> = campaigns.iter().map(|c| c.campaign.id.as_str()).collect();
+        assert_eq!(ids, ["1", "2"]);
+        let requested = transport.requested();
+        assert_eq!(requested.len(), 2);
+        assert!(!requested[0].contains("page%5Bcursor%5D"));
+        assert!(requested[1].ends_with("&page%5Bcursor%5D=c2"));
+    }
+
+    #[test]
+    fn select_picks_exactly_one_campaign() {
+        let campaigns = [
+            details(campaign("1", "alpha", "Alpha Art", Some("111"))),
+            details(campaign("2", "beta", "Beta Music", Some("222"))),
+            details(campaign("3", "gamma", "Alpha Art", None)),
+        ];
+        let one = [details(campaign("9", "solo", "", None))];
+
+        let found = [
+            (CampaignSelector::Single, &one[..], "9"),
+            (CampaignSelector::Id("2".into()), &campaigns[..], "2"),
+            (CampaignSelector::Name("BETA".into()), &campaigns[..], "2"),
+            (CampaignSelector::Name("beta music".into()), &campaigns[..], "2"),
+            (CampaignSelector::DiscordServer(111), &campaigns[..], "1"),
+        ];
+        for (selector, campaigns, id) in found {
+            let selected = selector.select(campaigns).unwrap();
+            assert_eq!(selected.campaign.id, id, "{selector:?}");
+        }
+
+        let not_found = [
+            (CampaignSelector::Single, &[][..]),
+            (CampaignSelector::Id("4".into()), &campaigns[..]),
+            (CampaignSelector::Name("delta".into()), &campaigns[..]),
+            (CampaignSelector::DiscordServer(333), &campaigns[..]),
+        ];
+        for (selector, campaigns) in not_found {
+            assert!(
+                matches!(selector.select(campaigns), Err(PatreonError::CampaignNotFound(_))),
+                "{selector:?}"
+            );
+        }
+
+        let ambiguous = [
+            (CampaignSelector::Single, vec!["1", "2", "3"]),
+            (CampaignSelector::Name("alpha art".into()), vec!["1", "3"]),
+        ];
+        for (selector, expected) in ambiguous {
+            match selector.select(&campaigns) {
+                Err(PatreonError::AmbiguousCampaign(ids)) => assert_eq!(ids, expected, "{selector:?}"),
+                other => panic!("{selector:?} gave {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn selector_from_env_prefers_the_id() {
+        // The only test touching these variables, so it can't race another one.
+        std::env::set_var("CAMPAIGN_ID", "");
+        std::env::set_var("CAMPAIGN_NAME", "");
+        assert_eq!(CampaignSelector::from_env(), CampaignSelector::Single);
+
+        std::env::set_var("CAMPAIGN_NAME", "alpha");
+        assert_eq!(CampaignSelector::from_env(), CampaignSelector::Name("alpha".into()));
+
+        std::env::set_var("CAMPAIGN_ID", "42");
+        assert_eq!(CampaignSelector::from_env(), CampaignSelector::Id("42".into()));
+
+        std::env::remove_var("CAMPAIGN_ID");
+        std::env::remove_var("CAMPAIGN_NAME");
+    }
+
+    #[tokio::test]
+    async fn retry_after_beyond_the_cap_gives_up_at_once() {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, "120".parse().unwrap());
+        let transport = Arc::new(MockTransport::default().respond_with(HttpResponse {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            headers,
+            body: String::new(),
+        }));
+        let api = PatreonApi::new("token").with_transport(transport.clone());
+
+        let error = api.identity().await.unwrap_err();
+        assert!(matches!(
+            error,
+            PatreonError::RateLimited { retry_after: Some(after) } if after.as_secs() == 120
+        ));
+        assert_eq!(transport.requested().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn non_json_error_body_is_kept() {
+        let transport = Arc::new(
+            MockTransport::default().respond(StatusCode::FORBIDDEN, "<html>blocked</html>"),
+        );
+        let api = PatreonApi::new("token").with_transport(transport);
+
+        match api.identity().await.unwrap_err() {
+            PatreonError::PatreonApi(status, errors) => {
+                assert_eq!(status, StatusCode::FORBIDDEN);
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].detail, "<html>blocked</html>");
+                assert_eq!(errors[0].title, "Forbidden");
+            }
+            error => panic!("unexpected error {error}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn members_page_parses_a_captured_payload() {
+        let transport = Arc::new(MockTransport::default().respond(
+            StatusCode::OK,
+            include_str!("../testdata/members_page.json"),
+        ));
+        let api = PatreonApi::new("token")
+            .with_transport(transport)
+            .with_campaign_id("4242");
+
+        let page = api.members_page(None).await.unwrap();
+        assert_eq!(page.next_cursor.as_deref(), Some("03:eyJ2IjoxfQ"));
+        assert_eq!(page.total, Some(16));
+        assert_eq!(page.members.len(), 2);
+        assert_eq!(page.users.len(), 2);
+
+        let active = &page.members[0];
+        assert_eq!(active.attributes.patron_status, Some(PatronStatus::ActivePatron));
+        assert_eq!(active.attributes.last_charge_status, Some(LastChrgeStatus::Paid));
+        assert_eq!(active.attributes.currently_entitled_amount_cents, 500);
+        let tiers: Vec<_> = page
+            .entitled_tiers(active)
+            .iter()
+            .map(|tier| tier.attributes.title.as_str())
+            .collect();
+        assert_eq!(tiers, ["Supporter", "Champion"]);
+        let user = page.user(active).unwrap();
+        assert_eq!(user.attributes.discord_id(), Some(123456789012345678));
+        assert_eq!(user.attributes.vanity, None);
+
+        let former = &page.members[1];
+        assert_eq!(former.attributes.patron_status, Some(PatronStatus::FormerPatron));
+        assert_eq!(former.attributes.pledge_cadence, None);
+        assert!(former.entitled_tier_ids().is_empty());
+        assert_eq!(former.relationships["address"].data, RelationshipData::None);
+        let user = page.user(former).unwrap();
+        assert_eq!(user.attributes.email, "");
+        assert_eq!(user.attributes.discord_id(), None);
+    }
+
+    #[test]
+    fn included_skips_documents_that_dont_parse() {
+        let included = Included::parse(vec![
+            serde_json::json!({ "type": "tier", "id": "1", "attributes": { "title": "Kept" } }),
+            serde_json::json!({ "type": "tier", "id": "2", "attributes": { "amount_cents": "lots" } }),
+            serde_json::json!({ "type": "user", "attributes": {} }),
+            serde_json::json!({ "type": "goal", "id": "3", "attributes": {} }),
+            serde_json::json!({ "id": "4" }),
+        ]);
+        assert_eq!(included.tiers.len(), 1);
+        assert_eq!(included.tiers[0].attributes.title, "Kept");
+        assert!(included.users.is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::api::{Benefit, Campaign, Tier};
+use crate::{PatreonError, PatreonResult};
+use std::env;
+
+/// A campaign returned by `PatreonApi::campaigns` together with its tiers and benefits.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CampaignDetails {
+    pub campaign: Campaign,
+    pub tiers: Vec<Tier>,
+    pub benefits: Vec<Benefit>,
+}
+
+/// Picks which of the token's campaigns the bot works with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CampaignSelector {
+    /// The only campaign the token can see; several campaigns is an error.
+    Single,
+    Id(String),
+    /// Matches the campaign vanity or creation name, ignoring case.
+    Name(String),
+    /// The campaign connected to this Discord server.
+    DiscordServer(u64),
+}
+
+impl CampaignSelector {
+    /// `CAMPAIGN_ID`, then `CAMPAIGN_NAME`, falling back to `Single`.
+    pub fn from_env() -> Self {
+        if let Ok(id) = env::var("CAMPAIGN_ID") {
+            if !id.is_empty() {
+                return Self::Id(id);
+            }
+        }
+        if let Ok(name) = env::var("CAMPAIGN_NAME") {
+            if !name.is_empty() {
+                return Self::Name(name);
+            }
+        }
+        Self::Single
+    }
+
+    pub fn select<'a>(&self, campaigns: &'a [CampaignDetails]) -> PatreonResult<&'a CampaignDetails> {
+        let matches: Vec<&CampaignDetails> = match self {
+            Self::Single => campaigns.iter().collect(),
+            Self::Id(id) => campaigns.iter().filter(|c| &c.campaign.id == id).collect(),
+            Self::Name(name) => campaigns
+                .iter()
+                .filter(|c| {
+                    let attr = &c.campaign.attributes;
+                    attr.vanity.eq_ignore_ascii_case(name)
+                        || attr.creation_name.eq_ignore_ascii_case(name)
+                })
+                .collect(),
+            Self::DiscordServer(server_id) => campaigns
+                .iter()
+                .filter(|c| {
+                    c.campaign.attributes.discord_server_id.as_deref()
+                        == Some(server_id.to_string().as_str())
+                })
+                .collect(),
+        };
+
+        match matches.as_slice() {
+            [campaign] => Ok(campaign),
+            [] => Err(PatreonError::CampaignNotFound(format!("{self:?}"))),
+            many => Err(PatreonError::AmbiguousCampaign(
+                many.iter().map(|c| c.campaign.id.clone()).collect(),
+            )),
+        }
+    }
+}
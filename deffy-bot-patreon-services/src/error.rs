@@ -1,6 +1,7 @@
 use reqwest::StatusCode;
 use serde_derive::{Deserialize, Serialize};
 use std::fmt::{Debug, Display, Formatter};
+use std::time::Duration;
 
 pub type PatreonResult<A> = std::result::Result<A, PatreonError>;
 
@@ -10,6 +11,16 @@ pub enum PatreonError {
     SerdeJson(serde_json::Error),
     PatreonOAuth(StatusCode, String),
     PatreonApi(StatusCode, Vec<ApiError>),
+    /// Still answered 429 after every retry allowed by the client's `RetryPolicy`.
+    RateLimited { retry_after: Option<Duration> },
+    /// The client has no campaign id; resolve one with `PatreonApi::resolve_campaign`.
+    NoCampaignSelected,
+    CampaignNotFound(String),
+    AmbiguousCampaign(Vec<String>),
+    /// The OAuth `state` came back unsigned, tampered with or too old.
+    InvalidState(String),
+    /// The user declined on Patreon, or the callback came back without a code.
+    AuthorizationDenied(String),
     Message(String),
 }
 
@@ -30,6 +41,22 @@ impl Display for PatreonError {
                 f.write_str(" ] }")?;
                 Ok(())
             }
+            PatreonError::RateLimited { retry_after } => {
+                write!(f, "RateLimited {{ retry_after : {retry_after:?} }}")
+            }
+            PatreonError::NoCampaignSelected => f.write_str("NoCampaignSelected"),
+            PatreonError::CampaignNotFound(selector) => {
+                write!(f, "CampaignNotFound {{ selector : {selector} }}")
+            }
+            PatreonError::AmbiguousCampaign(ids) => {
+                write!(f, "AmbiguousCampaign {{ ids : {ids:?} }}")
+            }
+            PatreonError::InvalidState(reason) => {
+                write!(f, "InvalidState {{ reason : {reason} }}")
+            }
+            PatreonError::AuthorizationDenied(reason) => {
+                write!(f, "AuthorizationDenied {{ reason : {reason} }}")
+            }
             PatreonError::Message(msg) => {
                 write!(f, "Message ( {msg} ) ,")
             }
@@ -1,10 +1,16 @@
 pub use api::*;
+pub use campaign::*;
 pub use error::*;
 pub use oauth2::*;
+pub use retry::*;
+pub use transport::*;
 pub use webhook::*;
 
 pub mod api;
+pub mod campaign;
 mod compile_rules;
 pub mod error;
 pub mod oauth2;
+pub mod retry;
+pub mod transport;
 pub mod webhook;
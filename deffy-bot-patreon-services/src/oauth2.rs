@@ -1,36 +1,116 @@
-use crate::{PatreonError, PatreonResult};
-use reqwest::StatusCode;
+use crate::api::{join_path, BASE_URI};
+use crate::{PatreonError, PatreonResult, Transport};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use url::Url;
 
-static BASE_URI: &str = "https://www.patreon.com";
-
-#[derive(Debug, Default)]
+#[derive(Debug, Clone)]
 pub struct PatreonOAuth {
     pub client_id: String,
     pub client_secret: String,
     pub redirect_uri: String,
-    pub agent: Arc<reqwest::Client>,
+    /// Root the authorize and token endpoints are resolved against.
+    pub base_url: Url,
+    pub agent: Arc<dyn Transport>,
 }
 
-impl PatreonOAuth {
-    pub fn get_authorization_url(&self, scope: &str, state: &str) -> String {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("/oauth2/authorize");
-        if !scope.is_empty() {
-            url.query_pairs_mut().append_pair("scope", scope);
+impl Default for PatreonOAuth {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            redirect_uri: String::new(),
+            base_url: Url::parse(BASE_URI).unwrap(),
+            agent: Arc::new(reqwest::Client::new()),
         }
-        if !state.is_empty() {
-            url.query_pairs_mut().append_pair("state", state);
+    }
+}
+
+impl PatreonOAuth {
+    /// `PATREON_CLIENT_ID`, `PATREON_CLIENT_SECRET` and `PATREON_REDIRECT_URI`, the redirect
+    /// defaulting to the callback route under `PUBLIC_BASE_URL`. `PATREON_BASE_URL` points the
+    /// client at another Patreon, like a local mock.
+    pub fn from_env() -> Self {
+        let env = |key: &str| std::env::var(key).unwrap_or_default();
+        let redirect_uri = match env("PATREON_REDIRECT_URI") {
+            uri if !uri.is_empty() => uri,
+            _ => format!(
+                "{}/patreon/oauth/callback",
+                env("PUBLIC_BASE_URL").trim_end_matches('/')
+            ),
+        };
+        let base_url = match env("PATREON_BASE_URL") {
+            base_url if !base_url.is_empty() => Url::parse(&base_url).unwrap_or_else(|e| {
+                tracing::error!("Ignoring invalid PATREON_BASE_URL {base_url}: {e}");
+                Url::parse(BASE_URI).unwrap()
+            }),
+            _ => Url::parse(BASE_URI).unwrap(),
+        };
+        Self {
+            client_id: env("PATREON_CLIENT_ID"),
+            client_secret: env("PATREON_CLIENT_SECRET"),
+            redirect_uri,
+            base_url,
+            ..Default::default()
         }
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> PatreonResult<Self> {
+        self.base_url = Url::parse(base_url)
+            .map_err(|e| PatreonError::Message(format!("invalid base url {base_url}: {e}")))?;
+        Ok(self)
+    }
+
+    pub fn with_transport(mut self, agent: Arc<dyn Transport>) -> Self {
+        self.agent = agent;
+        self
+    }
+
+    fn endpoint(&self, path: &str) -> Url {
+        join_path(&self.base_url, path)
+    }
+
+    pub fn get_authorization_url(&self, scopes: &Scopes, state: &str) -> String {
+        self.authorization_url(scopes, state).to_string()
+    }
+
+    /// `get_authorization_url` with a PKCE `code_challenge`; finish with `get_tokens_with_verifier`.
+    pub fn get_authorization_url_with_pkce(
+        &self,
+        scopes: &Scopes,
+        state: &str,
+        pkce: &PkceChallenge,
+    ) -> String {
+        let mut url = self.authorization_url(scopes, state);
+        url.query_pairs_mut()
+            .append_pair("code_challenge", &pkce.challenge)
+            .append_pair("code_challenge_method", "S256");
+        url.to_string()
+    }
+
+    fn authorization_url(&self, scopes: &Scopes, state: &str) -> Url {
+        let mut url = self.endpoint("/oauth2/authorize");
         url.query_pairs_mut()
-            .append_pair("scope ", "campaigns")
             .append_pair("response_type", "code")
             .append_pair("client_id", self.client_id.as_str())
             .append_pair("redirect_uri", self.redirect_uri.as_str());
-        url.to_string()
+        if !scopes.is_empty() {
+            url.query_pairs_mut()
+                .append_pair("scope", scopes.to_string().as_str());
+        }
+        if !state.is_empty() {
+            url.query_pairs_mut().append_pair("state", state);
+        }
+        url
     }
 
     pub async fn get_tokens(&self, code: &str) -> PatreonResult<TokensResponse> {
@@ -46,6 +126,24 @@ impl PatreonOAuth {
         .await
     }
 
+    pub async fn get_tokens_with_verifier(
+        &self,
+        code: &str,
+        code_verifier: &str,
+    ) -> PatreonResult<TokensResponse> {
+        self.parse_token_request(&{
+            let mut params = HashMap::new();
+            params.insert("grant_type", "authorization_code");
+            params.insert("code", code);
+            params.insert("code_verifier", code_verifier);
+            params.insert("client_id", self.client_id.as_str());
+            params.insert("client_secret", self.client_secret.as_str());
+            params.insert("redirect_uri", self.redirect_uri.as_str());
+            params
+        })
+        .await
+    }
+
     pub async fn refresh_tokens(&self, refresh_token: &str) -> PatreonResult<TokensResponse> {
         self.parse_token_request(&{
             let mut params = HashMap::new();
@@ -62,15 +160,321 @@ impl PatreonOAuth {
         &self,
         params: &HashMap<&str, &str>,
     ) -> PatreonResult<TokensResponse> {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("/api/oauth2/token");
-        let response = self.agent.post(url).form(params).send().await?;
-        let status = response.status();
-        let text = response.text().await?;
-        de_response(status, text)
+        let url = self.endpoint("/api/oauth2/token");
+        let mut request = reqwest::Request::new(Method::POST, url);
+        request.headers_mut().insert(
+            reqwest::header::CONTENT_TYPE,
+            reqwest::header::HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params.iter())
+            .finish();
+        *request.body_mut() = Some(body.into());
+        let response = self.agent.execute(request).await?;
+        de_response(response.status, response.body)
+    }
+}
+
+/// Patreon API v2 OAuth scopes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Scope {
+    Identity,
+    IdentityEmail,
+    IdentityMemberships,
+    Campaigns,
+    CampaignsWebhook,
+    CampaignsMembers,
+    CampaignsMembersEmail,
+    CampaignsMembersAddress,
+    CampaignsPosts,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 9] = [
+        Scope::Identity,
+        Scope::IdentityEmail,
+        Scope::IdentityMemberships,
+        Scope::Campaigns,
+        Scope::CampaignsWebhook,
+        Scope::CampaignsMembers,
+        Scope::CampaignsMembersEmail,
+        Scope::CampaignsMembersAddress,
+        Scope::CampaignsPosts,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Identity => "identity",
+            Scope::IdentityEmail => "identity[email]",
+            Scope::IdentityMemberships => "identity.memberships",
+            Scope::Campaigns => "campaigns",
+            Scope::CampaignsWebhook => "w:campaigns.webhook",
+            Scope::CampaignsMembers => "campaigns.members",
+            Scope::CampaignsMembersEmail => "campaigns.members[email]",
+            Scope::CampaignsMembersAddress => "campaigns.members.address",
+            Scope::CampaignsPosts => "campaigns.posts",
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = PatreonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| PatreonError::Message(format!("unknown scope: {s}")))
+    }
+}
+
+/// A set of scopes, written space separated in declaration order.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Scopes(BTreeSet<Scope>);
+
+impl Scopes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What account linking needs: who the user is, their email and their memberships.
+    pub fn identity() -> Self {
+        [Scope::Identity, Scope::IdentityEmail, Scope::IdentityMemberships]
+            .into_iter()
+            .collect()
+    }
+
+    pub fn with(mut self, scope: Scope) -> Self {
+        self.0.insert(scope);
+        self
+    }
+
+    pub fn contains(&self, scope: Scope) -> bool {
+        self.0.contains(&scope)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Reads the `scope` field of a token response; unknown scopes are skipped.
+    pub fn parse(scopes: &str) -> Self {
+        scopes
+            .split_whitespace()
+            .filter_map(|scope| scope.parse().ok())
+            .collect()
+    }
+}
+
+impl FromIterator<Scope> for Scopes {
+    fn from_iter<T: IntoIterator<Item = Scope>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Display for Scopes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        for scope in &self.0 {
+            if !first {
+                f.write_str(" ")?;
+            }
+            f.write_str(scope.as_str())?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// A PKCE verifier and its S256 challenge (RFC 7636).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PkceChallenge {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl PkceChallenge {
+    pub fn new() -> Self {
+        Self::from_verifier(URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>()))
+    }
+
+    pub fn from_verifier(verifier: impl Into<String>) -> Self {
+        let verifier = verifier.into();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Self {
+            verifier,
+            challenge,
+        }
+    }
+}
+
+impl Default for PkceChallenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Query parameters Patreon redirects back to the callback with.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct OAuthCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+impl OAuthCallback {
+    /// Checks the user approved, a code came back and the `state` is ours, fresh and not
+    /// used before. Returns the code to exchange together with the decoded state.
+    pub fn validate(
+        &self,
+        secret: &[u8],
+        max_age: Duration,
+        used_nonces: &UsedNonces,
+    ) -> PatreonResult<(String, OAuthState)> {
+        if let Some(error) = &self.error {
+            return Err(PatreonError::AuthorizationDenied(error.clone()));
+        }
+        let state = self
+            .state
+            .as_deref()
+            .filter(|state| !state.is_empty())
+            .ok_or_else(|| PatreonError::InvalidState("missing".to_string()))?;
+        let state = OAuthState::decode(state, secret, max_age)?;
+        let code = self
+            .code
+            .clone()
+            .filter(|code| !code.is_empty())
+            .ok_or_else(|| PatreonError::AuthorizationDenied("missing code".to_string()))?;
+        if !used_nonces.consume(&state.nonce, max_age) {
+            return Err(PatreonError::InvalidState("already used".to_string()));
+        }
+        Ok((code, state))
+    }
+}
+
+/// Nonces of the states already returned to the callback, so a captured callback URL
+/// can't be replayed. Each is kept as long as its state would still be accepted.
+///
+/// Held in memory only: after a restart a state can be replayed until it expires, but
+/// Patreon refuses the second exchange of its authorization code.
+#[derive(Debug, Default)]
+pub struct UsedNonces {
+    used: Mutex<HashMap<String, Instant>>,
+}
+
+impl UsedNonces {
+    /// Returns `false` if the nonce was already used within `max_age`.
+    pub fn consume(&self, nonce: &str, max_age: Duration) -> bool {
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, used_at| used_at.elapsed() < max_age);
+        used.insert(nonce.to_string(), Instant::now()).is_none()
+    }
+}
+
+/// The `state` passed through the authorize redirect, carrying the Discord user who
+/// started the link so the callback can't be replayed for somebody else.
+///
+/// Encoded as `discord_id.issued_at.nonce.signature`, signed with HMAC-SHA256.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OAuthState {
+    pub discord_id: u64,
+    /// Unix seconds.
+    pub issued_at: i64,
+    /// Recorded in `UsedNonces` by the callback, so each state is accepted once.
+    pub nonce: String,
+}
+
+impl OAuthState {
+    pub fn new(discord_id: u64) -> Self {
+        Self {
+            discord_id,
+            issued_at: chrono::Utc::now().timestamp(),
+            nonce: hex::encode(rand::random::<[u8; 16]>()),
+        }
+    }
+
+    pub fn encode(&self, secret: &[u8]) -> String {
+        let payload = format!("{}.{}.{}", self.discord_id, self.issued_at, self.nonce);
+        let signature = hex::encode(sign(secret, payload.as_bytes()).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// Checks the signature and age of a `state` returned to the callback.
+    pub fn decode(state: &str, secret: &[u8], max_age: Duration) -> PatreonResult<Self> {
+        let invalid = |reason: &str| PatreonError::InvalidState(reason.to_string());
+
+        let (payload, signature) = state.rsplit_once('.').ok_or_else(|| invalid("malformed"))?;
+        let signature = hex::decode(signature).map_err(|_| invalid("malformed signature"))?;
+        sign(secret, payload.as_bytes())
+            .verify_slice(&signature)
+            .map_err(|_| invalid("bad signature"))?;
+
+        let mut parts = payload.splitn(3, '.');
+        let (Some(discord_id), Some(issued_at), Some(nonce)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("malformed"));
+        };
+        let state = Self {
+            discord_id: discord_id.parse().map_err(|_| invalid("malformed discord id"))?,
+            issued_at: issued_at.parse().map_err(|_| invalid("malformed timestamp"))?,
+            nonce: nonce.to_string(),
+        };
+
+        let age = chrono::Utc::now().timestamp() - state.issued_at;
+        if age < 0 || age as u64 > max_age.as_secs() {
+            return Err(invalid("expired"));
+        }
+        Ok(state)
+    }
+
+    /// Signature for a login link handed to `discord_id` at `issued_at` (unix seconds), so
+    /// the login route only starts a link for the user the bot gave the link to, and only
+    /// until the link gets too old.
+    pub fn sign_login(secret: &[u8], discord_id: u64, issued_at: i64) -> String {
+        let payload = login_payload(discord_id, issued_at);
+        hex::encode(sign(secret, payload.as_bytes()).finalize().into_bytes())
+    }
+
+    /// Checks a login link made by `sign_login` is ours and at most `max_age` old.
+    pub fn verify_login(
+        secret: &[u8],
+        discord_id: u64,
+        issued_at: i64,
+        signature: &str,
+        max_age: Duration,
+    ) -> PatreonResult<()> {
+        let invalid = |reason: &str| PatreonError::InvalidState(reason.to_string());
+
+        let signature = hex::decode(signature).map_err(|_| invalid("malformed signature"))?;
+        sign(secret, login_payload(discord_id, issued_at).as_bytes())
+            .verify_slice(&signature)
+            .map_err(|_| invalid("bad signature"))?;
+
+        let age = chrono::Utc::now().timestamp() - issued_at;
+        if age < 0 || age as u64 > max_age.as_secs() {
+            return Err(invalid("expired"));
+        }
+        Ok(())
     }
 }
 
+/// Prefixed so a login signature can never pass as a `state` signature or the other way round.
+fn login_payload(discord_id: u64, issued_at: i64) -> String {
+    format!("login.{discord_id}.{issued_at}")
+}
+
+fn sign(secret: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(payload);
+    mac
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TokensResponse {
     pub access_token: String,
@@ -100,3 +504,187 @@ fn de_response<T: for<'de> serde::Deserialize<'de>>(
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockTransport;
+
+    fn oauth() -> PatreonOAuth {
+        PatreonOAuth {
+            client_id: "client123".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_uri: "https://bot.example.com/patreon/oauth/callback".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn authorization_url_with_identity_scopes() {
+        assert_eq!(
+            oauth().get_authorization_url(&Scopes::identity(), "abc.123"),
+            "https://www.patreon.com/oauth2/authorize\
+             ?response_type=code\
+             &client_id=client123\
+             &redirect_uri=https%3A%2F%2Fbot.example.com%2Fpatreon%2Foauth%2Fcallback\
+             &scope=identity+identity%5Bemail%5D+identity.memberships\
+             &state=abc.123"
+        );
+    }
+
+    #[test]
+    fn authorization_url_orders_scopes_and_skips_empty_state() {
+        let scopes = Scopes::new()
+            .with(Scope::CampaignsMembers)
+            .with(Scope::Campaigns)
+            .with(Scope::CampaignsWebhook)
+            .with(Scope::Campaigns);
+        assert_eq!(
+            oauth().get_authorization_url(&scopes, ""),
+            "https://www.patreon.com/oauth2/authorize\
+             ?response_type=code\
+             &client_id=client123\
+             &redirect_uri=https%3A%2F%2Fbot.example.com%2Fpatreon%2Foauth%2Fcallback\
+             &scope=campaigns+w%3Acampaigns.webhook+campaigns.members"
+        );
+    }
+
+    #[test]
+    fn authorization_url_without_scopes() {
+        assert_eq!(
+            oauth().get_authorization_url(&Scopes::new(), "s"),
+            "https://www.patreon.com/oauth2/authorize\
+             ?response_type=code\
+             &client_id=client123\
+             &redirect_uri=https%3A%2F%2Fbot.example.com%2Fpatreon%2Foauth%2Fcallback\
+             &state=s"
+        );
+    }
+
+    #[test]
+    fn authorization_url_with_pkce() {
+        let pkce = PkceChallenge::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+        assert_eq!(
+            oauth().get_authorization_url_with_pkce(&Scopes::new().with(Scope::Identity), "s", &pkce),
+            "https://www.patreon.com/oauth2/authorize\
+             ?response_type=code\
+             &client_id=client123\
+             &redirect_uri=https%3A%2F%2Fbot.example.com%2Fpatreon%2Foauth%2Fcallback\
+             &scope=identity\
+             &state=s\
+             &code_challenge=E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM\
+             &code_challenge_method=S256"
+        );
+    }
+
+    #[tokio::test]
+    async fn token_exchange_goes_through_the_transport() {
+        let transport = Arc::new(MockTransport::default().respond(
+            StatusCode::OK,
+            r#"{"access_token":"at","expires_in":2678400,"token_type":"Bearer","scope":"identity","refresh_token":"rt"}"#,
+        ));
+        let oauth = oauth()
+            .with_base_url("http://mock.local/patreon")
+            .unwrap()
+            .with_transport(transport.clone());
+
+        let tokens = oauth.get_tokens("code").await.unwrap();
+        assert_eq!(tokens.access_token, "at");
+        assert_eq!(tokens.refresh_token, "rt");
+        assert_eq!(transport.requested(), ["http://mock.local/patreon/api/oauth2/token"]);
+    }
+
+    #[test]
+    fn pkce_verifier_is_url_safe() {
+        let pkce = PkceChallenge::new();
+        assert_eq!(pkce.verifier.len(), 43);
+        assert!(pkce.verifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(PkceChallenge::from_verifier(pkce.verifier.clone()), pkce);
+    }
+
+    #[test]
+    fn scopes_parse_round_trip() {
+        let scopes = Scopes::parse("identity.memberships identity unknown identity[email]");
+        assert_eq!(scopes, Scopes::identity());
+        assert_eq!(scopes.to_string(), "identity identity[email] identity.memberships");
+    }
+
+    #[test]
+    fn callback_validation() {
+        let secret = b"state-secret";
+        let max_age = Duration::from_secs(600);
+        let state = OAuthState::new(42).encode(secret);
+
+        let callback = OAuthCallback {
+            code: Some("code".to_string()),
+            state: Some(state.clone()),
+            error: None,
+        };
+        let used = UsedNonces::default();
+        let (code, decoded) = callback.validate(secret, max_age, &used).unwrap();
+        assert_eq!(code, "code");
+        assert_eq!(decoded.discord_id, 42);
+
+        assert!(matches!(
+            callback.validate(secret, max_age, &used),
+            Err(PatreonError::InvalidState(reason)) if reason == "already used"
+        ));
+
+        assert!(matches!(
+            callback.validate(b"other-secret", max_age, &used),
+            Err(PatreonError::InvalidState(_))
+        ));
+
+        let denied = OAuthCallback {
+            error: Some("access_denied".to_string()),
+            ..callback.clone()
+        };
+        assert!(matches!(
+            denied.validate(secret, max_age, &used),
+            Err(PatreonError::AuthorizationDenied(_))
+        ));
+
+        let no_code = OAuthCallback {
+            code: None,
+            ..callback.clone()
+        };
+        assert!(matches!(
+            no_code.validate(secret, max_age, &used),
+            Err(PatreonError::AuthorizationDenied(_))
+        ));
+
+        let expired = OAuthState {
+            issued_at: chrono::Utc::now().timestamp() - 601,
+            ..OAuthState::new(42)
+        };
+        let expired = OAuthCallback {
+            state: Some(expired.encode(secret)),
+            ..callback
+        };
+        assert!(matches!(
+            expired.validate(secret, max_age, &used),
+            Err(PatreonError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn login_links_are_bound_to_the_user_and_expire() {
+        let secret = b"state-secret";
+        let max_age = Duration::from_secs(600);
+        let now = chrono::Utc::now().timestamp();
+        let sig = OAuthState::sign_login(secret, 42, now);
+
+        assert!(OAuthState::verify_login(secret, 42, now, &sig, max_age).is_ok());
+        assert!(OAuthState::verify_login(secret, 43, now, &sig, max_age).is_err());
+        assert!(OAuthState::verify_login(secret, 42, now + 1, &sig, max_age).is_err());
+        assert!(OAuthState::verify_login(b"other-secret", 42, now, &sig, max_age).is_err());
+        assert!(OAuthState::verify_login(secret, 42, now, "zz", max_age).is_err());
+
+        let old = now - 601;
+        let stale = OAuthState::sign_login(secret, 42, old);
+        assert!(matches!(
+            OAuthState::verify_login(secret, 42, old, &stale, max_age),
+            Err(PatreonError::InvalidState(reason)) if reason == "expired"
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// How `PatreonApi` retries a request that hit a 429, a 5xx or a transport error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, `0` disables retrying.
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// A 429 is always safe to retry; a 5xx only when repeating the request can't create
+    /// a duplicate.
+    pub fn is_retryable(status: StatusCode, idempotent: bool) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS || (idempotent && status.is_server_error())
+    }
+
+    /// Delay before retry number `attempt` (starting at 0). The server's `Retry-After` is
+    /// honoured as is, and `None` means it asks for longer than `max_delay`, so retrying
+    /// early would only burn the budget.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(
+                self.base_delay
+                    .saturating_mul(2u32.saturating_pow(attempt))
+                    .min(self.max_delay),
+            ),
+        }
+    }
+}
+
+/// Reads `Retry-After` as either delta-seconds or an HTTP date.
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let millis = date.timestamp_millis() - chrono::Utc::now().timestamp_millis();
+    Some(Duration::from_millis(millis.max(0) as u64))
+}
+
+/// Sliding window limit on how many requests one client sends.
+///
+/// Shared between clones of the same client, so a sync and a command using the
+/// same `PatreonApi` draw from the same budget.
+#[derive(Debug)]
+pub struct RequestBudget {
+    max_requests: usize,
+    window: Duration,
+    sent: Mutex<VecDeque<Instant>>,
+}
+
+impl Default for RequestBudget {
+    fn default() -> Self {
+        Self::new(100, Duration::from_secs(60))
+    }
+}
+
+impl RequestBudget {
+    pub fn new(max_requests: usize, window: Duration) -> Self {
+        Self {
+            max_requests: max_requests.max(1),
+            window,
+            sent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(usize::MAX, Duration::ZERO)
+    }
+
+    /// Waits until the window has room, then records one request.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut sent = self.sent.lock().await;
+                let now = Instant::now();
+                while let Some(first) = sent.front() {
+                    if now.duration_since(*first) >= self.window {
+                        sent.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                match sent.front() {
+                    Some(first) if sent.len() >= self.max_requests => {
+                        self.window - now.duration_since(*first)
+                    }
+                    _ => {
+                        sent.push_back(now);
+                        return;
+                    }
+                }
+            };
+            tracing::debug!("Patreon request budget exhausted, waiting {wait:?}");
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn delay_backs_off_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(5),
+        };
+        assert_eq!(policy.delay(0, None), Some(Duration::from_millis(500)));
+        assert_eq!(policy.delay(1, None), Some(Duration::from_secs(1)));
+        assert_eq!(policy.delay(3, None), Some(Duration::from_secs(4)));
+        assert_eq!(policy.delay(4, None), Some(Duration::from_secs(5)));
+        assert_eq!(policy.delay(u32::MAX, None), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn delay_honours_retry_after_within_the_cap() {
+        let policy = RetryPolicy::default();
+        assert_eq!(
+            policy.delay(4, Some(Duration::from_secs(2))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(60))),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(policy.delay(0, Some(Duration::from_secs(61))), None);
+    }
+
+    #[test]
+    fn retry_after_in_seconds_or_as_a_date() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static(" 120 "));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(120)));
+
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&date).unwrap());
+        let delay = parse_retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+
+    #[test]
+    fn only_429_and_idempotent_5xx_are_retried() {
+        assert!(RetryPolicy::is_retryable(StatusCode::TOO_MANY_REQUESTS, false));
+        assert!(RetryPolicy::is_retryable(StatusCode::BAD_GATEWAY, true));
+        assert!(!RetryPolicy::is_retryable(StatusCode::BAD_GATEWAY, false));
+        assert!(!RetryPolicy::is_retryable(StatusCode::NOT_FOUND, true));
+    }
+
+    #[tokio::test]
+    async fn budget_waits_for_the_window_to_slide() {
+        let budget = RequestBudget::new(2, Duration::from_millis(200));
+        let start = Instant::now();
+        budget.acquire().await;
+        budget.acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        budget.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn unlimited_budget_never_waits() {
+        let budget = RequestBudget::unlimited();
+        let start = Instant::now();
+        for _ in 0..1000 {
+            budget.acquire().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+}
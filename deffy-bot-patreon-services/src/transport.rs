@@ -0,0 +1,78 @@
+use crate::PatreonResult;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+
+pub type TransportFuture<'a> = Pin<Box<dyn Future<Output = PatreonResult<HttpResponse>> + Send + 'a>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+/// Sends a fully built request and hands back the raw response.
+///
+/// `reqwest::Client` is the default; tests can swap in their own implementation
+/// to serve canned Patreon responses without a network.
+pub trait Transport: Debug + Send + Sync {
+    fn execute(&self, request: reqwest::Request) -> TransportFuture<'_>;
+}
+
+impl Transport for reqwest::Client {
+    fn execute(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        Box::pin(async move {
+            let response = reqwest::Client::execute(self, request).await?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = response.text().await?;
+            Ok(HttpResponse {
+                status,
+                headers,
+                body,
+            })
+        })
+    }
+}
+
+/// Serves queued responses in order and records the url of every request it gets.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct MockTransport {
+    responses: std::sync::Mutex<std::collections::VecDeque<HttpResponse>>,
+    pub(crate) requests: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub(crate) fn respond(self, status: StatusCode, body: &str) -> Self {
+        self.respond_with(HttpResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: body.to_string(),
+        })
+    }
+
+    pub(crate) fn respond_with(self, response: HttpResponse) -> Self {
+        self.responses.lock().unwrap().push_back(response);
+        self
+    }
+
+    pub(crate) fn requested(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Transport for MockTransport {
+    fn execute(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        self.requests.lock().unwrap().push(request.url().to_string());
+        let response = self.responses.lock().unwrap().pop_front();
+        Box::pin(async move {
+            response.ok_or_else(|| crate::PatreonError::Message("no response queued".to_string()))
+        })
+    }
+}
@@ -14,24 +14,8 @@ pub enum Event {
     DeleteMemberPledge(Member),
 }
 
-#[derive(Debug, Default)]
-pub struct Webhook {
-    pub webhook_secret: String,
-}
-
-impl Webhook {
-    pub fn check_signature(&self, body: &[u8], signature: &str) -> PatreonResult<bool> {
-        use hmac::{Hmac, Mac};
-        use md5::Md5;
-        type HmacMd5 = Hmac<Md5>;
-        let mut mac = HmacMd5::new_from_slice(self.webhook_secret.as_bytes())
-            .map_err(|_| PatreonError::Message("Invalid hmac key length".to_string()))?;
-        mac.update(body);
-        let local = hex::encode(mac.finalize().into_bytes().as_slice());
-        Ok(local.eq(signature))
-    }
-
-    pub fn parse_event(&self, body: &[u8], trigger: &str) -> PatreonResult<Event> {
+impl Event {
+    pub fn parse(body: &[u8], trigger: &str) -> PatreonResult<Event> {
         match trigger {
             "pledges:create" => Ok(Event::CreatePledge(DocResponse::parse(body)?)),
             "pledges:update" => Ok(Event::UpdatePledge(DocResponse::parse(body)?)),
@@ -45,4 +29,218 @@ impl Webhook {
             _ => Err(PatreonError::Message(format!("unknown trigger: {trigger}"))),
         }
     }
+
+    pub fn trigger(&self) -> &'static str {
+        match self {
+            Event::CreatePledge(_) => "pledges:create",
+            Event::UpdatePledge(_) => "pledges:update",
+            Event::DeletePledge(_) => "pledges:delete",
+            Event::CreateMember(_) => "members:create",
+            Event::UpdateMember(_) => "members:update",
+            Event::DeleteMember(_) => "members:delete",
+            Event::CreateMemberPledge(_) => "members:pledge:create",
+            Event::UpdateMemberPledge(_) => "members:pledge:update",
+            Event::DeleteMemberPledge(_) => "members:pledge:delete",
+        }
+    }
+
+    /// Id of the member or pledge the event is about.
+    pub fn resource_id(&self) -> &str {
+        match self {
+            Event::CreatePledge(pledge)
+            | Event::UpdatePledge(pledge)
+            | Event::DeletePledge(pledge) => &pledge.id,
+            Event::CreateMember(member)
+            | Event::UpdateMember(member)
+            | Event::DeleteMember(member)
+            | Event::CreateMemberPledge(member)
+            | Event::UpdateMemberPledge(member)
+            | Event::DeleteMemberPledge(member) => &member.id,
+        }
+    }
+
+    /// Identifies one delivery of this event. Patreon resends the same body when it
+    /// retries, so a repeated fingerprint means the delivery was already handled.
+    pub fn fingerprint(&self, body: &[u8]) -> String {
+        use sha2::{Digest, Sha256};
+        let body_hash = hex::encode(Sha256::digest(body));
+        format!("{}:{}:{}", self.trigger(), self.resource_id(), body_hash)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    /// What Patreon signs `X-Patreon-Signature` with.
+    #[default]
+    HmacMd5,
+    HmacSha256,
+}
+
+/// Why a delivery failed signature verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SignatureRejection {
+    NoSecretConfigured,
+    MissingSignature,
+    MalformedSignature,
+    Mismatch,
+}
+
+impl SignatureRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignatureRejection::NoSecretConfigured => "no_secret_configured",
+            SignatureRejection::MissingSignature => "missing_signature",
+            SignatureRejection::MalformedSignature => "malformed_signature",
+            SignatureRejection::Mismatch => "mismatch",
+        }
+    }
+}
+
+impl std::fmt::Display for SignatureRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::error::Error for SignatureRejection {}
+
+#[derive(Debug, Default, Clone)]
+pub struct Webhook {
+    /// Every secret currently accepted; keep the old one listed while rotating.
+    pub webhook_secrets: Vec<String>,
+    pub algorithm: SignatureAlgorithm,
+}
+
+impl Webhook {
+    pub fn new(secrets: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            webhook_secrets: secrets
+                .into_iter()
+                .map(Into::into)
+                .filter(|secret: &String| !secret.is_empty())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_algorithm(mut self, algorithm: SignatureAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Checks the hex `signature` (either case) of `body` against every active secret in
+    /// constant time and returns the index of the secret that matched.
+    pub fn verify_signature(
+        &self,
+        body: &[u8],
+        signature: &str,
+    ) -> Result<usize, SignatureRejection> {
+        if self.webhook_secrets.is_empty() {
+            return Err(SignatureRejection::NoSecretConfigured);
+        }
+        let signature = signature.trim();
+        if signature.is_empty() {
+            return Err(SignatureRejection::MissingSignature);
+        }
+        let expected =
+            hex::decode(signature).map_err(|_| SignatureRejection::MalformedSignature)?;
+
+        self.webhook_secrets
+            .iter()
+            .position(|secret| self.verify_with(secret.as_bytes(), body, &expected))
+            .ok_or(SignatureRejection::Mismatch)
+    }
+
+    fn verify_with(&self, secret: &[u8], body: &[u8], expected: &[u8]) -> bool {
+        use hmac::{Hmac, Mac};
+        use md5::Md5;
+        use sha2::Sha256;
+        match self.algorithm {
+            SignatureAlgorithm::HmacMd5 => Hmac::<Md5>::new_from_slice(secret)
+                .map(|mac| mac.chain_update(body).verify_slice(expected).is_ok())
+                .unwrap_or(false),
+            SignatureAlgorithm::HmacSha256 => Hmac::<Sha256>::new_from_slice(secret)
+                .map(|mac| mac.chain_update(body).verify_slice(expected).is_ok())
+                .unwrap_or(false),
+        }
+    }
+
+    pub fn parse_event(&self, body: &[u8], trigger: &str) -> PatreonResult<Event> {
+        Event::parse(body, trigger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 2104 and RFC 4231 test vectors
+    const BODY: &[u8] = b"what do ya want for nothing?";
+    const MD5_SIGNATURE: &str = "750c783e6ab0b503eaa86e310a5db738";
+    const SHA256_SIGNATURE: &str =
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+
+    #[test]
+    fn verifies_md5_by_default() {
+        let webhook = Webhook::new(["Jefe"]);
+        assert_eq!(webhook.verify_signature(BODY, MD5_SIGNATURE), Ok(0));
+        assert_eq!(
+            webhook.verify_signature(BODY, SHA256_SIGNATURE),
+            Err(SignatureRejection::Mismatch)
+        );
+    }
+
+    #[test]
+    fn verifies_sha256() {
+        let webhook = Webhook::new(["Jefe"]).with_algorithm(SignatureAlgorithm::HmacSha256);
+        assert_eq!(webhook.verify_signature(BODY, SHA256_SIGNATURE), Ok(0));
+        assert_eq!(
+            webhook.verify_signature(BODY, MD5_SIGNATURE),
+            Err(SignatureRejection::Mismatch)
+        );
+    }
+
+    #[test]
+    fn hex_case_and_whitespace_dont_matter() {
+        let webhook = Webhook::new(["Jefe"]);
+        let upper = MD5_SIGNATURE.to_uppercase();
+        assert_eq!(webhook.verify_signature(BODY, &upper), Ok(0));
+        assert_eq!(webhook.verify_signature(BODY, &format!(" {MD5_SIGNATURE}\n")), Ok(0));
+    }
+
+    #[test]
+    fn rotated_secret_is_still_accepted() {
+        let webhook = Webhook::new(["new-secret", "", "Jefe"]);
+        assert_eq!(webhook.webhook_secrets.len(), 2);
+        assert_eq!(webhook.verify_signature(BODY, MD5_SIGNATURE), Ok(1));
+    }
+
+    #[test]
+    fn bad_signatures_are_rejected() {
+        let webhook = Webhook::new(["Jefe"]);
+        assert_eq!(
+            webhook.verify_signature(b"what do ya want for something?", MD5_SIGNATURE),
+            Err(SignatureRejection::Mismatch)
+        );
+        assert_eq!(
+            webhook.verify_signature(BODY, &MD5_SIGNATURE[..30]),
+            Err(SignatureRejection::Mismatch)
+        );
+        assert_eq!(
+            webhook.verify_signature(BODY, "not hex at all"),
+            Err(SignatureRejection::MalformedSignature)
+        );
+        assert_eq!(
+            webhook.verify_signature(BODY, &MD5_SIGNATURE[..31]),
+            Err(SignatureRejection::MalformedSignature)
+        );
+        assert_eq!(
+            webhook.verify_signature(BODY, "  "),
+            Err(SignatureRejection::MissingSignature)
+        );
+        assert_eq!(
+            Webhook::new([""]).verify_signature(BODY, MD5_SIGNATURE),
+            Err(SignatureRejection::NoSecretConfigured)
+        );
+    }
 }
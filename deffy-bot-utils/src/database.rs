@@ -1,11 +1,17 @@
-use std::{env, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Error;
 use chrono::{DateTime, Utc};
-use deffy_bot_patreon_services::{LastChrgeStatus, PatreonApi, PatronStatus};
+use deffy_bot_patreon_services::{CampaignSelector, LastChrgeStatus, Member, PatreonApi, PatronStatus};
+use serenity::futures::{self, TryStreamExt};
 use mongodb::{
-    Client, Collection, Database,
-    bson::{doc, to_bson},
+    Client, Database,
+    bson::doc,
 };
 use serde::{Deserialize, Serialize};
 use tokio::{
@@ -15,17 +21,36 @@ use tokio::{
 
 use mongodb::bson::DateTime as BsonDateTime;
 
+use crate::event::manager::{EVENT_MANAGER, EventTypeData};
+use crate::email_verification::{EmailVerification, SendCodeOutcome};
+use crate::guild_settings::{GuildSettings, GuildSettingsDatabase};
+use crate::member_binding::{BindOutcome, MemberBindingDatabase};
+use crate::repository::{PatronRepository, Repositories};
+use crate::scheduler::Scheduler;
+use crate::sync::{
+    MemberChange, SyncDiffCounts, SyncProgress, SyncReport, SyncRun, SyncRunDatabase, SyncTrigger,
+};
+
 static DB: OnceCell<Arc<Database>> = OnceCell::const_new();
 static TX_EVENT: OnceCell<mpsc::UnboundedSender<ScheduleMessage>> = OnceCell::const_new();
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DatabaseWebHookEvent {
     CreateMember,
     UpdateMember,
     DeleteMember,
+    CreatePledge,
+    UpgradePledge,
+    DowngradePledge,
+    DeletePledge,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PatreonUserData {
+    #[serde(default)]
+    pub patreon_member_id: Option<String>,
+    #[serde(default)]
+    pub patreon_user_id: Option<String>,
     pub patreon_email: Option<String>,
     pub patreon_username: String,
     pub patreon_status: Option<PatronStatus>,
@@ -33,50 +58,99 @@ pub struct PatreonUserData {
     pub last_charge_status: Option<LastChrgeStatus>,
     pub next_charge_date: Option<BsonDateTime>,
     pub lifetime_support_cents: i64,
+    #[serde(default)]
+    pub currently_entitled_amount_cents: i64,
+    #[serde(default)]
+    pub tier_ids: Vec<String>,
+}
+
+impl PatreonUserData {
+    pub fn from_member(member: &Member) -> Self {
+        let attr = &member.attributes;
+        Self {
+            patreon_member_id: Some(member.id.clone()),
+            patreon_user_id: member.user_id().map(str::to_string),
+            patreon_email: attr.email.clone(),
+            patreon_username: attr.full_name.clone(),
+            patreon_status: attr.patron_status,
+            last_charge_date: attr.last_charge_date.map(convert_to_bson_datetime),
+            next_charge_date: attr.next_charge_date.map(convert_to_bson_datetime),
+            last_charge_status: attr.last_charge_status,
+            lifetime_support_cents: attr.lifetime_support_cents,
+            currently_entitled_amount_cents: attr.currently_entitled_amount_cents,
+            tier_ids: member
+                .entitled_tier_ids()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ScheduleMessage {
     Info(String),
     Error(String),
-    ForceUpdate,
+    ForceUpdate(SyncTrigger),
 }
 
 pub struct DatabaseManager {}
 
 impl DatabaseManager {
     pub async fn init_db() -> Result<Self, Error> {
-        let mongo_uri = env::var("MONGO_URI").expect("MONGO_URI must be set");
+        let mongo_uri = env::var("MONGO_URI").map_err(|_| anyhow::anyhow!("MONGO_URI must be set"))?;
 
         let mongo_client = Arc::new(Client::with_uri_str(mongo_uri).await?);
 
         let db: Database = mongo_client.database("patreon_api_data");
 
-        DB.set(Arc::new(db)).expect("Already initialized");
+        DB.set(Arc::new(db)).map_err(|_| anyhow::anyhow!("Database already initialized"))?;
+        Repositories::install(Repositories::mongo());
 
         Ok(Self {})
     }
 
+    /// Panics without a database, only for code that runs once `init_db` succeeded.
     pub fn get_db() -> Arc<Database> {
         DB.get().expect("DB not initialized").clone()
     }
 
+    /// For the collections that have no in-memory repository, which are unavailable
+    /// when the bot runs without MongoDB.
+    pub fn require_db() -> Result<Arc<Database>, Error> {
+        DB.get()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("This needs MongoDB, the bot runs on in-memory storage"))
+    }
+
+    pub fn is_initialized() -> bool {
+        DB.initialized()
+    }
+
     pub fn force_update_patreon_data() {
-        if let Some(tx) = TX_EVENT.get() {
-            let _ = tx.send(ScheduleMessage::ForceUpdate);
+        Self::request_sync(SyncTrigger::Webhook);
+    }
+
+    /// Asks the collector for a sync run, `false` if the collector isn't running.
+    pub fn request_sync(trigger: SyncTrigger) -> bool {
+        match TX_EVENT.get() {
+            Some(tx) => tx.send(ScheduleMessage::ForceUpdate(trigger)).is_ok(),
+            None => false,
         }
     }
 
     pub async fn start_collect(&self) -> Result<(), Error> {
-        let db = Self::get_db();
-
         let (tx_log, mut rx_log) = mpsc::unbounded_channel::<ScheduleMessage>();
 
         let (tx_event, rx_event) = mpsc::unbounded_channel::<ScheduleMessage>();
 
         TX_EVENT.set(tx_event).expect("TX_EVENT already set");
 
-        Self::collect_patreon_api_data_db_loop(db, rx_event, tx_log).await?;
+        if let Err(e) = Scheduler::load().await {
+            tracing::error!("Failed to load the sync schedule, using the env config: {:?}", e);
+        }
+
+        Self::collect_patreon_api_data_db_loop(rx_event, tx_log).await?;
 
         tokio::spawn(async move {
             while let Some(msg) = rx_log.recv().await {
@@ -89,45 +163,52 @@ impl DatabaseManager {
             }
         });
 
-        Self::force_update_patreon_data();
+        Self::request_sync(SyncTrigger::Timer);
 
         Ok(())
     }
 
     async fn collect_patreon_api_data_db_loop(
-        db: Arc<Database>,
         mut rx: mpsc::UnboundedReceiver<ScheduleMessage>,
         tx: mpsc::UnboundedSender<ScheduleMessage>,
     ) -> Result<(), Error> {
         tokio::spawn(async move {
-            let collection: Collection<PatreonUserData> = db.collection("user_data");
+            let patrons = Repositories::get().patrons.clone();
 
-            let api = PatreonApi {
+            let mut api = PatreonApi {
                 access_token: env::var("PATREON_ACCESS_TOKEN")
                     .expect("PATREON_ACCESS_TOKEN must be set"),
                 ..Default::default()
             };
 
+            let mut schedule = Scheduler::subscribe();
+
             loop {
-                let interval = sleep(Duration::from_secs(86400));
+                let config = schedule.borrow_and_update().clone();
+                let interval = sleep(config.next_delay());
                 tokio::pin!(interval);
 
                 tokio::select! {
 
                     _ = &mut interval => {
-                    if let Err(e) = Self::fetch_update_patreon_data(&api, &collection, &tx).await {
+                    if let Err(e) = Self::run_sync(&mut api, patrons.as_ref(), &tx, SyncTrigger::Timer).await {
                         let _ = tx.send(ScheduleMessage::Error(format!(
                             "Failed to fetch and update Patreon data: {}", e
                         )));
                     } else {
-                        let _ = tx.send(ScheduleMessage::Info("24 hours passed, updating Patreon data".to_string()));
+                        let _ = tx.send(ScheduleMessage::Info(format!("Scheduled Patreon sync ({})", config.schedule)));
                     }
                 }
 
+                 Ok(()) = schedule.changed() => {
+                    let _ = tx.send(ScheduleMessage::Info("Patreon sync schedule changed".to_string()));
+                 }
+
                  Some(msg) = rx.recv() => {
                     match msg {
-                        ScheduleMessage::ForceUpdate => {
-                            if let Err(e) = Self::fetch_update_patreon_data(&api, &collection, &tx).await {
+                        ScheduleMessage::ForceUpdate(trigger) => {
+                            let trigger = Scheduler::coalesce(&mut rx, trigger).await;
+                            if let Err(e) = Self::run_sync(&mut api, patrons.as_ref(), &tx, trigger).await {
                                 let _ = tx.send(ScheduleMessage::Error(format!(
                                     "Failed to fetch and update Patreon data (force): {}", e
                                 )));
@@ -150,84 +231,180 @@ impl DatabaseManager {
         Ok(())
     }
 
-    async fn fetch_update_patreon_data(
-        api: &PatreonApi,
-        collection: &Collection<PatreonUserData>,
+    /// Runs one sync, publishing its progress and recording it in `sync_runs`.
+    async fn run_sync(
+        api: &mut PatreonApi,
+        patrons: &dyn PatronRepository,
         tx: &mpsc::UnboundedSender<ScheduleMessage>,
-    ) -> Result<(), Error> {
-        let api_rsp = api.all_members().await;
-        match api_rsp {
-            Ok(api_rsp) => {
-                let api_vec: Vec<PatreonUserData> = api_rsp
-                    .iter()
-                    .filter_map(|member| {
-                        let attr = &member.attributes;
-
-                        Some(PatreonUserData {
-                            patreon_email: attr.email.clone(),
-                            patreon_username: attr.full_name.clone(),
-                            patreon_status: attr.patron_status.clone(),
-                            last_charge_date: attr.last_charge_date.map(convert_to_bson_datetime),
-                            next_charge_date: attr.next_charge_date.map(convert_to_bson_datetime),
-                            last_charge_status: attr.last_charge_status,
-                            lifetime_support_cents: attr.lifetime_support_cents,
-                        })
-                    })
-                    .collect();
-
-                let _ = tx.send(ScheduleMessage::Info(format!(
-                    "Updated {} items",
-                    api_vec.len()
-                )));
-
-                // Clear the collection before inserting new data
-                if let Err(e) = collection.delete_many(doc! {}).await {
-                    let _ = tx.send(ScheduleMessage::Error(format!("DB clear error: {e}")));
-                }
+        trigger: SyncTrigger,
+    ) -> Result<SyncReport, Error> {
+        let mut progress = SyncProgress::start(trigger);
 
-                if let Err(e) = collection.insert_many(api_vec).await {
-                    let _ = tx.send(ScheduleMessage::Error(format!(" DB insert error: {e}")));
-                }
-            }
-            Err(e) => {
-                let _ = tx.send(ScheduleMessage::Error(format!(" DB error: {e}")));
-            }
+        let result = Self::fetch_update_patreon_data(api, patrons, tx, &mut progress).await;
+
+        let diff = match &result {
+            Ok(report) => SyncDiffCounts::from(report),
+            Err(_) => SyncDiffCounts::default(),
+        };
+        progress.error = result.as_ref().err().map(|e| e.to_string());
+        progress.finished = true;
+        progress.publish();
+
+        let run = SyncRun {
+            trigger,
+            started_at: convert_to_bson_datetime(progress.started_at),
+            ended_at: BsonDateTime::now(),
+            pages: progress.pages,
+            members: progress.members,
+            diff,
+            error: progress.error.clone(),
+        };
+        if let Err(e) = SyncRunDatabase::record_run(run).await {
+            let _ = tx.send(ScheduleMessage::Error(format!("Failed to record sync run: {e}")));
         }
-        Ok(())
+
+        result
     }
 
-    pub async fn update_discord_server_data<T>(sv_id: u64, key: &str, value: T) -> Result<(), Error>
-    where
-        T: Serialize,
-    {
-        let db = DatabaseManager::get_db();
-        let collection: Collection<DiscordServerData> = db.collection("server_data");
+    async fn fetch_update_patreon_data(
+        api: &mut PatreonApi,
+        patrons: &dyn PatronRepository,
+        tx: &mpsc::UnboundedSender<ScheduleMessage>,
+        progress: &mut SyncProgress,
+    ) -> Result<SyncReport, Error> {
+        if api.campaign_id.is_none() {
+            let campaign = api.resolve_campaign(&CampaignSelector::from_env()).await?;
+            let _ = tx.send(ScheduleMessage::Info(format!(
+                "Using Patreon campaign {} ({})",
+                campaign.campaign.id, campaign.campaign.attributes.creation_name
+            )));
+        }
 
-        let filter = doc! { "server_id": sv_id as i64 };
+        let previous: HashMap<String, PatreonUserData> = patrons
+            .all()
+            .await?
+            .into_iter()
+            .filter_map(|data| Some((data.patreon_member_id.clone()?, data)))
+            .collect();
+
+        let mut report = SyncReport::default();
+        let mut seen: HashSet<String> = HashSet::new();
+
+        // Upsert page by page so readers never see a half-empty collection
+        let pages = api.members_stream(None);
+        futures::pin_mut!(pages);
+        while let Some(page) = pages.try_next().await? {
+            report.pages += 1;
+            for member in &page.members {
+                let data = PatreonUserData::from_member(member);
+                report
+                    .changes
+                    .extend(MemberChange::between(previous.get(&member.id), &data));
+
+                patrons.upsert(&data).await?;
+
+                seen.insert(member.id.clone());
+                report.members += 1;
+            }
 
-        let bson_value = to_bson(&value).map_err(|e| Error::from(e))?;
+            progress.pages = report.pages;
+            progress.members = report.members;
+            progress.changes = report.changes.len();
+            progress.publish();
+        }
 
-        let update = doc! {
-            "$set": {
-                key: bson_value,
+        for (member_id, data) in previous.iter() {
+            if !seen.contains(member_id) {
+                report.changes.push(MemberChange::left(data.clone()));
             }
-        };
+        }
+
+        // Also drops rows stored before member ids were recorded
+        patrons.retain(&seen).await?;
 
-        if let Err(e) = collection.update_one(filter, update).upsert(true).await {
-            return Err(e.into());
+        for change in &mut report.changes {
+            change.campaign_id = api.campaign_id.clone();
         }
-        Ok(())
+
+        let _ = tx.send(ScheduleMessage::Info(format!(
+            "Synced {} members over {} pages, {} changes",
+            report.members,
+            report.pages,
+            report.changes.len()
+        )));
+
+        if previous.is_empty() {
+            // Nothing to compare against yet, every member would look like they just joined
+            let _ = tx.send(ScheduleMessage::Info(
+                "First sync, not emitting member changes".to_string(),
+            ));
+        } else {
+            let manager = EVENT_MANAGER.lock().await;
+            for change in &report.changes {
+                manager
+                    .emit(change.kind.event_type(), EventTypeData::PatreonMemberChange(change.clone()))
+                    .await;
+            }
+        }
+
+        Ok(report)
     }
 
-    pub async fn get_discord_server_data(sv_id: u64) -> Result<DiscordServerData, Error> {
-        let db = DatabaseManager::get_db();
-        let collection: Collection<DiscordServerData> = db.collection("server_data");
+    pub async fn get_patreon_member(member_id: &str) -> Result<Option<PatreonUserData>, Error> {
+        Repositories::get().patrons.find_by_member_id(member_id).await
+    }
 
-        let filter = doc! { "server_id": sv_id as i64 };
+    pub async fn get_patreon_member_by_user(
+        user_id: &str,
+    ) -> Result<Option<PatreonUserData>, Error> {
+        Repositories::get().patrons.find_by_user_id(user_id).await
+    }
 
-        let data = collection.find_one(filter).await?;
+    pub async fn get_patreon_member_by_email(email: &str) -> Result<Option<PatreonUserData>, Error> {
+        Repositories::get().patrons.find_by_email(email).await
+    }
+}
 
-        Ok(data.unwrap())
+/// What a verification step came to, each one is reported to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationOutcome {
+    /// The email may be used, a code was mailed to confirm it.
+    CodeSent,
+    Verified,
+    /// The membership was verified by this Discord account before.
+    AlreadyVerified,
+    /// The membership was verified by another Discord account.
+    AlreadyClaimed,
+    /// Paid patrons get their roles through the Patreon Discord integration.
+    ActivePatronMustUseIntegration,
+    NotFound,
+    FormerPatron,
+    RateLimited { retry_after: Duration },
+}
+
+impl VerificationOutcome {
+    /// Localization key of the reply.
+    pub fn message_key(&self) -> &'static str {
+        match self {
+            VerificationOutcome::CodeSent => "verify_code_sent",
+            VerificationOutcome::Verified => "verify_success",
+            VerificationOutcome::AlreadyVerified => "verify_already_verified",
+            VerificationOutcome::AlreadyClaimed => "verify_already_claimed",
+            VerificationOutcome::ActivePatronMustUseIntegration => "verify_already_active_member_error",
+            VerificationOutcome::NotFound => "verify_not_found",
+            VerificationOutcome::FormerPatron => "verify_former_patron",
+            VerificationOutcome::RateLimited { .. } => "verify_code_rate_limited",
+        }
+    }
+}
+
+impl From<BindOutcome> for VerificationOutcome {
+    fn from(outcome: BindOutcome) -> Self {
+        match outcome {
+            BindOutcome::Bound => VerificationOutcome::Verified,
+            BindOutcome::AlreadyBound => VerificationOutcome::AlreadyVerified,
+            BindOutcome::ClaimedByOther { .. } => VerificationOutcome::AlreadyClaimed,
+        }
     }
 }
 
@@ -240,83 +417,127 @@ impl PatreonVerification {
         Self { patreon_email }
     }
 
-    pub async fn verify(&self) -> Result<(bool, &String), Error> {
-        let db = DatabaseManager::get_db();
+    /// First step, checks the membership of the email and mails the code when it can
+    /// verify `discord_id`. Free and declined members can, paid and former patrons can't.
+    pub async fn verify(&self, discord_id: u64, guild_id: u64) -> Result<VerificationOutcome, Error> {
+        let Some(patron) = Repositories::get()
+            .patrons
+            .find_by_email(&self.patreon_email)
+            .await?
+        else {
+            return Ok(VerificationOutcome::NotFound);
+        };
 
-        let collection: Collection<PatreonUserData> = db.collection("user_data");
+        match patron.patreon_status {
+            Some(PatronStatus::ActivePatron) => {
+                return Ok(VerificationOutcome::ActivePatronMustUseIntegration);
+            }
+            Some(PatronStatus::FormerPatron) => return Ok(VerificationOutcome::FormerPatron),
+            _ => {}
+        }
 
-        let verify = collection
-            .find_one(doc! { "patreon_email": self.patreon_email.clone() })
-            .await?;
+        let binding = match &patron.patreon_member_id {
+            Some(member_id) => MemberBindingDatabase::get_by_member_id(member_id).await?,
+            None => None,
+        };
+        if let Some(binding) = binding {
+            return Ok(match binding.discord_id == discord_id {
+                true => VerificationOutcome::AlreadyVerified,
+                false => VerificationOutcome::AlreadyClaimed,
+            });
+        }
 
-        let is_active = verify.as_ref().and_then(|v| v.patreon_status);
+        Ok(
+            match EmailVerification::send_code(discord_id, guild_id, &self.patreon_email).await? {
+                SendCodeOutcome::Sent => VerificationOutcome::CodeSent,
+                SendCodeOutcome::RateLimited { retry_after } => {
+                    VerificationOutcome::RateLimited { retry_after }
+                }
+            },
+        )
+    }
 
-        if let Some(PatronStatus::ActivePatron) = is_active {
-            return Err(anyhow::Error::msg("409"));
-        }
+    /// Second step, once the mailed code came back. Binds the membership to `discord_id`.
+    pub async fn confirm(
+        &self,
+        discord_id: u64,
+        guild_id: u64,
+        locale: Option<String>,
+    ) -> Result<VerificationOutcome, Error> {
+        let Some(member_id) = self.member_id().await? else {
+            return Ok(VerificationOutcome::NotFound);
+        };
 
-        Ok((
-            verify.as_ref().is_some()
-                && verify.as_ref().unwrap().patreon_status.is_some()
-                && !(verify.as_ref().unwrap().patreon_status.unwrap()
-                    == PatronStatus::ActivePatron),
-            &self.patreon_email,
-        ))
+        let bound =
+            MemberBindingDatabase::bind(&member_id, discord_id, guild_id, &self.patreon_email, locale)
+                .await?;
+        Ok(bound.into())
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DiscordServerData {
-    pub verify_role_id: u64,
-    pub log_channel_id: u64,
-    pub webhook_create_member_channel_id: u64,
-    pub webhook_update_member_channel_id: u64,
-    pub webhook_delete_member_channel_id: u64,
+    async fn member_id(&self) -> Result<Option<String>, Error> {
+        Ok(Repositories::get()
+            .patrons
+            .find_by_email(&self.patreon_email)
+            .await?
+            .and_then(|patron| patron.patreon_member_id))
+    }
 }
 
 pub struct DiscordServerDatabaseManager {}
 
 impl DiscordServerDatabaseManager {
-    pub async fn get_verify_roles() -> Option<u64> {
-        let guild_id = env::var("GUILD_ID").expect("GUILD_ID must be set");
-
-        let data = DatabaseManager::get_discord_server_data(guild_id.parse::<u64>().unwrap()).await;
+    pub async fn get_settings(sv_id: u64) -> Result<GuildSettings, Error> {
+        GuildSettingsDatabase::get(sv_id).await
+    }
 
-        if let Ok(data) = data {
-            return Some(data.verify_role_id);
-        }
-        None
+    pub async fn get_verify_roles(sv_id: u64) -> Option<u64> {
+        Self::get_settings(sv_id).await.ok()?.verify_role_id
     }
 
-    pub async fn get_logging_channel() -> Option<u64> {
-        let guild_id = env::var("GUILD_ID").expect("GUILD_ID must be set");
+    pub async fn get_logging_channel(sv_id: u64) -> Option<u64> {
+        Self::get_settings(sv_id).await.ok()?.log_channel_id
+    }
 
-        let data = DatabaseManager::get_discord_server_data(guild_id.parse::<u64>().unwrap()).await;
+    pub async fn get_webhook_channel(sv_id: u64, event: DatabaseWebHookEvent) -> Option<u64> {
+        Self::get_settings(sv_id).await.ok()?.webhook_channels.get(event)
+    }
 
-        if let Ok(data) = data {
-            return Some(data.log_channel_id);
-        }
-        None
+    /// Every server that should receive events of `campaign_id`.
+    pub async fn get_campaign_servers(campaign_id: Option<&str>) -> Result<Vec<GuildSettings>, Error> {
+        GuildSettingsDatabase::get_campaign_servers(campaign_id).await
     }
 
-    pub async fn get_webhook_patreon_channel() -> Option<DiscordServerData> {
-        let guild_id = env::var("GUILD_ID").expect("GUILD_ID must be set");
+    pub async fn set_campaign(sv_id: u64, campaign_id: Option<String>) -> Result<(), Error> {
+        GuildSettingsDatabase::update(sv_id, |settings| settings.campaign_id = campaign_id).await?;
+        Ok(())
+    }
 
-        let data = DatabaseManager::get_discord_server_data(guild_id.parse::<u64>().unwrap()).await;
+    pub async fn set_verify_roles(sv_id: u64, id: u64) -> Result<(), Error> {
+        GuildSettingsDatabase::update(sv_id, |settings| settings.verify_role_id = Some(id)).await?;
+        Ok(())
+    }
 
-        if let Ok(data) = data {
-            return Some(data);
-        }
-        None
+    /// Maps `tier_id` to `role_id`, or removes the mapping when `role_id` is `None`.
+    pub async fn set_tier_role(sv_id: u64, tier_id: String, role_id: Option<u64>) -> Result<(), Error> {
+        GuildSettingsDatabase::update(sv_id, |settings| match role_id {
+            Some(role_id) => {
+                settings.tier_roles.insert(tier_id, role_id);
+            }
+            None => {
+                settings.tier_roles.remove(&tier_id);
+            }
+        })
+        .await?;
+        Ok(())
     }
 
-    pub async fn set_verify_roles(sv_id: u64, id: u64) -> Result<(), Error> {
-        DatabaseManager::update_discord_server_data(sv_id, "verify_role_id", id).await?;
+    pub async fn set_grace_period(sv_id: u64, hours: u32) -> Result<(), Error> {
+        GuildSettingsDatabase::update(sv_id, |settings| settings.grace_period_hours = hours).await?;
         Ok(())
     }
 
     pub async fn set_logging_channel(sv_id: u64, channel_id: u64) -> Result<(), Error> {
-        DatabaseManager::update_discord_server_data(sv_id, "log_channel_id", channel_id).await?;
+        GuildSettingsDatabase::update(sv_id, |settings| settings.log_channel_id = Some(channel_id)).await?;
         Ok(())
     }
 
@@ -325,36 +546,71 @@ impl DiscordServerDatabaseManager {
         channel_id: u64,
         event: DatabaseWebHookEvent,
     ) -> Result<(), Error> {
-        match event {
-            DatabaseWebHookEvent::CreateMember => {
-                DatabaseManager::update_discord_server_data(
-                    sv_id,
-                    "webhook_create_member_channel_id",
-                    channel_id,
-                )
-                .await?;
-            }
-            DatabaseWebHookEvent::UpdateMember => {
-                DatabaseManager::update_discord_server_data(
-                    sv_id,
-                    "webhook_update_member_channel_id",
-                    channel_id,
-                )
-                .await?;
-            }
-            DatabaseWebHookEvent::DeleteMember => {
-                DatabaseManager::update_discord_server_data(
-                    sv_id,
-                    "webhook_delete_member_channel_id",
-                    channel_id,
-                )
-                .await?;
-            }
-        }
+        GuildSettingsDatabase::update(sv_id, |settings| {
+            settings.webhook_channels.set(event, Some(channel_id))
+        })
+        .await?;
         Ok(())
     }
 }
 
 fn convert_to_bson_datetime(chrono_dt: DateTime<Utc>) -> BsonDateTime {
     BsonDateTime::from_millis(chrono_dt.timestamp_millis())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{in_memory_repositories, patron};
+
+    use super::*;
+
+    async fn store(member_id: &str, email: &str, status: Option<PatronStatus>) {
+        let data = PatreonUserData {
+            patreon_status: status,
+            ..patron(member_id, email)
+        };
+        in_memory_repositories().patrons.upsert(&data).await.unwrap();
+    }
+
+    async fn verify(email: &str, discord_id: u64) -> VerificationOutcome {
+        PatreonVerification::new(email.to_string()).verify(discord_id, 7).await.unwrap()
+    }
+
+    async fn confirm(email: &str, discord_id: u64) -> VerificationOutcome {
+        PatreonVerification::new(email.to_string())
+            .confirm(discord_id, 7, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn verify_refuses_unknown_and_paid_emails() {
+        store("verify-active", "active@example.com", Some(PatronStatus::ActivePatron)).await;
+        store("verify-former", "former@example.com", Some(PatronStatus::FormerPatron)).await;
+
+        assert_eq!(verify("nobody@example.com", 2501).await, VerificationOutcome::NotFound);
+        assert_eq!(
+            verify("active@example.com", 2501).await,
+            VerificationOutcome::ActivePatronMustUseIntegration
+        );
+        assert_eq!(verify("former@example.com", 2501).await, VerificationOutcome::FormerPatron);
+    }
+
+    #[tokio::test]
+    async fn verify_reports_bound_memberships() {
+        store("verify-bound", "bound@example.com", None).await;
+        assert_eq!(confirm("bound@example.com", 2502).await, VerificationOutcome::Verified);
+
+        assert_eq!(verify("bound@example.com", 2502).await, VerificationOutcome::AlreadyVerified);
+        assert_eq!(verify("bound@example.com", 2503).await, VerificationOutcome::AlreadyClaimed);
+    }
+
+    #[tokio::test]
+    async fn confirm_binds_the_membership_once() {
+        store("confirm-1", "confirm@example.com", Some(PatronStatus::DeclinedPatron)).await;
+
+        assert_eq!(confirm("missing@example.com", 2504).await, VerificationOutcome::NotFound);
+        assert_eq!(confirm("confirm@example.com", 2504).await, VerificationOutcome::Verified);
+        assert_eq!(confirm("confirm@example.com", 2504).await, VerificationOutcome::AlreadyVerified);
+        assert_eq!(confirm("confirm@example.com", 2505).await, VerificationOutcome::AlreadyClaimed);
+    }
+}
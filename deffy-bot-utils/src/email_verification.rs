@@ -0,0 +1,356 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use mongodb::bson::DateTime as BsonDateTime;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::mail::{Mail, MailTransport, mailer};
+use crate::repository::Repositories;
+
+/// How long a code can be entered after it was sent.
+pub const CODE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Wrong guesses allowed before the code is thrown away.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Minimum time between two codes for the same user.
+const RESEND_INTERVAL: Duration = Duration::from_secs(60);
+
+/// At most `MAX_SENDS` codes per user within this window.
+const SEND_WINDOW: Duration = Duration::from_secs(60 * 60);
+const MAX_SENDS: usize = 5;
+
+/// The code a Discord user was sent, one pending per user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerificationCode {
+    pub discord_id: u64,
+    pub guild_id: u64,
+    pub email: String,
+    /// Only a hash is kept, see `hash_code`.
+    pub code_hash: String,
+    pub attempts: u32,
+    pub expires_at: BsonDateTime,
+    /// When codes were sent within `SEND_WINDOW`, for the rate limit.
+    pub sent_at: Vec<BsonDateTime>,
+}
+
+impl VerificationCode {
+    pub fn is_expired(&self) -> bool {
+        BsonDateTime::now() >= self.expires_at
+    }
+}
+
+/// Codes sent to one address, so switching Discord accounts doesn't get around the limit.
+/// Same limits as per user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailSends {
+    /// Trimmed and lowercased.
+    #[serde(rename = "_id")]
+    pub address: String,
+    pub sent_at: Vec<BsonDateTime>,
+    /// `SEND_WINDOW` after the last send, when none of them counts any more.
+    pub expires_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendCodeOutcome {
+    Sent,
+    RateLimited { retry_after: Duration },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodeCheck {
+    Verified { email: String, guild_id: u64 },
+    Wrong { attempts_left: u32 },
+    Expired,
+    TooManyAttempts,
+    NotRequested,
+}
+
+pub struct EmailVerification {}
+
+impl EmailVerification {
+    /// Mails a new code to `email`, replacing any pending one of the user.
+    pub async fn send_code(discord_id: u64, guild_id: u64, email: &str) -> Result<SendCodeOutcome, Error> {
+        let mailer = mailer().ok_or_else(|| anyhow::anyhow!("Mail is not configured"))?;
+        Self::send_code_with(mailer.as_ref(), discord_id, guild_id, email).await
+    }
+
+    async fn send_code_with(
+        mailer: &dyn MailTransport,
+        discord_id: u64,
+        guild_id: u64,
+        email: &str,
+    ) -> Result<SendCodeOutcome, Error> {
+        let repository = &Repositories::get().verification_codes;
+        let now = BsonDateTime::now().timestamp_millis();
+        let in_window = |at: &BsonDateTime| now - at.timestamp_millis() < SEND_WINDOW.as_millis() as i64;
+
+        let mut sent_at: Vec<BsonDateTime> = repository
+            .get(discord_id)
+            .await?
+            .map(|pending| pending.sent_at)
+            .unwrap_or_default();
+        sent_at.retain(in_window);
+
+        let address = email.trim().to_lowercase();
+        let mut email_sent_at = repository
+            .email_sends(&address)
+            .await?
+            .map(|sends| sends.sent_at)
+            .unwrap_or_default();
+        email_sent_at.retain(in_window);
+
+        let retry_after = Self::retry_after(&sent_at, now).max(Self::retry_after(&email_sent_at, now));
+        if let Some(retry_after) = retry_after {
+            return Ok(SendCodeOutcome::RateLimited { retry_after });
+        }
+
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        sent_at.push(BsonDateTime::from_millis(now));
+
+        mailer
+            .send(&Mail {
+                to: email.to_string(),
+                subject: "Your verification code".to_string(),
+                body: format!(
+                    "Your Discord verification code is {}.\nIt expires in {} minutes. If you didn't ask for it, ignore this mail.",
+                    code,
+                    CODE_TTL.as_secs() / 60
+                ),
+            })
+            .await?;
+
+        email_sent_at.push(BsonDateTime::from_millis(now));
+        repository
+            .save_email_sends(&EmailSends {
+                address,
+                sent_at: email_sent_at,
+                expires_at: BsonDateTime::from_millis(now + SEND_WINDOW.as_millis() as i64),
+            })
+            .await?;
+
+        repository
+            .save(&VerificationCode {
+                discord_id,
+                guild_id,
+                email: email.to_string(),
+                code_hash: hash_code(discord_id, &code),
+                attempts: 0,
+                expires_at: BsonDateTime::from_millis(now + CODE_TTL.as_millis() as i64),
+                sent_at,
+            })
+            .await?;
+
+        Ok(SendCodeOutcome::Sent)
+    }
+
+    fn retry_after(sent_at: &[BsonDateTime], now: i64) -> Option<Duration> {
+        let wait_until = |at: &BsonDateTime, window: Duration| {
+            Duration::from_millis((at.timestamp_millis() + window.as_millis() as i64 - now).max(0) as u64)
+        };
+
+        if let Some(last) = sent_at.iter().max() {
+            let wait = wait_until(last, RESEND_INTERVAL);
+            if !wait.is_zero() {
+                return Some(wait);
+            }
+        }
+
+        if sent_at.len() >= MAX_SENDS {
+            // The oldest send leaving the window frees a slot
+            return sent_at.iter().min().map(|oldest| wait_until(oldest, SEND_WINDOW));
+        }
+
+        None
+    }
+
+    /// Checks `code` against the pending code of the user, consuming it on success.
+    pub async fn check_code(discord_id: u64, code: &str) -> Result<CodeCheck, Error> {
+        let repository = &Repositories::get().verification_codes;
+
+        let Some(pending) = repository.get(discord_id).await? else {
+            return Ok(CodeCheck::NotRequested);
+        };
+
+        if pending.code_hash.is_empty() {
+            return Ok(CodeCheck::NotRequested);
+        }
+        if pending.is_expired() {
+            return Ok(CodeCheck::Expired);
+        }
+
+        // Every guess takes an attempt first, the right one included
+        let Some(mut pending) = repository.claim_attempt(discord_id, MAX_ATTEMPTS).await? else {
+            return Ok(CodeCheck::TooManyAttempts);
+        };
+        if pending.code_hash.is_empty() {
+            return Ok(CodeCheck::NotRequested);
+        }
+
+        if hash_code(discord_id, code.trim()) == pending.code_hash {
+            // Keep the send history for the rate limit, but the code can't be used again
+            pending.code_hash.clear();
+            repository.save(&pending).await?;
+            return Ok(CodeCheck::Verified {
+                email: pending.email,
+                guild_id: pending.guild_id,
+            });
+        }
+
+        Ok(match MAX_ATTEMPTS - pending.attempts {
+            0 => CodeCheck::TooManyAttempts,
+            attempts_left => CodeCheck::Wrong { attempts_left },
+        })
+    }
+}
+
+/// Codes only have a million values, so the hash is salted with the user it was sent to.
+fn hash_code(discord_id: u64, code: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}:{}", discord_id, code).as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::mail::FileMailTransport;
+    use crate::test_support::in_memory_repositories;
+
+    use super::*;
+
+    fn sink(name: &str) -> (FileMailTransport, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("deffy-mail-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        (FileMailTransport::new(&dir), dir)
+    }
+
+    /// The code in the only mail written to `dir`.
+    fn mailed_code(dir: &PathBuf) -> String {
+        let mut mails = std::fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path());
+        let mail = std::fs::read_to_string(mails.next().unwrap()).unwrap();
+        assert!(mails.next().is_none(), "expected a single mail");
+
+        let code = mail.split("code is ").nth(1).unwrap();
+        code[..6].to_string()
+    }
+
+    fn at(now: i64, seconds_ago: i64) -> BsonDateTime {
+        BsonDateTime::from_millis(now - seconds_ago * 1000)
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = BsonDateTime::now().timestamp_millis();
+
+        assert_eq!(EmailVerification::retry_after(&[], now), None);
+        assert_eq!(
+            EmailVerification::retry_after(&[at(now, 20)], now),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(EmailVerification::retry_after(&[at(now, 61)], now), None);
+
+        let window_full: Vec<_> = [3000, 2500, 2000, 1500, 1000].map(|ago| at(now, ago)).into();
+        assert_eq!(
+            EmailVerification::retry_after(&window_full, now),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(EmailVerification::retry_after(&window_full[1..], now), None);
+    }
+
+    #[tokio::test]
+    async fn a_mailed_code_verifies_once() {
+        in_memory_repositories();
+        let (mailer, dir) = sink("once");
+
+        let outcome = EmailVerification::send_code_with(&mailer, 2101, 7, "once@example.com").await.unwrap();
+        assert_eq!(outcome, SendCodeOutcome::Sent);
+        let code = mailed_code(&dir);
+
+        assert_eq!(
+            EmailVerification::check_code(2101, &format!(" {} ", code)).await.unwrap(),
+            CodeCheck::Verified { email: "once@example.com".to_string(), guild_id: 7 }
+        );
+        assert_eq!(EmailVerification::check_code(2101, &code).await.unwrap(), CodeCheck::NotRequested);
+        assert_eq!(EmailVerification::check_code(2102, &code).await.unwrap(), CodeCheck::NotRequested);
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_use_up_the_attempts() {
+        in_memory_repositories();
+        let (mailer, dir) = sink("attempts");
+
+        EmailVerification::send_code_with(&mailer, 2103, 7, "attempts@example.com").await.unwrap();
+        let code = mailed_code(&dir);
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+
+        for attempts_left in (1..MAX_ATTEMPTS).rev() {
+            assert_eq!(
+                EmailVerification::check_code(2103, wrong).await.unwrap(),
+                CodeCheck::Wrong { attempts_left }
+            );
+        }
+        assert_eq!(EmailVerification::check_code(2103, wrong).await.unwrap(), CodeCheck::TooManyAttempts);
+        assert_eq!(EmailVerification::check_code(2103, &code).await.unwrap(), CodeCheck::TooManyAttempts);
+    }
+
+    #[tokio::test]
+    async fn concurrent_guesses_stop_at_the_attempt_limit() {
+        let repositories = in_memory_repositories();
+        let (mailer, dir) = sink("concurrent");
+
+        EmailVerification::send_code_with(&mailer, 2108, 7, "concurrent@example.com").await.unwrap();
+        let wrong = if mailed_code(&dir) == "000000" { "000001" } else { "000000" };
+
+        let guesses = (0..MAX_ATTEMPTS * 2).map(|_| EmailVerification::check_code(2108, wrong));
+        let outcomes = serenity::futures::future::try_join_all(guesses).await.unwrap();
+
+        let wrong_outcomes = outcomes.iter().filter(|outcome| matches!(outcome, CodeCheck::Wrong { .. })).count();
+        assert_eq!(wrong_outcomes, MAX_ATTEMPTS as usize - 1);
+        let pending = repositories.verification_codes.get(2108).await.unwrap().unwrap();
+        assert_eq!(pending.attempts, MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn expired_codes_are_refused() {
+        let repositories = in_memory_repositories();
+
+        repositories
+            .verification_codes
+            .save(&VerificationCode {
+                discord_id: 2104,
+                guild_id: 7,
+                email: "expired@example.com".to_string(),
+                code_hash: hash_code(2104, "123456"),
+                attempts: 0,
+                expires_at: BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() - 1),
+                sent_at: Vec::new(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(EmailVerification::check_code(2104, "123456").await.unwrap(), CodeCheck::Expired);
+    }
+
+    #[tokio::test]
+    async fn sends_are_limited_per_user_and_per_address() {
+        in_memory_repositories();
+        let (mailer, _) = sink("limits");
+
+        let send = |discord_id, email| EmailVerification::send_code_with(&mailer, discord_id, 7, email);
+
+        assert_eq!(send(2105, "first@example.com").await.unwrap(), SendCodeOutcome::Sent);
+        assert!(matches!(
+            send(2105, "second@example.com").await.unwrap(),
+            SendCodeOutcome::RateLimited { .. }
+        ));
+        // Another Discord account asking for the same address, in any case
+        assert!(matches!(
+            send(2106, "First@Example.com").await.unwrap(),
+            SendCodeOutcome::RateLimited { .. }
+        ));
+        assert_eq!(send(2107, "third@example.com").await.unwrap(), SendCodeOutcome::Sent);
+    }
+}
@@ -1,13 +1,29 @@
 use std::{collections::HashMap, sync::Arc};
 
-use deffy_bot_patreon_services::{ApiDocument, MemberAttributes};
+use deffy_bot_patreon_services::{ApiDocument, Event, MemberAttributes, PledgeAttributes};
 use once_cell::sync::Lazy;
 use serenity::async_trait;
 use tokio::sync::Mutex;
 
+use crate::sync::MemberChange;
+
 #[derive(Clone)]
 pub enum EventTypeData {
-    PatreonMemberData(ApiDocument<MemberAttributes>)
+    PatreonMemberData(ApiDocument<MemberAttributes>),
+    PatreonPledgeData(ApiDocument<PledgeAttributes>),
+    PatreonMemberChange(MemberChange),
+}
+
+impl EventTypeData {
+    /// Campaign the event belongs to, when the payload says so.
+    pub fn campaign_id(&self) -> Option<String> {
+        let id = match self {
+            EventTypeData::PatreonMemberData(member) => member.related_ids("campaign").first().copied(),
+            EventTypeData::PatreonPledgeData(pledge) => pledge.related_ids("campaign").first().copied(),
+            EventTypeData::PatreonMemberChange(change) => change.campaign_id.as_deref(),
+        };
+        id.map(str::to_string)
+    }
 }
 
 inventory::collect!(&'static dyn EventInfo);
@@ -19,6 +35,35 @@ pub enum EventType {
     PatreonWebhookUserCreated,
     PatreonWebhookUserUpdated,
     PatreonWebhookUserDeleted,
+    PatreonWebhookPledgeCreated,
+    PatreonWebhookPledgeUpdated,
+    PatreonWebhookPledgeDeleted,
+    PatreonWebhookMemberPledgeCreated,
+    PatreonWebhookMemberPledgeUpdated,
+    PatreonWebhookMemberPledgeDeleted,
+    PatreonSyncMemberJoined,
+    PatreonSyncMemberLeft,
+    PatreonSyncMemberStatusChanged,
+    PatreonSyncMemberTierChanged,
+    PatreonSyncChargeDeclined,
+}
+
+impl EventType {
+    /// The event type and payload a Patreon webhook delivery is emitted as.
+    pub fn from_webhook(event: Event) -> (EventType, EventTypeData) {
+        use EventTypeData::{PatreonMemberData, PatreonPledgeData};
+        match event {
+            Event::CreateMember(member) => (EventType::PatreonWebhookUserCreated, PatreonMemberData(member)),
+            Event::UpdateMember(member) => (EventType::PatreonWebhookUserUpdated, PatreonMemberData(member)),
+            Event::DeleteMember(member) => (EventType::PatreonWebhookUserDeleted, PatreonMemberData(member)),
+            Event::CreatePledge(pledge) => (EventType::PatreonWebhookPledgeCreated, PatreonPledgeData(pledge)),
+            Event::UpdatePledge(pledge) => (EventType::PatreonWebhookPledgeUpdated, PatreonPledgeData(pledge)),
+            Event::DeletePledge(pledge) => (EventType::PatreonWebhookPledgeDeleted, PatreonPledgeData(pledge)),
+            Event::CreateMemberPledge(member) => (EventType::PatreonWebhookMemberPledgeCreated, PatreonMemberData(member)),
+            Event::UpdateMemberPledge(member) => (EventType::PatreonWebhookMemberPledgeUpdated, PatreonMemberData(member)),
+            Event::DeleteMemberPledge(member) => (EventType::PatreonWebhookMemberPledgeDeleted, PatreonMemberData(member)),
+        }
+    }
 }
 
 pub struct EventManager {
@@ -28,6 +73,11 @@ pub struct EventManager {
 #[async_trait]
 pub trait UtilsEventHandler: Send + Sync + 'static {
     async fn handle(&self,data: EventTypeData) -> Result<(), anyhow::Error>;
+
+    /// Identifies the handler across restarts, see `EventManager::dispatch`.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
 pub trait EventInfo: Send + Sync + 'static {
@@ -54,6 +104,54 @@ impl EventManager {
         handlers.entry(event).or_default().push(handler);
     }
 
+    pub async fn handlers(&self, event_type: &EventType) -> Vec<Arc<dyn UtilsEventHandler>> {
+        let map = self.event_handlers.lock().await;
+        map.get(event_type).cloned().unwrap_or_default()
+    }
+
+    /// Like `emit`, but waits for every handler and fails if any of them did.
+    ///
+    /// Handlers named in `completed` are skipped and the ones that succeed are added to
+    /// it, so retrying a failed dispatch only runs the handlers that failed.
+    ///
+    /// Takes the handler list up front so the `EVENT_MANAGER` lock isn't held while
+    /// the handlers run.
+    pub async fn dispatch(
+        event_type: EventType,
+        data: EventTypeData,
+        completed: &mut Vec<String>,
+    ) -> Result<(), anyhow::Error> {
+        let handlers = EVENT_MANAGER.lock().await.handlers(&event_type).await;
+        if handlers.is_empty() {
+            tracing::warn!("No handlers registered for event: {:?}", event_type);
+            return Ok(());
+        }
+
+        let tasks: Vec<_> = handlers
+            .into_iter()
+            .filter(|handler| !completed.iter().any(|name| name == handler.name()))
+            .map(|handler| {
+                let data = data.clone();
+                let name = handler.name();
+                (name, tokio::spawn(async move { handler.handle(data).await }))
+            })
+            .collect();
+
+        let mut errors = Vec::new();
+        for (name, task) in tasks {
+            match task.await {
+                Ok(Ok(())) => completed.push(name.to_string()),
+                Ok(Err(err)) => errors.push(format!("{name}: {err}")),
+                Err(err) => errors.push(format!("{name} panicked: {err}")),
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("{:?} handler errors: {}", event_type, errors.join("; "))),
+        }
+    }
+
     pub async fn emit(&self, event_type: EventType,data: EventTypeData) {
         let handler_list = {
             let map: tokio::sync::MutexGuard<
@@ -78,3 +176,74 @@ impl EventManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use deffy_bot_patreon_services::Member;
+
+    use super::*;
+
+    struct CountingHandler {
+        name: &'static str,
+        calls: AtomicU32,
+        fail_first: bool,
+    }
+
+    #[async_trait]
+    impl UtilsEventHandler for CountingHandler {
+        async fn handle(&self, _data: EventTypeData) -> Result<(), anyhow::Error> {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.fail_first && calls == 0 {
+                return Err(anyhow::anyhow!("first call fails"));
+            }
+            Ok(())
+        }
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+    }
+
+    #[tokio::test]
+    async fn retried_dispatch_skips_completed_handlers() {
+        let ok = Arc::new(CountingHandler {
+            name: "ok",
+            calls: AtomicU32::new(0),
+            fail_first: false,
+        });
+        let flaky = Arc::new(CountingHandler {
+            name: "flaky",
+            calls: AtomicU32::new(0),
+            fail_first: true,
+        });
+        {
+            let mut manager = EVENT_MANAGER.lock().await;
+            manager
+                .register_event(EventType::PatreonWebhookMemberPledgeDeleted, ok.clone())
+                .await;
+            manager
+                .register_event(EventType::PatreonWebhookMemberPledgeDeleted, flaky.clone())
+                .await;
+        }
+        let data = EventTypeData::PatreonMemberData(Member::default());
+
+        let mut completed = Vec::new();
+        let first = EventManager::dispatch(
+            EventType::PatreonWebhookMemberPledgeDeleted,
+            data.clone(),
+            &mut completed,
+        )
+        .await;
+        assert!(first.is_err());
+        assert_eq!(completed, ["ok"]);
+
+        EventManager::dispatch(EventType::PatreonWebhookMemberPledgeDeleted, data, &mut completed)
+            .await
+            .unwrap();
+        assert_eq!(ok.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(completed, ["ok", "flaky"]);
+    }
+}
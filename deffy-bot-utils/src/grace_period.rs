@@ -0,0 +1,365 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use deffy_bot_patreon_services::{LastChrgeStatus, PatronStatus};
+use mongodb::bson::DateTime as BsonDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::database::PatreonUserData;
+use crate::guild_settings::GuildSettings;
+use crate::member_binding::MemberBindingDatabase;
+use crate::repository::Repositories;
+
+/// Used by servers that never set `/setup grace_period`.
+pub const DEFAULT_GRACE_PERIOD_HOURS: u32 = 72;
+
+/// How often the pending revocations are checked.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevocationReason {
+    MemberDeleted,
+    ChargeDeclined,
+    /// The member canceled their pledge but is still in the campaign.
+    Canceled,
+}
+
+impl RevocationReason {
+    /// Localization key of the DM sent when the roles are revoked.
+    pub fn message_key(&self) -> &'static str {
+        match self {
+            RevocationReason::MemberDeleted => "grace_revoked_member_deleted",
+            RevocationReason::ChargeDeclined => "grace_revoked_charge_declined",
+            RevocationReason::Canceled => "grace_revoked_canceled",
+        }
+    }
+
+    /// Why `member` lapsed, `None` when they are in good standing. `member` is `None`
+    /// once they left the campaign.
+    pub fn of(member: Option<&PatreonUserData>) -> Option<RevocationReason> {
+        match member {
+            None => Some(RevocationReason::MemberDeleted),
+            Some(member) if member.patreon_status == Some(PatronStatus::FormerPatron) => {
+                Some(RevocationReason::Canceled)
+            }
+            Some(member) if member.last_charge_status == Some(LastChrgeStatus::Declined) => {
+                Some(RevocationReason::ChargeDeclined)
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// Whether the member is back since `previous` was stored: a canceled pledge was renewed,
+/// the last charge turned paid or a newer charge went through. Only then is a running grace
+/// period canceled, a member merely still showing an old paid charge isn't back.
+pub fn recovered(previous: Option<&PatreonUserData>, current: &PatreonUserData) -> bool {
+    let renewed = current.patreon_status == Some(PatronStatus::ActivePatron)
+        && previous.is_some_and(|previous| previous.patreon_status == Some(PatronStatus::FormerPatron));
+    if renewed {
+        return true;
+    }
+
+    if current.last_charge_status != Some(LastChrgeStatus::Paid) {
+        return false;
+    }
+    match previous {
+        None => true,
+        Some(previous) => {
+            previous.last_charge_status != Some(LastChrgeStatus::Paid)
+                || current.last_charge_date > previous.last_charge_date
+        }
+    }
+}
+
+/// A linked member who lapsed and loses their patron roles once the grace period of
+/// each server ends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingRevocation {
+    pub patreon_member_id: String,
+    pub discord_id: u64,
+    pub reason: RevocationReason,
+    /// Campaign the member lapsed in, only the servers following it revoke the roles.
+    /// `None` for revocations stored before it was recorded, which go to every server.
+    #[serde(default)]
+    pub campaign_id: Option<String>,
+    pub lapsed_at: BsonDateTime,
+    /// Servers the roles were already revoked in.
+    #[serde(default)]
+    pub revoked_in: Vec<u64>,
+    /// Servers whose verify role was among the revoked roles, it is given back there if
+    /// the member returns.
+    #[serde(default)]
+    pub verify_role_revoked_in: Vec<u64>,
+    /// Every server handled it. Kept only while a verify role waits to be given back.
+    #[serde(default)]
+    pub finished: bool,
+    /// Locale of the DM, see `MemberBinding::locale`.
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+
+impl PendingRevocation {
+    /// Whether the grace period of the server ended and it wasn't revoked there yet.
+    pub fn is_due(&self, settings: &GuildSettings) -> bool {
+        let grace = Duration::from_secs(settings.grace_period_hours as u64 * 60 * 60);
+        let due_at = self.lapsed_at.timestamp_millis() + grace.as_millis() as i64;
+
+        !self.revoked_in.contains(&settings.server_id) && BsonDateTime::now().timestamp_millis() >= due_at
+    }
+}
+
+/// Goes through the installed `RevocationRepository`.
+pub struct GracePeriod {}
+
+impl GracePeriod {
+    /// Starts the grace period of a lapsed member of `campaign_id`. Members nobody verified
+    /// as are ignored, and a grace period already running keeps its start.
+    pub async fn start(
+        member_id: &str,
+        campaign_id: Option<&str>,
+        reason: RevocationReason,
+    ) -> Result<bool, Error> {
+        let repository = &Repositories::get().revocations;
+
+        let verify_role_revoked_in = match repository.get(member_id).await? {
+            Some(pending) if !pending.finished => return Ok(false),
+            Some(finished) => finished.verify_role_revoked_in,
+            None => Vec::new(),
+        };
+
+        let Some(binding) = MemberBindingDatabase::get_by_member_id(member_id).await? else {
+            return Ok(false);
+        };
+
+        repository
+            .save(&PendingRevocation {
+                patreon_member_id: member_id.to_string(),
+                discord_id: binding.discord_id,
+                reason,
+                campaign_id: campaign_id.map(str::to_string),
+                lapsed_at: BsonDateTime::now(),
+                revoked_in: Vec::new(),
+                verify_role_revoked_in,
+                finished: false,
+                locale: binding.locale,
+            })
+            .await?;
+
+        tracing::info!("Grace period started for member {} ({:?})", member_id, reason);
+        Ok(true)
+    }
+
+    /// Drops the revocation of the member, running or finished, and returns it so the
+    /// caller can give back the verify roles it took.
+    pub async fn cancel(member_id: &str) -> Result<Option<PendingRevocation>, Error> {
+        let repository = &Repositories::get().revocations;
+
+        let Some(revocation) = repository.get(member_id).await? else {
+            return Ok(None);
+        };
+
+        repository.remove(member_id).await?;
+        tracing::info!("Grace period of member {} canceled", member_id);
+        Ok(Some(revocation))
+    }
+
+    /// Revocations still waiting for a server.
+    pub async fn pending() -> Result<Vec<PendingRevocation>, Error> {
+        let revocations = Repositories::get().revocations.all().await?;
+        Ok(revocations.into_iter().filter(|revocation| !revocation.finished).collect())
+    }
+
+    pub async fn mark_revoked(member_id: &str, guild_id: u64, verify_role_revoked: bool) -> Result<(), Error> {
+        let repository = &Repositories::get().revocations;
+
+        let Some(mut pending) = repository.get(member_id).await? else {
+            return Ok(());
+        };
+
+        if !pending.revoked_in.contains(&guild_id) {
+            pending.revoked_in.push(guild_id);
+            if verify_role_revoked {
+                pending.verify_role_revoked_in.push(guild_id);
+            }
+            repository.save(&pending).await?;
+        }
+        Ok(())
+    }
+
+    /// Called once every server handled the revocation. It is forgotten, unless it took a
+    /// verify role to give back.
+    pub async fn finish(member_id: &str) -> Result<(), Error> {
+        let repository = &Repositories::get().revocations;
+
+        match repository.get(member_id).await? {
+            Some(mut revocation) if !revocation.verify_role_revoked_in.is_empty() => {
+                revocation.finished = true;
+                repository.save(&revocation).await
+            }
+            Some(_) => repository.remove(member_id).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::member_binding::MemberBindingDatabase;
+    use crate::test_support::{in_memory_repositories, patron};
+
+    use super::*;
+
+    const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+    fn revocation(lapsed_hours_ago: i64, revoked_in: Vec<u64>) -> PendingRevocation {
+        PendingRevocation {
+            patreon_member_id: "m1".to_string(),
+            discord_id: 1,
+            reason: RevocationReason::ChargeDeclined,
+            campaign_id: None,
+            lapsed_at: BsonDateTime::from_millis(
+                BsonDateTime::now().timestamp_millis() - lapsed_hours_ago * HOUR_MILLIS,
+            ),
+            revoked_in,
+            verify_role_revoked_in: Vec::new(),
+            finished: false,
+            locale: None,
+        }
+    }
+
+    fn server(server_id: u64, grace_period_hours: u32) -> GuildSettings {
+        GuildSettings {
+            grace_period_hours,
+            ..GuildSettings::new(server_id)
+        }
+    }
+
+    #[test]
+    fn reason_of() {
+        let paid = PatreonUserData {
+            last_charge_status: Some(LastChrgeStatus::Paid),
+            ..patron("m1", "m1@example.com")
+        };
+        let declined = PatreonUserData {
+            last_charge_status: Some(LastChrgeStatus::Declined),
+            ..patron("m1", "m1@example.com")
+        };
+
+        assert_eq!(RevocationReason::of(None), Some(RevocationReason::MemberDeleted));
+        assert_eq!(RevocationReason::of(Some(&declined)), Some(RevocationReason::ChargeDeclined));
+        assert_eq!(RevocationReason::of(Some(&paid)), None);
+        // Canceled, even with the last charge paid
+        let canceled = PatreonUserData {
+            patreon_status: Some(PatronStatus::FormerPatron),
+            ..paid.clone()
+        };
+        assert_eq!(RevocationReason::of(Some(&canceled)), Some(RevocationReason::Canceled));
+        // Free members never paid, nothing to lapse
+        assert_eq!(RevocationReason::of(Some(&patron("m1", "m1@example.com"))), None);
+    }
+
+    #[test]
+    fn recovered_on_a_new_payment_or_a_renewed_pledge() {
+        let charged = |status, days_ago: i64| PatreonUserData {
+            last_charge_status: Some(status),
+            last_charge_date: Some(BsonDateTime::from_millis(
+                BsonDateTime::now().timestamp_millis() - days_ago * 24 * HOUR_MILLIS,
+            )),
+            ..patron("m1", "m1@example.com")
+        };
+        let paid = charged(LastChrgeStatus::Paid, 1);
+        let declined = charged(LastChrgeStatus::Declined, 1);
+
+        assert!(recovered(None, &paid));
+        assert!(recovered(Some(&declined), &paid));
+        assert!(recovered(Some(&charged(LastChrgeStatus::Paid, 30)), &paid));
+        // The same paid charge seen again
+        assert!(!recovered(Some(&paid), &paid));
+        assert!(!recovered(Some(&paid), &declined));
+        assert!(!recovered(None, &patron("m1", "m1@example.com")));
+
+        let with_status = |status| PatreonUserData {
+            patreon_status: Some(status),
+            ..paid.clone()
+        };
+        assert!(recovered(
+            Some(&with_status(PatronStatus::FormerPatron)),
+            &with_status(PatronStatus::ActivePatron)
+        ));
+        assert!(!recovered(
+            Some(&with_status(PatronStatus::ActivePatron)),
+            &with_status(PatronStatus::FormerPatron)
+        ));
+    }
+
+    #[test]
+    fn is_due_once_the_grace_period_of_the_server_ended() {
+        let revocation = revocation(10, vec![3]);
+
+        assert!(revocation.is_due(&server(1, 0)));
+        assert!(revocation.is_due(&server(1, 9)));
+        assert!(!revocation.is_due(&server(1, 11)));
+        // Already revoked there
+        assert!(!revocation.is_due(&server(3, 0)));
+    }
+
+    #[tokio::test]
+    async fn start_records_the_campaign_once() {
+        in_memory_repositories();
+
+        assert!(!GracePeriod::start("grace-1", Some("c1"), RevocationReason::MemberDeleted).await.unwrap());
+
+        MemberBindingDatabase::bind("grace-1", 2401, 7, "grace@example.com", Some("th".to_string()))
+            .await
+            .unwrap();
+        assert!(GracePeriod::start("grace-1", Some("c1"), RevocationReason::MemberDeleted).await.unwrap());
+        assert!(!GracePeriod::start("grace-1", Some("c2"), RevocationReason::ChargeDeclined).await.unwrap());
+
+        let pending = Repositories::get().revocations.get("grace-1").await.unwrap().unwrap();
+        assert_eq!(pending.discord_id, 2401);
+        assert_eq!(pending.campaign_id.as_deref(), Some("c1"));
+        assert_eq!(pending.reason, RevocationReason::MemberDeleted);
+        assert_eq!(pending.locale.as_deref(), Some("th"));
+
+        GracePeriod::mark_revoked("grace-1", 5, false).await.unwrap();
+        GracePeriod::mark_revoked("grace-1", 5, false).await.unwrap();
+        let pending = Repositories::get().revocations.get("grace-1").await.unwrap().unwrap();
+        assert_eq!(pending.revoked_in, vec![5]);
+
+        assert!(GracePeriod::cancel("grace-1").await.unwrap().is_some());
+        assert!(GracePeriod::cancel("grace-1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn finished_revocations_remember_the_verify_roles_they_took() {
+        in_memory_repositories();
+        let is_pending = |member_id: &'static str| async move {
+            GracePeriod::pending().await.unwrap().iter().any(|r| r.patreon_member_id == member_id)
+        };
+
+        MemberBindingDatabase::bind("grace-2", 2402, 7, "grace2@example.com", None).await.unwrap();
+        MemberBindingDatabase::bind("grace-3", 2403, 7, "grace3@example.com", None).await.unwrap();
+        GracePeriod::start("grace-2", None, RevocationReason::ChargeDeclined).await.unwrap();
+        GracePeriod::start("grace-3", None, RevocationReason::ChargeDeclined).await.unwrap();
+
+        // Nothing to give back, forgotten
+        GracePeriod::mark_revoked("grace-3", 7, false).await.unwrap();
+        GracePeriod::finish("grace-3").await.unwrap();
+        assert!(Repositories::get().revocations.get("grace-3").await.unwrap().is_none());
+
+        GracePeriod::mark_revoked("grace-2", 7, true).await.unwrap();
+        GracePeriod::mark_revoked("grace-2", 8, false).await.unwrap();
+        GracePeriod::finish("grace-2").await.unwrap();
+        assert!(!is_pending("grace-2").await);
+
+        // Lapsing again starts over, still knowing where the verify role went
+        assert!(GracePeriod::start("grace-2", None, RevocationReason::MemberDeleted).await.unwrap());
+        assert!(is_pending("grace-2").await);
+        GracePeriod::finish("grace-2").await.unwrap();
+
+        let canceled = GracePeriod::cancel("grace-2").await.unwrap().unwrap();
+        assert_eq!(canceled.verify_role_revoked_in, vec![7]);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::Error;
+use mongodb::bson::{self, Bson, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::database::DatabaseWebHookEvent;
+use crate::grace_period::DEFAULT_GRACE_PERIOD_HOURS;
+use crate::repository::Repositories;
+
+/// Version written by this build. Version 1 is the flat `DiscordServerData` layout,
+/// stored without a `version` field.
+pub const GUILD_SETTINGS_VERSION: u32 = 2;
+
+/// Channels the Patreon webhook events are posted to, one per event.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WebhookChannels {
+    pub member_created: Option<u64>,
+    pub member_updated: Option<u64>,
+    pub member_deleted: Option<u64>,
+    pub pledge_created: Option<u64>,
+    pub pledge_upgraded: Option<u64>,
+    pub pledge_downgraded: Option<u64>,
+    pub pledge_canceled: Option<u64>,
+}
+
+impl WebhookChannels {
+    pub fn get(&self, event: DatabaseWebHookEvent) -> Option<u64> {
+        *self.slot(event)
+    }
+
+    pub fn set(&mut self, event: DatabaseWebHookEvent, channel_id: Option<u64>) {
+        *self.slot_mut(event) = channel_id;
+    }
+
+    fn slot(&self, event: DatabaseWebHookEvent) -> &Option<u64> {
+        match event {
+            DatabaseWebHookEvent::CreateMember => &self.member_created,
+            DatabaseWebHookEvent::UpdateMember => &self.member_updated,
+            DatabaseWebHookEvent::DeleteMember => &self.member_deleted,
+            DatabaseWebHookEvent::CreatePledge => &self.pledge_created,
+            DatabaseWebHookEvent::UpgradePledge => &self.pledge_upgraded,
+            DatabaseWebHookEvent::DowngradePledge => &self.pledge_downgraded,
+            DatabaseWebHookEvent::DeletePledge => &self.pledge_canceled,
+        }
+    }
+
+    fn slot_mut(&mut self, event: DatabaseWebHookEvent) -> &mut Option<u64> {
+        match event {
+            DatabaseWebHookEvent::CreateMember => &mut self.member_created,
+            DatabaseWebHookEvent::UpdateMember => &mut self.member_updated,
+            DatabaseWebHookEvent::DeleteMember => &mut self.member_deleted,
+            DatabaseWebHookEvent::CreatePledge => &mut self.pledge_created,
+            DatabaseWebHookEvent::UpgradePledge => &mut self.pledge_upgraded,
+            DatabaseWebHookEvent::DowngradePledge => &mut self.pledge_downgraded,
+            DatabaseWebHookEvent::DeletePledge => &mut self.pledge_canceled,
+        }
+    }
+}
+
+/// Configuration of one Discord server, as kept in `server_data`.
+/// Every setting is optional; a server that never ran `/setup` gets the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GuildSettings {
+    pub server_id: u64,
+    pub version: u32,
+    /// Patreon campaign this server follows. `None` follows every campaign.
+    #[serde(default)]
+    pub campaign_id: Option<String>,
+    #[serde(default)]
+    pub verify_role_id: Option<u64>,
+    #[serde(default)]
+    pub log_channel_id: Option<u64>,
+    #[serde(default)]
+    pub webhook_channels: WebhookChannels,
+    /// Role granted per Patreon tier id, or per `FREE_TIER` for free members.
+    #[serde(default)]
+    pub tier_roles: BTreeMap<String, u64>,
+    /// How long lapsed patrons keep their roles.
+    #[serde(default = "default_grace_period_hours")]
+    pub grace_period_hours: u32,
+}
+
+fn default_grace_period_hours() -> u32 {
+    DEFAULT_GRACE_PERIOD_HOURS
+}
+
+/// A setting pointing at a channel or role the server doesn't have anymore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingIssue {
+    pub setting: &'static str,
+    pub id: u64,
+    pub kind: SettingIssueKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingIssueKind {
+    MissingChannel,
+    MissingRole,
+}
+
+impl std::fmt::Display for SettingIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            SettingIssueKind::MissingChannel => write!(f, "{}: channel {} no longer exists", self.setting, self.id),
+            SettingIssueKind::MissingRole => write!(f, "{}: role {} no longer exists", self.setting, self.id),
+        }
+    }
+}
+
+impl GuildSettings {
+    pub fn new(server_id: u64) -> Self {
+        Self {
+            server_id,
+            version: GUILD_SETTINGS_VERSION,
+            campaign_id: None,
+            verify_role_id: None,
+            log_channel_id: None,
+            webhook_channels: WebhookChannels::default(),
+            tier_roles: BTreeMap::new(),
+            grace_period_hours: DEFAULT_GRACE_PERIOD_HOURS,
+        }
+    }
+
+    /// Reads a stored document of any version.
+    pub fn from_document(document: Document) -> Result<Self, Error> {
+        let version = match document.get("version") {
+            Some(Bson::Int32(version)) => *version as u32,
+            Some(Bson::Int64(version)) => *version as u32,
+            _ => 1,
+        };
+
+        match version {
+            1 => Self::from_v1(&document),
+            GUILD_SETTINGS_VERSION => Ok(bson::from_document(document)?),
+            other => Err(anyhow::anyhow!("Unknown guild settings version {}", other)),
+        }
+    }
+
+    /// The flat layout, where unset channels were missing or `0`.
+    /// A document without a server id can't belong to any server and is refused.
+    fn from_v1(document: &Document) -> Result<Self, Error> {
+        let id = |key: &str| match document.get(key) {
+            Some(Bson::Int64(id)) => Some(*id as u64),
+            Some(Bson::Int32(id)) => Some(*id as u64),
+            _ => None,
+        }
+        .filter(|id| *id != 0);
+
+        let server_id = id("server_id")
+            .ok_or_else(|| anyhow::anyhow!("Server settings {:?} have no server id", document.get("_id")))?;
+
+        Ok(Self {
+            campaign_id: document.get_str("campaign_id").ok().map(str::to_string),
+            verify_role_id: id("verify_role_id"),
+            log_channel_id: id("log_channel_id"),
+            webhook_channels: WebhookChannels {
+                member_created: id("webhook_create_member_channel_id"),
+                member_updated: id("webhook_update_member_channel_id"),
+                member_deleted: id("webhook_delete_member_channel_id"),
+                pledge_created: id("webhook_create_pledge_channel_id"),
+                pledge_upgraded: id("webhook_upgrade_pledge_channel_id"),
+                pledge_downgraded: id("webhook_downgrade_pledge_channel_id"),
+                pledge_canceled: id("webhook_delete_pledge_channel_id"),
+            },
+            ..Self::new(server_id)
+        })
+    }
+
+    /// Every channel setting with its name, set or not.
+    pub fn channels(&self) -> Vec<(&'static str, Option<u64>)> {
+        let webhook = &self.webhook_channels;
+        vec![
+            ("log channel", self.log_channel_id),
+            ("member created", webhook.member_created),
+            ("member updated", webhook.member_updated),
+            ("member deleted", webhook.member_deleted),
+            ("pledge created", webhook.pledge_created),
+            ("pledge upgraded", webhook.pledge_upgraded),
+            ("pledge downgraded", webhook.pledge_downgraded),
+            ("pledge canceled", webhook.pledge_canceled),
+        ]
+    }
+
+    /// Every role setting with its name, set or not.
+    pub fn roles(&self) -> Vec<(&'static str, Option<u64>)> {
+        vec![("verify role", self.verify_role_id)]
+    }
+
+    /// Roles the bot grants to verified patrons, the verify role and every tier role.
+    pub fn patron_roles(&self) -> HashSet<u64> {
+        self.tier_roles.values().copied().chain(self.verify_role_id).collect()
+    }
+
+    /// Settings referring to a channel or role missing from the given ids of the server.
+    pub fn validate(&self, channels: &HashSet<u64>, roles: &HashSet<u64>) -> Vec<SettingIssue> {
+        let channel_issues = self
+            .channels()
+            .into_iter()
+            .filter_map(|(setting, id)| id.map(|id| (setting, id)))
+            .filter(|(_, id)| !channels.contains(id))
+            .map(|(setting, id)| SettingIssue {
+                setting,
+                id,
+                kind: SettingIssueKind::MissingChannel,
+            });
+
+        let tier_roles = self.tier_roles.values().map(|id| ("tier role", Some(*id)));
+
+        let role_issues = self
+            .roles()
+            .into_iter()
+            .chain(tier_roles)
+            .filter_map(|(setting, id)| id.map(|id| (setting, id)))
+            .filter(|(_, id)| !roles.contains(id))
+            .map(|(setting, id)| SettingIssue {
+                setting,
+                id,
+                kind: SettingIssueKind::MissingRole,
+            });
+
+        channel_issues.chain(role_issues).collect()
+    }
+}
+
+/// Goes through the installed `GuildSettingsRepository`.
+pub struct GuildSettingsDatabase {}
+
+impl GuildSettingsDatabase {
+    /// The settings of the server, the defaults if it has none stored.
+    pub async fn get(server_id: u64) -> Result<GuildSettings, Error> {
+        let settings = Repositories::get().guild_settings.get(server_id).await?;
+        Ok(settings.unwrap_or_else(|| GuildSettings::new(server_id)))
+    }
+
+    /// Writes the settings in the current version.
+    pub async fn save(settings: &GuildSettings) -> Result<(), Error> {
+        Repositories::get().guild_settings.save(settings).await
+    }
+
+    /// Applies `change` to the stored settings and saves them.
+    pub async fn update(
+        server_id: u64,
+        change: impl FnOnce(&mut GuildSettings),
+    ) -> Result<GuildSettings, Error> {
+        let mut settings = Self::get(server_id).await?;
+        change(&mut settings);
+        Self::save(&settings).await?;
+        Ok(settings)
+    }
+
+    /// Every server that should receive events of `campaign_id`, including the ones
+    /// not bound to a campaign. With no campaign, every configured server.
+    pub async fn get_campaign_servers(campaign_id: Option<&str>) -> Result<Vec<GuildSettings>, Error> {
+        Repositories::get().guild_settings.campaign_servers(campaign_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+
+    #[test]
+    fn v1_documents_are_upgraded() {
+        let document = doc! {
+            "server_id": 42_i64,
+            "campaign_id": "123",
+            "verify_role_id": 7_i64,
+            "log_channel_id": 0_i64,
+            "webhook_create_member_channel_id": 8_i32,
+            "webhook_delete_pledge_channel_id": 9_i64,
+        };
+
+        let settings = GuildSettings::from_document(document).unwrap();
+
+        assert_eq!(settings.server_id, 42);
+        assert_eq!(settings.version, GUILD_SETTINGS_VERSION);
+        assert_eq!(settings.campaign_id.as_deref(), Some("123"));
+        assert_eq!(settings.verify_role_id, Some(7));
+        assert_eq!(settings.log_channel_id, None);
+        assert_eq!(settings.webhook_channels.member_created, Some(8));
+        assert_eq!(settings.webhook_channels.pledge_canceled, Some(9));
+        assert_eq!(settings.webhook_channels.member_updated, None);
+        assert_eq!(settings.grace_period_hours, DEFAULT_GRACE_PERIOD_HOURS);
+    }
+
+    #[test]
+    fn v1_documents_without_a_server_are_refused() {
+        assert!(GuildSettings::from_document(doc! { "verify_role_id": 7_i64 }).is_err());
+        assert!(GuildSettings::from_document(doc! { "server_id": 0_i64 }).is_err());
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let mut settings = GuildSettings::new(42);
+        settings.tier_roles.insert("free".to_string(), 5);
+        settings.webhook_channels.set(DatabaseWebHookEvent::UpgradePledge, Some(6));
+
+        let document = bson::to_document(&settings).unwrap();
+        assert_eq!(GuildSettings::from_document(document).unwrap(), settings);
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        assert!(GuildSettings::from_document(doc! { "server_id": 42_i64, "version": 99 }).is_err());
+    }
+
+    #[tokio::test]
+    async fn update_goes_through_the_installed_repository() {
+        crate::test_support::in_memory_repositories();
+
+        assert_eq!(GuildSettingsDatabase::get(1801).await.unwrap(), GuildSettings::new(1801));
+
+        GuildSettingsDatabase::update(1801, |settings| settings.verify_role_id = Some(7))
+            .await
+            .unwrap();
+        GuildSettingsDatabase::update(1801, |settings| settings.grace_period_hours = 0)
+            .await
+            .unwrap();
+
+        let settings = GuildSettingsDatabase::get(1801).await.unwrap();
+        assert_eq!(settings.verify_role_id, Some(7));
+        assert_eq!(settings.grace_period_hours, 0);
+    }
+
+    #[test]
+    fn validate_reports_missing_channels_and_roles() {
+        let mut settings = GuildSettings::new(42);
+        settings.log_channel_id = Some(1);
+        settings.webhook_channels.member_created = Some(2);
+        settings.verify_role_id = Some(3);
+        settings.tier_roles.insert("free".to_string(), 4);
+
+        let issues = settings.validate(&HashSet::from([1]), &HashSet::from([3]));
+
+        assert_eq!(
+            issues,
+            vec![
+                SettingIssue { setting: "member created", id: 2, kind: SettingIssueKind::MissingChannel },
+                SettingIssue { setting: "tier role", id: 4, kind: SettingIssueKind::MissingRole },
+            ]
+        );
+    }
+}
@@ -1,4 +1,20 @@
 pub mod database;
+pub mod guild_settings;
 pub mod builder_utils;
 pub mod wip_database;
-pub mod event;
\ No newline at end of file
+pub mod webhook_database;
+pub mod link_database;
+pub mod token_database;
+pub mod event;
+pub mod sync;
+pub mod repository;
+pub mod migration;
+pub mod mail;
+pub mod email_verification;
+pub mod member_binding;
+pub mod tier_roles;
+pub mod grace_period;
+pub mod scheduler;
+
+#[cfg(test)]
+mod test_support;
@@ -0,0 +1,88 @@
+use anyhow::Error;
+use deffy_bot_patreon_services::{Member, PatronStatus, User};
+use mongodb::{
+    Collection,
+    bson::{DateTime as BsonDateTime, doc},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::database::DatabaseManager;
+
+/// A Discord account linked to a Patreon account through OAuth.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PatreonLink {
+    pub discord_id: u64,
+    pub patreon_user_id: String,
+    pub patreon_email: Option<String>,
+    pub patreon_full_name: String,
+    pub memberships: Vec<LinkedMembership>,
+    pub linked_at: BsonDateTime,
+    /// Set when the stored tokens stopped refreshing; the user has to link again.
+    #[serde(default)]
+    pub broken_at: Option<BsonDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LinkedMembership {
+    pub member_id: String,
+    pub campaign_id: Option<String>,
+    pub patron_status: Option<PatronStatus>,
+}
+
+impl PatreonLink {
+    pub fn new(discord_id: u64, user: &User, members: &[Member]) -> Self {
+        Self {
+            discord_id,
+            patreon_user_id: user.id.clone(),
+            patreon_email: Some(user.attributes.email.clone()).filter(|email| !email.is_empty()),
+            patreon_full_name: user.attributes.full_name.clone(),
+            memberships: members
+                .iter()
+                .map(|member| LinkedMembership {
+                    member_id: member.id.clone(),
+                    campaign_id: member.campaign_id().map(str::to_string),
+                    patron_status: member.attributes.patron_status,
+                })
+                .collect(),
+            linked_at: BsonDateTime::now(),
+            broken_at: None,
+        }
+    }
+}
+
+pub struct PatreonLinkDatabase {}
+
+impl PatreonLinkDatabase {
+    fn collection() -> Result<Collection<PatreonLink>, Error> {
+        Ok(DatabaseManager::require_db()?.collection("patreon_links"))
+    }
+
+    /// Stores the link, replacing any earlier link of the same Discord account.
+    pub async fn upsert_link(link: PatreonLink) -> Result<(), Error> {
+        let filter = doc! { "discord_id": link.discord_id as i64 };
+        Self::collection()?
+            .replace_one(filter, link)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn mark_broken(discord_id: u64) -> Result<(), Error> {
+        let filter = doc! { "discord_id": discord_id as i64 };
+        let update = doc! { "$set": { "broken_at": BsonDateTime::now() } };
+        Self::collection()?.update_one(filter, update).await?;
+        Ok(())
+    }
+
+    pub async fn get_link(discord_id: u64) -> Result<Option<PatreonLink>, Error> {
+        let filter = doc! { "discord_id": discord_id as i64 };
+        Ok(Self::collection()?.find_one(filter).await?)
+    }
+
+    pub async fn get_link_by_patreon_user(
+        patreon_user_id: &str,
+    ) -> Result<Option<PatreonLink>, Error> {
+        let filter = doc! { "patreon_user_id": patreon_user_id };
+        Ok(Self::collection()?.find_one(filter).await?)
+    }
+}
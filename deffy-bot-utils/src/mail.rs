@@ -0,0 +1,115 @@
+use std::{env, path::PathBuf, sync::Arc};
+
+use anyhow::Error;
+use chrono::Utc;
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::Mailbox,
+    transport::smtp::authentication::Credentials,
+};
+use once_cell::sync::Lazy;
+use serenity::async_trait;
+
+static MAILER: Lazy<Option<Arc<dyn MailTransport>>> = Lazy::new(|| match mail_transport_from_env() {
+    Ok(transport) => Some(transport),
+    Err(e) => {
+        tracing::warn!("Mail can't be sent: {}", e);
+        None
+    }
+});
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, mail: &Mail) -> Result<(), Error>;
+}
+
+/// The transport configured by `MAIL_TRANSPORT`, `None` when mail is not set up.
+pub fn mailer() -> Option<Arc<dyn MailTransport>> {
+    MAILER.clone()
+}
+
+/// `MAIL_TRANSPORT=smtp` reads `SMTP_HOST`, `SMTP_PORT`, `SMTP_USERNAME`, `SMTP_PASSWORD` and
+/// `MAIL_FROM`. `MAIL_TRANSPORT=file` writes every mail to `MAIL_SINK_DIR` instead.
+pub fn mail_transport_from_env() -> Result<Arc<dyn MailTransport>, Error> {
+    match env::var("MAIL_TRANSPORT").as_deref() {
+        Ok("smtp") => Ok(Arc::new(SmtpMailTransport::from_env()?)),
+        Ok("file") => Ok(Arc::new(FileMailTransport::new(
+            env::var("MAIL_SINK_DIR").unwrap_or_else(|_| "mail".to_string()),
+        ))),
+        Ok(other) => Err(anyhow::anyhow!("Unknown MAIL_TRANSPORT `{}`, expected smtp or file", other)),
+        Err(_) => Err(anyhow::anyhow!("MAIL_TRANSPORT is not set")),
+    }
+}
+
+pub struct SmtpMailTransport {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailTransport {
+    pub fn from_env() -> Result<Self, Error> {
+        let host = env::var("SMTP_HOST").map_err(|_| anyhow::anyhow!("SMTP_HOST is not set"))?;
+        let from = env::var("MAIL_FROM").map_err(|_| anyhow::anyhow!("MAIL_FROM is not set"))?;
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?;
+        if let Some(port) = env::var("SMTP_PORT").ok().and_then(|port| port.parse().ok()) {
+            builder = builder.port(port);
+        }
+        if let (Ok(username), Ok(password)) = (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from.parse()?,
+        })
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpMailTransport {
+    async fn send(&self, mail: &Mail) -> Result<(), Error> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail.to.parse()?)
+            .subject(&mail.subject)
+            .body(mail.body.clone())?;
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Writes each mail to its own file, for local runs and tests.
+pub struct FileMailTransport {
+    dir: PathBuf,
+}
+
+impl FileMailTransport {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl MailTransport for FileMailTransport {
+    async fn send(&self, mail: &Mail) -> Result<(), Error> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            mail.to.replace(|c: char| !c.is_ascii_alphanumeric() && c != '@' && c != '.', "_")
+        );
+        let content = format!("To: {}\nSubject: {}\n\n{}\n", mail.to, mail.subject, mail.body);
+
+        tokio::fs::write(self.dir.join(name), content).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::Error;
+use mongodb::bson::DateTime as BsonDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::grace_period::GracePeriod;
+use crate::repository::Repositories;
+
+/// The Discord account that verified with a Patreon membership. A membership binds one account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberBinding {
+    pub patreon_member_id: String,
+    pub discord_id: u64,
+    /// Server the verification happened in.
+    pub guild_id: u64,
+    pub email: String,
+    pub bound_at: BsonDateTime,
+    /// Discord locale of the user when they verified, for the messages sent to them later.
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindOutcome {
+    Bound,
+    /// The membership was already bound to this Discord account.
+    AlreadyBound,
+    ClaimedByOther { discord_id: u64 },
+}
+
+/// Goes through the installed `MemberBindingRepository`.
+pub struct MemberBindingDatabase {}
+
+impl MemberBindingDatabase {
+    pub async fn bind(
+        member_id: &str,
+        discord_id: u64,
+        guild_id: u64,
+        email: &str,
+        locale: Option<String>,
+    ) -> Result<BindOutcome, Error> {
+        let repository = &Repositories::get().bindings;
+
+        let binding = MemberBinding {
+            patreon_member_id: member_id.to_string(),
+            discord_id,
+            guild_id,
+            email: email.to_string(),
+            bound_at: BsonDateTime::now(),
+            locale,
+        };
+
+        if repository.insert(&binding).await? {
+            return Ok(BindOutcome::Bound);
+        }
+
+        // Someone holds the membership, lost a race or verified before
+        match repository.find_by_member_id(member_id).await? {
+            Some(existing) if existing.discord_id == discord_id => Ok(BindOutcome::AlreadyBound),
+            Some(existing) => Ok(BindOutcome::ClaimedByOther {
+                discord_id: existing.discord_id,
+            }),
+            None => Err(anyhow::anyhow!("Binding of member {} vanished", member_id)),
+        }
+    }
+
+    pub async fn get_by_member_id(member_id: &str) -> Result<Option<MemberBinding>, Error> {
+        Repositories::get().bindings.find_by_member_id(member_id).await
+    }
+
+    pub async fn get_by_discord_id(discord_id: u64) -> Result<Vec<MemberBinding>, Error> {
+        Repositories::get().bindings.find_by_discord_id(discord_id).await
+    }
+
+    /// Removes every binding of the Discord account and the grace periods running for
+    /// them, returning what was removed. The roles are left to the caller.
+    pub async fn unlink(discord_id: u64) -> Result<Vec<MemberBinding>, Error> {
+        let repository = &Repositories::get().bindings;
+
+        let bindings = repository.find_by_discord_id(discord_id).await?;
+        for binding in &bindings {
+            repository.remove(&binding.patreon_member_id).await?;
+            GracePeriod::cancel(&binding.patreon_member_id).await?;
+        }
+        Ok(bindings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grace_period::RevocationReason;
+    use crate::test_support::in_memory_repositories;
+
+    use super::*;
+
+    async fn bind(member_id: &str, discord_id: u64) -> BindOutcome {
+        MemberBindingDatabase::bind(member_id, discord_id, 7, "patron@example.com", None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_membership_binds_one_account() {
+        in_memory_repositories();
+
+        assert_eq!(bind("bind-1", 2201).await, BindOutcome::Bound);
+        assert_eq!(bind("bind-1", 2201).await, BindOutcome::AlreadyBound);
+        assert_eq!(bind("bind-1", 2202).await, BindOutcome::ClaimedByOther { discord_id: 2201 });
+
+        let binding = MemberBindingDatabase::get_by_member_id("bind-1").await.unwrap().unwrap();
+        assert_eq!(binding.discord_id, 2201);
+    }
+
+    #[tokio::test]
+    async fn unlink_frees_the_memberships_and_their_grace_periods() {
+        in_memory_repositories();
+
+        bind("unlink-1", 2203).await;
+        bind("unlink-2", 2203).await;
+        assert!(GracePeriod::start("unlink-1", None, RevocationReason::ChargeDeclined).await.unwrap());
+
+        let removed = MemberBindingDatabase::unlink(2203).await.unwrap();
+        let mut removed: Vec<_> = removed.iter().map(|b| b.patreon_member_id.as_str()).collect();
+        removed.sort();
+        assert_eq!(removed, ["unlink-1", "unlink-2"]);
+
+        assert!(MemberBindingDatabase::get_by_discord_id(2203).await.unwrap().is_empty());
+        assert!(GracePeriod::cancel("unlink-1").await.unwrap().is_none());
+
+        // Free to be claimed by another account now
+        assert_eq!(bind("unlink-1", 2204).await, BindOutcome::Bound);
+    }
+}
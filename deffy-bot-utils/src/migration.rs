@@ -0,0 +1,350 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::Error;
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime as BsonDateTime, Document, doc},
+    error::ErrorKind,
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use serenity::futures::{FutureExt, future::BoxFuture};
+
+use crate::database::DatabaseManager;
+use crate::guild_settings::GuildSettings;
+use crate::repository::{GuildSettingsRepository, mongo::MongoGuildSettingsRepository};
+use crate::webhook_database::DELIVERY_TTL;
+
+/// One schema change, applied once and recorded in `migrations` under its id.
+pub struct Migration {
+    pub id: &'static str,
+    pub description: &'static str,
+    run: fn() -> BoxFuture<'static, Result<(), Error>>,
+}
+
+const DUPLICATE_KEY: i32 = 11000;
+
+/// In the order they are applied. Never reorder or edit an applied migration, add a new one.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        id: "0001_user_data_indexes",
+        description: "index patrons by member id, email and user id",
+        run: || user_data_indexes().boxed(),
+    },
+    Migration {
+        id: "0002_server_data_indexes",
+        description: "one settings document per server, index the campaign",
+        run: || server_data_indexes().boxed(),
+    },
+    Migration {
+        id: "0003_wip_data_indexes",
+        description: "index WIP entries by title and message",
+        run: || wip_data_indexes().boxed(),
+    },
+    Migration {
+        id: "0004_server_data_v2",
+        description: "rewrite flat server settings as GuildSettings v2",
+        run: || upgrade_server_data().boxed(),
+    },
+    Migration {
+        id: "0005_verification_codes_index",
+        description: "one pending email code per Discord user",
+        run: || verification_codes_index().boxed(),
+    },
+    Migration {
+        id: "0006_member_bindings_indexes",
+        description: "one Discord account per Patreon membership",
+        run: || member_bindings_indexes().boxed(),
+    },
+    Migration {
+        id: "0007_pending_revocations_index",
+        description: "one pending revocation per Patreon membership",
+        run: || pending_revocations_index().boxed(),
+    },
+    Migration {
+        id: "0008_webhook_deliveries_ttl",
+        description: "expire recorded webhook deliveries",
+        run: || webhook_deliveries_ttl().boxed(),
+    },
+    Migration {
+        id: "0009_webhook_inbox_indexes",
+        description: "index the webhook inbox by due time and dead letters by date",
+        run: || webhook_inbox_indexes().boxed(),
+    },
+    Migration {
+        id: "0010_dedupe_user_data",
+        description: "keep one patron row per member id and make member ids unique",
+        run: || dedupe_user_data().boxed(),
+    },
+    Migration {
+        id: "0011_email_sends_ttl",
+        description: "expire email verification send limits",
+        run: || email_sends_ttl().boxed(),
+    },
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub description: String,
+    pub applied_at: BsonDateTime,
+}
+
+pub struct MigrationRunner {}
+
+impl MigrationRunner {
+    fn collection() -> Collection<AppliedMigration> {
+        DatabaseManager::get_db().collection("migrations")
+    }
+
+    /// Applies every migration not recorded yet, stopping at the first failure.
+    /// Returns the ids of the ones applied by this call.
+    pub async fn run() -> Result<Vec<&'static str>, Error> {
+        let applied = Self::applied().await?;
+        let mut ran = Vec::new();
+
+        for migration in MIGRATIONS.iter().filter(|m| !applied.contains(m.id)) {
+            tracing::info!("Applying migration {}: {}", migration.id, migration.description);
+
+            (migration.run)()
+                .await
+                .map_err(|e| anyhow::anyhow!("Migration {} failed: {}", migration.id, e))?;
+
+            Self::collection()
+                .insert_one(AppliedMigration {
+                    id: migration.id.to_string(),
+                    description: migration.description.to_string(),
+                    applied_at: BsonDateTime::now(),
+                })
+                .await?;
+            ran.push(migration.id);
+        }
+
+        Ok(ran)
+    }
+
+    pub async fn applied() -> Result<HashSet<String>, Error> {
+        let mut cursor = Self::collection().find(doc! {}).await?;
+
+        let mut applied = HashSet::new();
+        while cursor.advance().await? {
+            applied.insert(cursor.deserialize_current()?.id);
+        }
+        Ok(applied)
+    }
+}
+
+fn index(keys: Document) -> IndexModel {
+    IndexModel::builder().keys(keys).build()
+}
+
+fn unique_index(keys: Document, partial_filter: Option<Document>) -> IndexModel {
+    let options = IndexOptions::builder()
+        .unique(true)
+        .partial_filter_expression(partial_filter)
+        .build();
+    IndexModel::builder().keys(keys).options(options).build()
+}
+
+async fn create_indexes(collection: &str, indexes: Vec<IndexModel>) -> Result<(), Error> {
+    DatabaseManager::get_db()
+        .collection::<Document>(collection)
+        .create_indexes(indexes)
+        .await?;
+    Ok(())
+}
+
+/// Rows stored before member ids were recorded have none.
+fn member_id_index() -> IndexModel {
+    unique_index(
+        doc! { "patreon_member_id": 1 },
+        Some(doc! { "patreon_member_id": { "$type": "string" } }),
+    )
+}
+
+/// Keeps the newest row of every member id stored more than once, then adds the unique
+/// member id index 0001 had to leave out. The next sync writes the member again anyway.
+async fn dedupe_user_data() -> Result<(), Error> {
+    let collection: Collection<Document> = DatabaseManager::get_db().collection("user_data");
+
+    let pipeline = vec![
+        doc! { "$match": { "patreon_member_id": { "$type": "string" } } },
+        doc! { "$sort": { "_id": 1 } },
+        doc! { "$group": {
+            "_id": "$patreon_member_id",
+            "ids": { "$push": "$_id" },
+            "count": { "$sum": 1 },
+        } },
+        doc! { "$match": { "count": { "$gt": 1 } } },
+    ];
+
+    let mut cursor = collection.aggregate(pipeline).allow_disk_use(true).await?;
+    while cursor.advance().await? {
+        let group = cursor.deserialize_current()?;
+        let mut ids = group.get_array("ids")?.clone();
+        // Sorted by `_id`, the last one is the newest
+        ids.pop();
+
+        tracing::info!(
+            "Removing {} duplicate rows of member {:?}",
+            ids.len(),
+            group.get("_id")
+        );
+        collection.delete_many(doc! { "_id": { "$in": ids } }).await?;
+    }
+    create_indexes("user_data", vec![member_id_index()]).await
+}
+
+/// The unique member id index fails on a collection holding the same member twice, it is
+/// then left to 0010. Where this was applied the index exists and nothing changes.
+async fn user_data_indexes() -> Result<(), Error> {
+    create_indexes(
+        "user_data",
+        vec![
+            index(doc! { "patreon_email": 1 }),
+            index(doc! { "patreon_user_id": 1 }),
+        ],
+    )
+    .await?;
+
+    let result = DatabaseManager::get_db()
+        .collection::<Document>("user_data")
+        .create_index(member_id_index())
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => match *e.kind {
+            ErrorKind::Command(ref command) if command.code == DUPLICATE_KEY => {
+                tracing::warn!("Patrons are stored twice, the member id index waits for 0010");
+                Ok(())
+            }
+            _ => Err(e.into()),
+        },
+    }
+}
+
+async fn server_data_indexes() -> Result<(), Error> {
+    create_indexes(
+        "server_data",
+        vec![
+            unique_index(doc! { "server_id": 1 }, None),
+            index(doc! { "campaign_id": 1 }),
+        ],
+    )
+    .await
+}
+
+async fn wip_data_indexes() -> Result<(), Error> {
+    create_indexes(
+        "wip_data",
+        vec![
+            index(doc! { "title": 1 }),
+            unique_index(doc! { "message_id": 1 }, None),
+        ],
+    )
+    .await
+}
+
+async fn verification_codes_index() -> Result<(), Error> {
+    create_indexes(
+        "verification_codes",
+        vec![unique_index(doc! { "discord_id": 1 }, None)],
+    )
+    .await
+}
+
+async fn member_bindings_indexes() -> Result<(), Error> {
+    create_indexes(
+        "member_bindings",
+        vec![
+            unique_index(doc! { "patreon_member_id": 1 }, None),
+            index(doc! { "discord_id": 1 }),
+        ],
+    )
+    .await
+}
+
+async fn pending_revocations_index() -> Result<(), Error> {
+    create_indexes(
+        "pending_revocations",
+        vec![unique_index(doc! { "patreon_member_id": 1 }, None)],
+    )
+    .await
+}
+
+async fn webhook_deliveries_ttl() -> Result<(), Error> {
+    let ttl = IndexOptions::builder().expire_after(DELIVERY_TTL).build();
+    create_indexes(
+        "webhook_deliveries",
+        vec![IndexModel::builder().keys(doc! { "received_at": 1 }).options(ttl).build()],
+    )
+    .await
+}
+
+async fn webhook_inbox_indexes() -> Result<(), Error> {
+    // The worker claims the oldest due delivery
+    create_indexes(
+        "webhook_inbox",
+        vec![index(doc! { "next_attempt_at": 1, "received_at": 1 })],
+    )
+    .await?;
+    create_indexes(
+        "webhook_dead_letter",
+        vec![index(doc! { "dead_lettered_at": 1 })],
+    )
+    .await
+}
+
+async fn email_sends_ttl() -> Result<(), Error> {
+    // Each document carries its own expiry time
+    let ttl = IndexOptions::builder().expire_after(Duration::ZERO).build();
+    create_indexes(
+        "email_sends",
+        vec![IndexModel::builder().keys(doc! { "expires_at": 1 }).options(ttl).build()],
+    )
+    .await
+}
+
+async fn upgrade_server_data() -> Result<(), Error> {
+    let collection: Collection<Document> = DatabaseManager::get_db().collection("server_data");
+    let repository = MongoGuildSettingsRepository::default();
+
+    let mut cursor = collection.find(doc! { "version": { "$exists": false } }).await?;
+    while cursor.advance().await? {
+        match GuildSettings::from_document(cursor.deserialize_current()?) {
+            Ok(settings) => repository.save(&settings).await?,
+            // Left as is, nothing reads a v1 document without a server id
+            Err(e) => tracing::warn!("Skipping server settings: {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(id: &str) -> usize {
+        MIGRATIONS.iter().position(|m| m.id == id).unwrap()
+    }
+
+    #[test]
+    fn ids_are_unique() {
+        let ids: HashSet<_> = MIGRATIONS.iter().map(|m| m.id).collect();
+        assert_eq!(ids.len(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn ids_follow_the_order_they_are_applied_in() {
+        let ids: Vec<_> = MIGRATIONS.iter().map(|m| m.id).collect();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(ids, sorted);
+    }
+
+    #[test]
+    fn duplicates_are_removed_after_the_indexes_that_tolerate_them() {
+        assert!(position("0001_user_data_indexes") < position("0010_dedupe_user_data"));
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use anyhow::Error;
+use mongodb::bson::DateTime as BsonDateTime;
+use serenity::async_trait;
+
+use crate::database::PatreonUserData;
+use crate::email_verification::{EmailSends, VerificationCode};
+use crate::grace_period::PendingRevocation;
+use crate::guild_settings::GuildSettings;
+use crate::member_binding::MemberBinding;
+use crate::repository::{
+    GuildSettingsRepository, MemberBindingRepository, PatronRepository, RevocationRepository,
+    VerificationCodeRepository, WipRepository,
+};
+use crate::wip_database::WipEntry;
+
+/// Patrons keyed by member id.
+#[derive(Default)]
+pub struct InMemoryPatronRepository {
+    patrons: Mutex<HashMap<String, PatreonUserData>>,
+}
+
+impl InMemoryPatronRepository {
+    fn find(&self, matches: impl Fn(&PatreonUserData) -> bool) -> Option<PatreonUserData> {
+        self.patrons.lock().unwrap().values().find(|data| matches(data)).cloned()
+    }
+}
+
+#[async_trait]
+impl PatronRepository for InMemoryPatronRepository {
+    async fn all(&self) -> Result<Vec<PatreonUserData>, Error> {
+        Ok(self.patrons.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn find_by_member_id(&self, member_id: &str) -> Result<Option<PatreonUserData>, Error> {
+        Ok(self.patrons.lock().unwrap().get(member_id).cloned())
+    }
+
+    async fn find_by_user_id(&self, user_id: &str) -> Result<Option<PatreonUserData>, Error> {
+        Ok(self.find(|data| data.patreon_user_id.as_deref() == Some(user_id)))
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<PatreonUserData>, Error> {
+        Ok(self.find(|data| data.patreon_email.as_deref() == Some(email)))
+    }
+
+    async fn upsert(&self, data: &PatreonUserData) -> Result<(), Error> {
+        let Some(member_id) = &data.patreon_member_id else {
+            return Err(anyhow::anyhow!("Can't store a patron without a member id"));
+        };
+
+        self.patrons.lock().unwrap().insert(member_id.clone(), data.clone());
+        Ok(())
+    }
+
+    async fn retain(&self, member_ids: &HashSet<String>) -> Result<(), Error> {
+        self.patrons
+            .lock()
+            .unwrap()
+            .retain(|member_id, _| member_ids.contains(member_id));
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryGuildSettingsRepository {
+    settings: Mutex<HashMap<u64, GuildSettings>>,
+}
+
+#[async_trait]
+impl GuildSettingsRepository for InMemoryGuildSettingsRepository {
+    async fn get(&self, server_id: u64) -> Result<Option<GuildSettings>, Error> {
+        Ok(self.settings.lock().unwrap().get(&server_id).cloned())
+    }
+
+    async fn save(&self, settings: &GuildSettings) -> Result<(), Error> {
+        self.settings
+            .lock()
+            .unwrap()
+            .insert(settings.server_id, settings.clone());
+        Ok(())
+    }
+
+    async fn campaign_servers(&self, campaign_id: Option<&str>) -> Result<Vec<GuildSettings>, Error> {
+        Ok(self
+            .settings
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|settings| match (campaign_id, settings.campaign_id.as_deref()) {
+                (Some(wanted), Some(followed)) => wanted == followed,
+                _ => true,
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryWipRepository {
+    entries: Mutex<Vec<WipEntry>>,
+}
+
+#[async_trait]
+impl WipRepository for InMemoryWipRepository {
+    async fn create(&self, entry: WipEntry) -> Result<(), Error> {
+        self.entries.lock().unwrap().push(entry);
+        Ok(())
+    }
+
+    async fn update_state(&self, message_id: u64, state: u8) -> Result<(), Error> {
+        if let Some(entry) = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|entry| entry.message_id == message_id)
+        {
+            entry.state = state;
+        }
+        Ok(())
+    }
+
+    async fn get(&self, title: &str) -> Result<Option<WipEntry>, Error> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.title == title)
+            .cloned())
+    }
+
+    async fn remove(&self, title: &str) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(index) = entries.iter().position(|entry| entry.title == title) {
+            entries.remove(index);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryVerificationCodeRepository {
+    codes: Mutex<HashMap<u64, VerificationCode>>,
+    email_sends: Mutex<HashMap<String, EmailSends>>,
+}
+
+#[async_trait]
+impl VerificationCodeRepository for InMemoryVerificationCodeRepository {
+    async fn get(&self, discord_id: u64) -> Result<Option<VerificationCode>, Error> {
+        Ok(self.codes.lock().unwrap().get(&discord_id).cloned())
+    }
+
+    async fn save(&self, code: &VerificationCode) -> Result<(), Error> {
+        self.codes.lock().unwrap().insert(code.discord_id, code.clone());
+        Ok(())
+    }
+
+    async fn claim_attempt(
+        &self,
+        discord_id: u64,
+        max_attempts: u32,
+    ) -> Result<Option<VerificationCode>, Error> {
+        let mut codes = self.codes.lock().unwrap();
+        Ok(codes
+            .get_mut(&discord_id)
+            .filter(|code| code.attempts < max_attempts)
+            .map(|code| {
+                code.attempts += 1;
+                code.clone()
+            }))
+    }
+
+    async fn email_sends(&self, address: &str) -> Result<Option<EmailSends>, Error> {
+        Ok(self.email_sends.lock().unwrap().get(address).cloned())
+    }
+
+    async fn save_email_sends(&self, sends: &EmailSends) -> Result<(), Error> {
+        let now = BsonDateTime::now();
+        let mut email_sends = self.email_sends.lock().unwrap();
+        email_sends.retain(|_, sends| sends.expires_at > now);
+        email_sends.insert(sends.address.clone(), sends.clone());
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryMemberBindingRepository {
+    bindings: Mutex<HashMap<String, MemberBinding>>,
+}
+
+#[async_trait]
+impl MemberBindingRepository for InMemoryMemberBindingRepository {
+    async fn find_by_member_id(&self, member_id: &str) -> Result<Option<MemberBinding>, Error> {
+        Ok(self.bindings.lock().unwrap().get(member_id).cloned())
+    }
+
+    async fn find_by_discord_id(&self, discord_id: u64) -> Result<Vec<MemberBinding>, Error> {
+        Ok(self
+            .bindings
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|binding| binding.discord_id == discord_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn insert(&self, binding: &MemberBinding) -> Result<bool, Error> {
+        let mut bindings = self.bindings.lock().unwrap();
+        if bindings.contains_key(&binding.patreon_member_id) {
+            return Ok(false);
+        }
+        bindings.insert(binding.patreon_member_id.clone(), binding.clone());
+        Ok(true)
+    }
+
+    async fn remove(&self, member_id: &str) -> Result<(), Error> {
+        self.bindings.lock().unwrap().remove(member_id);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryRevocationRepository {
+    revocations: Mutex<HashMap<String, PendingRevocation>>,
+}
+
+#[async_trait]
+impl RevocationRepository for InMemoryRevocationRepository {
+    async fn get(&self, member_id: &str) -> Result<Option<PendingRevocation>, Error> {
+        Ok(self.revocations.lock().unwrap().get(member_id).cloned())
+    }
+
+    async fn all(&self) -> Result<Vec<PendingRevocation>, Error> {
+        Ok(self.revocations.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn save(&self, revocation: &PendingRevocation) -> Result<(), Error> {
+        self.revocations
+            .lock()
+            .unwrap()
+            .insert(revocation.patreon_member_id.clone(), revocation.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, member_id: &str) -> Result<(), Error> {
+        self.revocations.lock().unwrap().remove(member_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::patron;
+
+    #[tokio::test]
+    async fn patrons_are_found_and_retained_by_member_id() {
+        let repository = InMemoryPatronRepository::default();
+        repository.upsert(&patron("a", "a@example.com")).await.unwrap();
+        repository.upsert(&patron("b", "b@example.com")).await.unwrap();
+
+        let found = repository.find_by_email("b@example.com").await.unwrap();
+        assert_eq!(found.unwrap().patreon_member_id.as_deref(), Some("b"));
+        assert!(repository.find_by_user_id("user-a").await.unwrap().is_some());
+
+        repository.retain(&HashSet::from(["a".to_string()])).await.unwrap();
+        assert!(repository.find_by_member_id("b").await.unwrap().is_none());
+        assert_eq!(repository.all().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn campaign_servers_include_servers_without_a_campaign() {
+        let repository = InMemoryGuildSettingsRepository::default();
+        let mut following = GuildSettings::new(1);
+        following.campaign_id = Some("c1".to_string());
+        let mut other = GuildSettings::new(2);
+        other.campaign_id = Some("c2".to_string());
+        repository.save(&following).await.unwrap();
+        repository.save(&other).await.unwrap();
+        repository.save(&GuildSettings::new(3)).await.unwrap();
+
+        let mut servers: Vec<u64> = repository
+            .campaign_servers(Some("c1"))
+            .await
+            .unwrap()
+            .iter()
+            .map(|settings| settings.server_id)
+            .collect();
+        servers.sort();
+        assert_eq!(servers, vec![1, 3]);
+        assert_eq!(repository.campaign_servers(None).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn wip_state_is_updated_by_message_id() {
+        let repository = InMemoryWipRepository::default();
+        repository
+            .create(WipEntry {
+                title: "chapter".to_string(),
+                channel_id: 1,
+                description: String::new(),
+                message_id: 42,
+                image: String::new(),
+                state: 1,
+            })
+            .await
+            .unwrap();
+
+        repository.update_state(42, 3).await.unwrap();
+        assert_eq!(repository.get("chapter").await.unwrap().unwrap().state, 3);
+
+        repository.remove("chapter").await.unwrap();
+        assert!(repository.get("chapter").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn a_membership_is_bound_once() {
+        let repository = InMemoryMemberBindingRepository::default();
+        let binding = |discord_id| MemberBinding {
+            patreon_member_id: "m1".to_string(),
+            discord_id,
+            guild_id: 1,
+            email: "a@example.com".to_string(),
+            bound_at: mongodb::bson::DateTime::now(),
+            locale: None,
+        };
+
+        assert!(repository.insert(&binding(10)).await.unwrap());
+        assert!(!repository.insert(&binding(20)).await.unwrap());
+        assert_eq!(repository.find_by_member_id("m1").await.unwrap().unwrap().discord_id, 10);
+
+        repository.remove("m1").await.unwrap();
+        assert!(repository.find_by_discord_id(10).await.unwrap().is_empty());
+        assert!(repository.insert(&binding(20)).await.unwrap());
+    }
+}
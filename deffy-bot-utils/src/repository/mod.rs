@@ -0,0 +1,156 @@
+pub mod memory;
+pub mod mongo;
+
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Error;
+use once_cell::sync::OnceCell;
+use serenity::async_trait;
+
+use crate::database::PatreonUserData;
+use crate::email_verification::{EmailSends, VerificationCode};
+use crate::grace_period::PendingRevocation;
+use crate::guild_settings::GuildSettings;
+use crate::member_binding::MemberBinding;
+use crate::wip_database::WipEntry;
+
+static REPOSITORIES: OnceCell<Repositories> = OnceCell::new();
+
+/// Patreon members as stored by the sync.
+#[async_trait]
+pub trait PatronRepository: Send + Sync {
+    async fn all(&self) -> Result<Vec<PatreonUserData>, Error>;
+
+    async fn find_by_member_id(&self, member_id: &str) -> Result<Option<PatreonUserData>, Error>;
+
+    async fn find_by_user_id(&self, user_id: &str) -> Result<Option<PatreonUserData>, Error>;
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<PatreonUserData>, Error>;
+
+    /// Inserts the member or replaces the one with the same member id.
+    async fn upsert(&self, data: &PatreonUserData) -> Result<(), Error>;
+
+    /// Removes every member whose id isn't in `member_ids`, including rows without an id.
+    async fn retain(&self, member_ids: &HashSet<String>) -> Result<(), Error>;
+}
+
+#[async_trait]
+pub trait GuildSettingsRepository: Send + Sync {
+    async fn get(&self, server_id: u64) -> Result<Option<GuildSettings>, Error>;
+
+    async fn save(&self, settings: &GuildSettings) -> Result<(), Error>;
+
+    /// Servers following `campaign_id` or no campaign at all. With no campaign, every server.
+    async fn campaign_servers(&self, campaign_id: Option<&str>) -> Result<Vec<GuildSettings>, Error>;
+}
+
+#[async_trait]
+pub trait WipRepository: Send + Sync {
+    async fn create(&self, entry: WipEntry) -> Result<(), Error>;
+
+    async fn update_state(&self, message_id: u64, state: u8) -> Result<(), Error>;
+
+    async fn get(&self, title: &str) -> Result<Option<WipEntry>, Error>;
+
+    async fn remove(&self, title: &str) -> Result<(), Error>;
+}
+
+/// One-time codes of the email verification, one per Discord user.
+#[async_trait]
+pub trait VerificationCodeRepository: Send + Sync {
+    async fn get(&self, discord_id: u64) -> Result<Option<VerificationCode>, Error>;
+
+    /// Inserts the code or replaces the one of the same user.
+    async fn save(&self, code: &VerificationCode) -> Result<(), Error>;
+
+    /// Counts an attempt at the code of the user in one step, so concurrent guesses can't
+    /// go past `max_attempts`. Returns the code as updated, or `None` when there is no
+    /// code or its attempts are used up.
+    async fn claim_attempt(
+        &self,
+        discord_id: u64,
+        max_attempts: u32,
+    ) -> Result<Option<VerificationCode>, Error>;
+
+    async fn email_sends(&self, address: &str) -> Result<Option<EmailSends>, Error>;
+
+    /// Inserts the sends or replaces the ones of the same address. Records past their
+    /// `expires_at` are dropped.
+    async fn save_email_sends(&self, sends: &EmailSends) -> Result<(), Error>;
+}
+
+/// Which Discord account claimed each Patreon membership.
+#[async_trait]
+pub trait MemberBindingRepository: Send + Sync {
+    async fn find_by_member_id(&self, member_id: &str) -> Result<Option<MemberBinding>, Error>;
+
+    async fn find_by_discord_id(&self, discord_id: u64) -> Result<Vec<MemberBinding>, Error>;
+
+    /// Stores the binding unless the membership is already bound, returning whether it was stored.
+    async fn insert(&self, binding: &MemberBinding) -> Result<bool, Error>;
+
+    async fn remove(&self, member_id: &str) -> Result<(), Error>;
+}
+
+/// Lapsed members waiting for the end of their grace period.
+#[async_trait]
+pub trait RevocationRepository: Send + Sync {
+    async fn get(&self, member_id: &str) -> Result<Option<PendingRevocation>, Error>;
+
+    async fn all(&self) -> Result<Vec<PendingRevocation>, Error>;
+
+    /// Inserts the revocation or replaces the one of the same member.
+    async fn save(&self, revocation: &PendingRevocation) -> Result<(), Error>;
+
+    async fn remove(&self, member_id: &str) -> Result<(), Error>;
+}
+
+/// The storage backends the bot reads and writes through.
+#[derive(Clone)]
+pub struct Repositories {
+    pub patrons: Arc<dyn PatronRepository>,
+    pub guild_settings: Arc<dyn GuildSettingsRepository>,
+    pub wip: Arc<dyn WipRepository>,
+    pub verification_codes: Arc<dyn VerificationCodeRepository>,
+    pub bindings: Arc<dyn MemberBindingRepository>,
+    pub revocations: Arc<dyn RevocationRepository>,
+}
+
+impl Repositories {
+    /// Backed by the database of `DatabaseManager`, which must be initialized.
+    pub fn mongo() -> Self {
+        Self {
+            patrons: Arc::new(mongo::MongoPatronRepository::default()),
+            guild_settings: Arc::new(mongo::MongoGuildSettingsRepository::default()),
+            wip: Arc::new(mongo::MongoWipRepository::default()),
+            verification_codes: Arc::new(mongo::MongoVerificationCodeRepository::default()),
+            bindings: Arc::new(mongo::MongoMemberBindingRepository::default()),
+            revocations: Arc::new(mongo::MongoRevocationRepository::default()),
+        }
+    }
+
+    /// Kept in memory and lost on restart, for debug runs and tests.
+    pub fn in_memory() -> Self {
+        Self {
+            patrons: Arc::new(memory::InMemoryPatronRepository::default()),
+            guild_settings: Arc::new(memory::InMemoryGuildSettingsRepository::default()),
+            wip: Arc::new(memory::InMemoryWipRepository::default()),
+            verification_codes: Arc::new(memory::InMemoryVerificationCodeRepository::default()),
+            bindings: Arc::new(memory::InMemoryMemberBindingRepository::default()),
+            revocations: Arc::new(memory::InMemoryRevocationRepository::default()),
+        }
+    }
+
+    /// Sets the repositories used from now on. Only the first call has an effect.
+    pub fn install(repositories: Repositories) -> bool {
+        REPOSITORIES.set(repositories).is_ok()
+    }
+
+    pub fn get() -> &'static Repositories {
+        REPOSITORIES.get().expect("Repositories not installed")
+    }
+
+    pub fn is_installed() -> bool {
+        REPOSITORIES.get().is_some()
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+
+use anyhow::Error;
+use mongodb::{
+    Collection,
+    bson::{self, Document, doc},
+    error::{ErrorKind, WriteFailure},
+    options::ReturnDocument,
+};
+use serenity::async_trait;
+
+use crate::database::{DatabaseManager, PatreonUserData};
+use crate::email_verification::{EmailSends, VerificationCode};
+use crate::grace_period::PendingRevocation;
+use crate::guild_settings::{GUILD_SETTINGS_VERSION, GuildSettings};
+use crate::member_binding::MemberBinding;
+use crate::repository::{
+    GuildSettingsRepository, MemberBindingRepository, PatronRepository, RevocationRepository,
+    VerificationCodeRepository, WipRepository,
+};
+use crate::wip_database::WipEntry;
+
+#[derive(Default)]
+pub struct MongoPatronRepository {}
+
+impl MongoPatronRepository {
+    fn collection() -> Collection<PatreonUserData> {
+        DatabaseManager::get_db().collection("user_data")
+    }
+
+    async fn find_one(filter: Document) -> Result<Option<PatreonUserData>, Error> {
+        Ok(Self::collection().find_one(filter).await?)
+    }
+}
+
+#[async_trait]
+impl PatronRepository for MongoPatronRepository {
+    async fn all(&self) -> Result<Vec<PatreonUserData>, Error> {
+        let mut cursor = Self::collection().find(doc! {}).await?;
+
+        let mut patrons = Vec::new();
+        while cursor.advance().await? {
+            patrons.push(cursor.deserialize_current()?);
+        }
+        Ok(patrons)
+    }
+
+    async fn find_by_member_id(&self, member_id: &str) -> Result<Option<PatreonUserData>, Error> {
+        Self::find_one(doc! { "patreon_member_id": member_id }).await
+    }
+
+    async fn find_by_user_id(&self, user_id: &str) -> Result<Option<PatreonUserData>, Error> {
+        Self::find_one(doc! { "patreon_user_id": user_id }).await
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<PatreonUserData>, Error> {
+        Self::find_one(doc! { "patreon_email": email }).await
+    }
+
+    async fn upsert(&self, data: &PatreonUserData) -> Result<(), Error> {
+        let Some(member_id) = &data.patreon_member_id else {
+            return Err(anyhow::anyhow!("Can't store a patron without a member id"));
+        };
+
+        Self::collection()
+            .replace_one(doc! { "patreon_member_id": member_id }, data)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    async fn retain(&self, member_ids: &HashSet<String>) -> Result<(), Error> {
+        let member_ids: Vec<&String> = member_ids.iter().collect();
+        Self::collection()
+            .delete_many(doc! { "patreon_member_id": { "$nin": member_ids } })
+            .await?;
+        Ok(())
+    }
+}
+
+/// Reads every stored version of the settings, always writes the current one.
+#[derive(Default)]
+pub struct MongoGuildSettingsRepository {}
+
+impl MongoGuildSettingsRepository {
+    fn collection() -> Collection<Document> {
+        DatabaseManager::get_db().collection("server_data")
+    }
+}
+
+#[async_trait]
+impl GuildSettingsRepository for MongoGuildSettingsRepository {
+    async fn get(&self, server_id: u64) -> Result<Option<GuildSettings>, Error> {
+        let document = Self::collection()
+            .find_one(doc! { "server_id": server_id as i64 })
+            .await?;
+
+        document.map(GuildSettings::from_document).transpose()
+    }
+
+    async fn save(&self, settings: &GuildSettings) -> Result<(), Error> {
+        let settings = GuildSettings {
+            version: GUILD_SETTINGS_VERSION,
+            ..settings.clone()
+        };
+
+        Self::collection()
+            .replace_one(
+                doc! { "server_id": settings.server_id as i64 },
+                bson::to_document(&settings)?,
+            )
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    async fn campaign_servers(&self, campaign_id: Option<&str>) -> Result<Vec<GuildSettings>, Error> {
+        let filter = match campaign_id {
+            Some(campaign_id) => doc! {
+                "$or": [{ "campaign_id": campaign_id }, { "campaign_id": null }]
+            },
+            None => doc! {},
+        };
+
+        let mut cursor = Self::collection().find(filter).await?;
+
+        let mut servers = Vec::new();
+        while cursor.advance().await? {
+            match GuildSettings::from_document(cursor.deserialize_current()?) {
+                Ok(server) => servers.push(server),
+                Err(e) => tracing::warn!("Skipping unreadable server settings: {}", e),
+            }
+        }
+        Ok(servers)
+    }
+}
+
+#[derive(Default)]
+pub struct MongoWipRepository {}
+
+impl MongoWipRepository {
+    fn collection() -> Collection<WipEntry> {
+        DatabaseManager::get_db().collection("wip_data")
+    }
+}
+
+#[async_trait]
+impl WipRepository for MongoWipRepository {
+    async fn create(&self, entry: WipEntry) -> Result<(), Error> {
+        Self::collection().insert_one(entry).await?;
+        Ok(())
+    }
+
+    async fn update_state(&self, message_id: u64, state: u8) -> Result<(), Error> {
+        let filter = doc! { "message_id": message_id as i64 };
+
+        let update = doc! {
+            "$set": {
+                "state": state as i64,
+            }
+        };
+
+        Self::collection().update_one(filter, update).await?;
+        Ok(())
+    }
+
+    async fn get(&self, title: &str) -> Result<Option<WipEntry>, Error> {
+        Ok(Self::collection().find_one(doc! { "title": title }).await?)
+    }
+
+    async fn remove(&self, title: &str) -> Result<(), Error> {
+        Self::collection().delete_one(doc! { "title": title }).await?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MongoVerificationCodeRepository {}
+
+impl MongoVerificationCodeRepository {
+    fn collection() -> Collection<VerificationCode> {
+        DatabaseManager::get_db().collection("verification_codes")
+    }
+
+    /// Expired by the TTL index of migration 0011.
+    fn email_sends_collection() -> Collection<EmailSends> {
+        DatabaseManager::get_db().collection("email_sends")
+    }
+}
+
+#[async_trait]
+impl VerificationCodeRepository for MongoVerificationCodeRepository {
+    async fn get(&self, discord_id: u64) -> Result<Option<VerificationCode>, Error> {
+        Ok(Self::collection()
+            .find_one(doc! { "discord_id": discord_id as i64 })
+            .await?)
+    }
+
+    async fn save(&self, code: &VerificationCode) -> Result<(), Error> {
+        Self::collection()
+            .replace_one(doc! { "discord_id": code.discord_id as i64 }, code)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    async fn claim_attempt(
+        &self,
+        discord_id: u64,
+        max_attempts: u32,
+    ) -> Result<Option<VerificationCode>, Error> {
+        Ok(Self::collection()
+            .find_one_and_update(
+                doc! { "discord_id": discord_id as i64, "attempts": { "$lt": max_attempts as i64 } },
+                doc! { "$inc": { "attempts": 1 } },
+            )
+            .return_document(ReturnDocument::After)
+            .await?)
+    }
+
+    async fn email_sends(&self, address: &str) -> Result<Option<EmailSends>, Error> {
+        Ok(Self::email_sends_collection()
+            .find_one(doc! { "_id": address })
+            .await?)
+    }
+
+    async fn save_email_sends(&self, sends: &EmailSends) -> Result<(), Error> {
+        Self::email_sends_collection()
+            .replace_one(doc! { "_id": &sends.address }, sends)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+}
+
+/// The unique index on `patreon_member_id` makes the insert the claim.
+#[derive(Default)]
+pub struct MongoMemberBindingRepository {}
+
+impl MongoMemberBindingRepository {
+    fn collection() -> Collection<MemberBinding> {
+        DatabaseManager::get_db().collection("member_bindings")
+    }
+}
+
+#[async_trait]
+impl MemberBindingRepository for MongoMemberBindingRepository {
+    async fn find_by_member_id(&self, member_id: &str) -> Result<Option<MemberBinding>, Error> {
+        Ok(Self::collection()
+            .find_one(doc! { "patreon_member_id": member_id })
+            .await?)
+    }
+
+    async fn find_by_discord_id(&self, discord_id: u64) -> Result<Vec<MemberBinding>, Error> {
+        let mut cursor = Self::collection()
+            .find(doc! { "discord_id": discord_id as i64 })
+            .await?;
+
+        let mut bindings = Vec::new();
+        while cursor.advance().await? {
+            bindings.push(cursor.deserialize_current()?);
+        }
+        Ok(bindings)
+    }
+
+    async fn insert(&self, binding: &MemberBinding) -> Result<bool, Error> {
+        match Self::collection().insert_one(binding).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn remove(&self, member_id: &str) -> Result<(), Error> {
+        Self::collection()
+            .delete_one(doc! { "patreon_member_id": member_id })
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MongoRevocationRepository {}
+
+impl MongoRevocationRepository {
+    fn collection() -> Collection<PendingRevocation> {
+        DatabaseManager::get_db().collection("pending_revocations")
+    }
+}
+
+#[async_trait]
+impl RevocationRepository for MongoRevocationRepository {
+    async fn get(&self, member_id: &str) -> Result<Option<PendingRevocation>, Error> {
+        Ok(Self::collection()
+            .find_one(doc! { "patreon_member_id": member_id })
+            .await?)
+    }
+
+    async fn all(&self) -> Result<Vec<PendingRevocation>, Error> {
+        let mut cursor = Self::collection().find(doc! {}).await?;
+
+        let mut revocations = Vec::new();
+        while cursor.advance().await? {
+            revocations.push(cursor.deserialize_current()?);
+        }
+        Ok(revocations)
+    }
+
+    async fn save(&self, revocation: &PendingRevocation) -> Result<(), Error> {
+        Self::collection()
+            .replace_one(
+                doc! { "patreon_member_id": &revocation.patreon_member_id },
+                revocation,
+            )
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, member_id: &str) -> Result<(), Error> {
+        Self::collection()
+            .delete_one(doc! { "patreon_member_id": member_id })
+            .await?;
+        Ok(())
+    }
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
@@ -0,0 +1,359 @@
+use std::{env, str::FromStr, time::Duration};
+
+use anyhow::Error;
+use chrono::Utc;
+use cron::Schedule;
+use mongodb::{Collection, bson::doc};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+
+use crate::database::{DatabaseManager, ScheduleMessage};
+use crate::sync::SyncTrigger;
+
+const CONFIG_ID: &str = "patreon_sync";
+
+static SCHEDULER: Lazy<watch::Sender<SchedulerConfig>> =
+    Lazy::new(|| watch::Sender::new(SchedulerConfig::from_env()));
+
+/// When the timer sync runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SyncSchedule {
+    Interval { seconds: u64 },
+    /// Standard 5 field cron, days of week `0`-`7` with `0` and `7` both Sunday. 6 or 7
+    /// fields (seconds first, year last) are read by the `cron` crate as they are, where days
+    /// of week go from `1` (Sunday) to `7` (Saturday). Evaluated in UTC.
+    Cron { expression: String },
+}
+
+impl SyncSchedule {
+    /// Reads `30m`, `6h`, `1d`, plain seconds, or a cron expression.
+    pub fn parse(value: &str) -> Result<Self, Error> {
+        let value = value.trim();
+        let interval = |number: &str, unit: u64| -> Option<SyncSchedule> {
+            let seconds = number.parse::<u64>().ok()?.checked_mul(unit)?;
+            (seconds > 0).then_some(SyncSchedule::Interval { seconds })
+        };
+
+        let parsed = match value.char_indices().last() {
+            Some((i, 's')) => interval(&value[..i], 1),
+            Some((i, 'm')) => interval(&value[..i], 60),
+            Some((i, 'h')) => interval(&value[..i], 60 * 60),
+            Some((i, 'd')) => interval(&value[..i], 24 * 60 * 60),
+            _ => interval(value, 1),
+        };
+        if let Some(schedule) = parsed {
+            return Ok(schedule);
+        }
+
+        let schedule = SyncSchedule::Cron {
+            expression: value.to_string(),
+        };
+        schedule.cron()?;
+        Ok(schedule)
+    }
+
+    fn cron(&self) -> Result<Option<Schedule>, Error> {
+        let SyncSchedule::Cron { expression } = self else {
+            return Ok(None);
+        };
+        // The cron crate wants a seconds field and numbers days of week from 1
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let expression = match fields.as_slice() {
+            [minute, hour, day, month, day_of_week] => format!(
+                "0 {minute} {hour} {day} {month} {}",
+                standard_day_of_week(day_of_week)?
+            ),
+            _ => expression.clone(),
+        };
+        Schedule::from_str(&expression)
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("invalid cron expression `{}`: {}", expression, e))
+    }
+
+    /// Time until the next run, before jitter.
+    pub fn next_delay(&self) -> Duration {
+        match self {
+            SyncSchedule::Interval { seconds } => Duration::from_secs(*seconds),
+            SyncSchedule::Cron { .. } => self
+                .cron()
+                .ok()
+                .flatten()
+                .and_then(|schedule| schedule.upcoming(Utc).next())
+                .and_then(|next| (next - Utc::now()).to_std().ok())
+                .unwrap_or(Duration::from_secs(24 * 60 * 60)),
+        }
+    }
+}
+
+/// Rewrites a standard cron day of week field (`0`-`7`, Sunday is `0` and `7`) as the list
+/// of `cron` crate ordinals (`1` Sunday to `7` Saturday). Names mean the same in both and are
+/// kept.
+fn standard_day_of_week(field: &str) -> Result<String, Error> {
+    let invalid = || anyhow::anyhow!("invalid day of week `{}`", field);
+    let number = |value: &str| -> Result<u32, Error> {
+        value.parse::<u32>().ok().filter(|day| *day <= 7).ok_or_else(invalid)
+    };
+
+    let mut items = Vec::new();
+    for item in field.split(',') {
+        if item == "*" || item == "?" || item.chars().any(|c| c.is_ascii_alphabetic()) {
+            items.push(item.to_string());
+            continue;
+        }
+
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, number(step)?.max(1)),
+            None => (item, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (number(start)?, number(end)?),
+            None if range == "*" => (0, 6),
+            // `5/2` runs from 5 to the end of the week
+            None if step > 1 => (number(range)?, 7),
+            None => (number(range)?, number(range)?),
+        };
+        if start > end {
+            return Err(invalid());
+        }
+
+        items.extend(
+            (start..=end)
+                .step_by(step as usize)
+                .map(|day| (day % 7 + 1).to_string()),
+        );
+    }
+
+    Ok(items.join(","))
+}
+
+impl std::fmt::Display for SyncSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncSchedule::Interval { seconds } => write!(f, "every {}s", seconds),
+            SyncSchedule::Cron { expression } => write!(f, "cron `{}`", expression),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchedulerConfig {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub schedule: SyncSchedule,
+    /// Up to this many seconds are added to every timer run.
+    pub jitter_seconds: u64,
+    /// Webhook sync requests within this window are merged into one run.
+    pub debounce_seconds: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            id: CONFIG_ID.to_string(),
+            schedule: SyncSchedule::Interval { seconds: 86400 },
+            jitter_seconds: 300,
+            debounce_seconds: 30,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    /// `SYNC_SCHEDULE`, `SYNC_JITTER_SECS` and `SYNC_DEBOUNCE_SECS`, each falling back to the default.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let seconds = |key: &str, default: u64| {
+            env::var(key)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        let schedule = match env::var("SYNC_SCHEDULE") {
+            Ok(value) => SyncSchedule::parse(&value).unwrap_or_else(|e| {
+                tracing::error!("Ignoring SYNC_SCHEDULE: {}", e);
+                default.schedule.clone()
+            }),
+            Err(_) => default.schedule.clone(),
+        };
+
+        Self {
+            schedule,
+            jitter_seconds: seconds("SYNC_JITTER_SECS", default.jitter_seconds),
+            debounce_seconds: seconds("SYNC_DEBOUNCE_SECS", default.debounce_seconds),
+            ..default
+        }
+    }
+
+    pub fn next_delay(&self) -> Duration {
+        let jitter = match self.jitter_seconds {
+            0 => 0,
+            max => rand::thread_rng().gen_range(0..=max),
+        };
+        self.schedule.next_delay() + Duration::from_secs(jitter)
+    }
+
+    pub fn debounce(&self) -> Duration {
+        Duration::from_secs(self.debounce_seconds)
+    }
+}
+
+pub struct Scheduler {}
+
+impl Scheduler {
+    fn collection() -> Result<Collection<SchedulerConfig>, Error> {
+        Ok(DatabaseManager::require_db()?.collection("scheduler_config"))
+    }
+
+    pub fn current() -> SchedulerConfig {
+        SCHEDULER.borrow().clone()
+    }
+
+    pub fn subscribe() -> watch::Receiver<SchedulerConfig> {
+        SCHEDULER.subscribe()
+    }
+
+    /// Picks up a schedule saved from Discord, keeping the env config otherwise.
+    pub async fn load() -> Result<(), Error> {
+        if let Some(config) = Self::collection()?.find_one(doc! { "_id": CONFIG_ID }).await? {
+            SCHEDULER.send_replace(config);
+        }
+        Ok(())
+    }
+
+    /// Saves the config and applies it to the running collector.
+    pub async fn update(config: SchedulerConfig) -> Result<(), Error> {
+        let config = SchedulerConfig {
+            id: CONFIG_ID.to_string(),
+            ..config
+        };
+        config.schedule.cron()?;
+
+        Self::collection()?
+            .replace_one(doc! { "_id": CONFIG_ID }, &config)
+            .upsert(true)
+            .await?;
+        SCHEDULER.send_replace(config);
+        Ok(())
+    }
+
+    /// Waits until no further sync request arrived for the debounce window, merging
+    /// everything received meanwhile into one trigger. A manual request runs right away.
+    pub async fn coalesce(
+        rx: &mut mpsc::UnboundedReceiver<ScheduleMessage>,
+        first: SyncTrigger,
+    ) -> SyncTrigger {
+        let debounce = Self::current().debounce();
+        let mut trigger = first;
+        // Don't let a steady stream of webhooks hold the sync off forever
+        let deadline = tokio::time::Instant::now() + debounce * 5;
+
+        while trigger != SyncTrigger::Manual && !debounce.is_zero() {
+            let wait = debounce.min(deadline.saturating_duration_since(tokio::time::Instant::now()));
+            match tokio::time::timeout(wait, rx.recv()).await {
+                Ok(Some(ScheduleMessage::ForceUpdate(next))) => {
+                    if next == SyncTrigger::Manual {
+                        trigger = next;
+                    }
+                }
+                Ok(Some(other)) => tracing::debug!("Ignoring {:?} while debouncing", other),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        trigger
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Datelike, Timelike, Weekday};
+
+    use super::*;
+
+    fn cron(expression: &str) -> SyncSchedule {
+        SyncSchedule::Cron {
+            expression: expression.to_string(),
+        }
+    }
+
+    fn upcoming(expression: &str, count: usize) -> Vec<DateTime<Utc>> {
+        cron(expression)
+            .cron()
+            .unwrap()
+            .unwrap()
+            .upcoming(Utc)
+            .take(count)
+            .collect()
+    }
+
+    fn weekdays(expression: &str) -> Vec<Weekday> {
+        let mut days: Vec<_> = upcoming(expression, 7).iter().map(|at| at.weekday()).collect();
+        days.sort_by_key(|day| day.num_days_from_sunday());
+        days.dedup();
+        days
+    }
+
+    #[test]
+    fn parse_intervals() {
+        let interval = |seconds| SyncSchedule::Interval { seconds };
+        assert_eq!(SyncSchedule::parse("45s").unwrap(), interval(45));
+        assert_eq!(SyncSchedule::parse("30m").unwrap(), interval(30 * 60));
+        assert_eq!(SyncSchedule::parse(" 6h ").unwrap(), interval(6 * 60 * 60));
+        assert_eq!(SyncSchedule::parse("1d").unwrap(), interval(24 * 60 * 60));
+        assert_eq!(SyncSchedule::parse("3600").unwrap(), interval(3600));
+    }
+
+    #[test]
+    fn parse_rejects_zero_and_overflow() {
+        assert!(SyncSchedule::parse("0").is_err());
+        assert!(SyncSchedule::parse("0m").is_err());
+        assert!(SyncSchedule::parse("").is_err());
+        assert!(SyncSchedule::parse("-5m").is_err());
+        assert!(SyncSchedule::parse("300000000000000d").is_err());
+        assert!(SyncSchedule::parse("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn parse_cron() {
+        assert_eq!(SyncSchedule::parse("0 9 * * 1").unwrap(), cron("0 9 * * 1"));
+        assert_eq!(SyncSchedule::parse("0 0 9 * * 2").unwrap(), cron("0 0 9 * * 2"));
+        assert!(SyncSchedule::parse("0 9 * * 8").is_err());
+        assert!(SyncSchedule::parse("0 9 * * 5-1").is_err());
+        assert!(SyncSchedule::parse("not a schedule").is_err());
+    }
+
+    #[test]
+    fn five_field_cron_uses_standard_days_of_week() {
+        use Weekday::*;
+        let monday = upcoming("0 9 * * 1", 3);
+        assert!(monday.iter().all(|at| at.weekday() == Mon && at.hour() == 9 && at.minute() == 0));
+
+        assert_eq!(weekdays("* * * * 0"), [Sun]);
+        assert_eq!(weekdays("0 0 * * 7"), [Sun]);
+        assert_eq!(weekdays("0 0 * * 1-5"), [Mon, Tue, Wed, Thu, Fri]);
+        assert_eq!(weekdays("0 0 * * 5-7"), [Sun, Fri, Sat]);
+        assert_eq!(weekdays("0 0 * * 0,3"), [Sun, Wed]);
+        assert_eq!(weekdays("0 0 * * */2"), [Sun, Tue, Thu, Sat]);
+        assert_eq!(weekdays("0 0 * * 1-5/2"), [Mon, Wed, Fri]);
+        assert_eq!(weekdays("0 0 * * MON"), [Mon]);
+    }
+
+    #[test]
+    fn six_field_cron_uses_the_crate_days_of_week() {
+        let at = upcoming("0 0 9 * * 2", 3);
+        assert!(at.iter().all(|at| at.weekday() == Weekday::Mon));
+    }
+
+    #[test]
+    fn next_delay() {
+        assert_eq!(
+            SyncSchedule::Interval { seconds: 90 }.next_delay(),
+            Duration::from_secs(90)
+        );
+        assert!(cron("* * * * *").next_delay() <= Duration::from_secs(60));
+        assert!(cron("0 9 * * 1").next_delay() <= Duration::from_secs(7 * 24 * 60 * 60));
+    }
+}
@@ -0,0 +1,364 @@
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use deffy_bot_patreon_services::LastChrgeStatus;
+use mongodb::{
+    Collection,
+    bson::{DateTime as BsonDateTime, doc},
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::database::{DatabaseManager, PatreonUserData};
+use crate::event::manager::EventType;
+
+static SYNC_PROGRESS: Lazy<watch::Sender<SyncProgress>> =
+    Lazy::new(|| watch::Sender::new(SyncProgress::default()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemberChangeKind {
+    Joined,
+    Left,
+    StatusChanged,
+    TierChanged,
+    ChargeDeclined,
+}
+
+impl MemberChangeKind {
+    pub const ALL: [MemberChangeKind; 5] = [
+        MemberChangeKind::Joined,
+        MemberChangeKind::Left,
+        MemberChangeKind::StatusChanged,
+        MemberChangeKind::TierChanged,
+        MemberChangeKind::ChargeDeclined,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemberChangeKind::Joined => "joined",
+            MemberChangeKind::Left => "left",
+            MemberChangeKind::StatusChanged => "status_changed",
+            MemberChangeKind::TierChanged => "tier_changed",
+            MemberChangeKind::ChargeDeclined => "charge_declined",
+        }
+    }
+
+    pub fn event_type(&self) -> EventType {
+        match self {
+            MemberChangeKind::Joined => EventType::PatreonSyncMemberJoined,
+            MemberChangeKind::Left => EventType::PatreonSyncMemberLeft,
+            MemberChangeKind::StatusChanged => EventType::PatreonSyncMemberStatusChanged,
+            MemberChangeKind::TierChanged => EventType::PatreonSyncMemberTierChanged,
+            MemberChangeKind::ChargeDeclined => EventType::PatreonSyncChargeDeclined,
+        }
+    }
+}
+
+/// One difference between the stored member and what Patreon returned in a sync.
+#[derive(Debug, Clone)]
+pub struct MemberChange {
+    pub kind: MemberChangeKind,
+    pub member_id: String,
+    /// `None` when the member joined.
+    pub previous: Option<PatreonUserData>,
+    /// `None` when the member left.
+    pub current: Option<PatreonUserData>,
+    /// Campaign the sync ran for, set by the collector.
+    pub campaign_id: Option<String>,
+}
+
+impl MemberChange {
+    /// Every change from `previous` to `current` of the same member.
+    pub fn between(previous: Option<&PatreonUserData>, current: &PatreonUserData) -> Vec<MemberChange> {
+        let member_id = current.patreon_member_id.clone().unwrap_or_default();
+        let change = |kind| MemberChange {
+            kind,
+            member_id: member_id.clone(),
+            previous: previous.cloned(),
+            current: Some(current.clone()),
+            campaign_id: None,
+        };
+
+        let Some(old) = previous else {
+            return vec![change(MemberChangeKind::Joined)];
+        };
+
+        let mut changes = Vec::new();
+        if old.patreon_status != current.patreon_status {
+            changes.push(change(MemberChangeKind::StatusChanged));
+        }
+
+        let mut old_tiers = old.tier_ids.clone();
+        let mut new_tiers = current.tier_ids.clone();
+        old_tiers.sort();
+        new_tiers.sort();
+        if old_tiers != new_tiers {
+            changes.push(change(MemberChangeKind::TierChanged));
+        }
+
+        // A new decline, either the status flipped or a later charge was declined too
+        let declined = current.last_charge_status == Some(LastChrgeStatus::Declined);
+        if declined
+            && (old.last_charge_status != current.last_charge_status
+                || old.last_charge_date != current.last_charge_date)
+        {
+            changes.push(change(MemberChangeKind::ChargeDeclined));
+        }
+
+        changes
+    }
+
+    pub fn left(previous: PatreonUserData) -> MemberChange {
+        MemberChange {
+            kind: MemberChangeKind::Left,
+            member_id: previous.patreon_member_id.clone().unwrap_or_default(),
+            previous: Some(previous),
+            current: None,
+            campaign_id: None,
+        }
+    }
+}
+
+/// What one run of the Patreon sync did.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub pages: u32,
+    pub members: u32,
+    pub changes: Vec<MemberChange>,
+}
+
+impl SyncReport {
+    pub fn count(&self, kind: MemberChangeKind) -> usize {
+        self.changes.iter().filter(|change| change.kind == kind).count()
+    }
+}
+
+/// What started a sync run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncTrigger {
+    #[default]
+    Timer,
+    Webhook,
+    Manual,
+}
+
+impl SyncTrigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncTrigger::Timer => "timer",
+            SyncTrigger::Webhook => "webhook",
+            SyncTrigger::Manual => "manual",
+        }
+    }
+}
+
+/// State of the current or last sync run, published as the run goes.
+#[derive(Debug, Clone, Default)]
+pub struct SyncProgress {
+    pub trigger: SyncTrigger,
+    pub started_at: DateTime<Utc>,
+    pub pages: u32,
+    pub members: u32,
+    pub changes: usize,
+    pub finished: bool,
+    pub error: Option<String>,
+}
+
+impl SyncProgress {
+    pub fn start(trigger: SyncTrigger) -> Self {
+        let progress = Self {
+            trigger,
+            started_at: Utc::now(),
+            ..Default::default()
+        };
+        progress.publish();
+        progress
+    }
+
+    pub fn publish(&self) {
+        SYNC_PROGRESS.send_replace(self.clone());
+    }
+
+    pub fn subscribe() -> watch::Receiver<SyncProgress> {
+        SYNC_PROGRESS.subscribe()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncDiffCounts {
+    pub joined: u32,
+    pub left: u32,
+    pub status_changed: u32,
+    pub tier_changed: u32,
+    pub charge_declined: u32,
+}
+
+impl From<&SyncReport> for SyncDiffCounts {
+    fn from(report: &SyncReport) -> Self {
+        let count = |kind| report.count(kind) as u32;
+        Self {
+            joined: count(MemberChangeKind::Joined),
+            left: count(MemberChangeKind::Left),
+            status_changed: count(MemberChangeKind::StatusChanged),
+            tier_changed: count(MemberChangeKind::TierChanged),
+            charge_declined: count(MemberChangeKind::ChargeDeclined),
+        }
+    }
+}
+
+/// One finished sync run, as kept in `sync_runs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRun {
+    pub trigger: SyncTrigger,
+    pub started_at: BsonDateTime,
+    pub ended_at: BsonDateTime,
+    pub pages: u32,
+    pub members: u32,
+    pub diff: SyncDiffCounts,
+    pub error: Option<String>,
+}
+
+pub struct SyncRunDatabase {}
+
+impl SyncRunDatabase {
+    fn collection() -> Result<Collection<SyncRun>, Error> {
+        Ok(DatabaseManager::require_db()?.collection("sync_runs"))
+    }
+
+    pub async fn record_run(run: SyncRun) -> Result<(), Error> {
+        Self::collection()?.insert_one(run).await?;
+        Ok(())
+    }
+
+    /// The last `limit` runs, newest first.
+    pub async fn recent_runs(limit: i64) -> Result<Vec<SyncRun>, Error> {
+        let mut cursor = Self::collection()?
+            .find(doc! {})
+            .sort(doc! { "started_at": -1 })
+            .limit(limit)
+            .await?;
+
+        let mut runs = Vec::new();
+        while cursor.advance().await? {
+            runs.push(cursor.deserialize_current()?);
+        }
+        Ok(runs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use deffy_bot_patreon_services::PatronStatus;
+
+    use super::*;
+    use crate::test_support::patron;
+
+    fn member(
+        status: Option<PatronStatus>,
+        tiers: &[&str],
+        charge: Option<(LastChrgeStatus, i64)>,
+    ) -> PatreonUserData {
+        PatreonUserData {
+            patreon_status: status,
+            tier_ids: tiers.iter().map(|tier| tier.to_string()).collect(),
+            last_charge_status: charge.map(|(status, _)| status),
+            last_charge_date: charge.map(|(_, at)| BsonDateTime::from_millis(at)),
+            ..patron("m1", "m1@example.com")
+        }
+    }
+
+    #[test]
+    fn member_changes() {
+        use LastChrgeStatus::{Declined, Paid};
+        use MemberChangeKind::*;
+        use PatronStatus::{ActivePatron, DeclinedPatron, FormerPatron};
+
+        type Case = (&'static str, Option<PatreonUserData>, PatreonUserData, Vec<MemberChangeKind>);
+
+        let active = Some(ActivePatron);
+        let cases: Vec<Case> = vec![
+            ("new member", None, member(active, &["t1"], None), vec![Joined]),
+            (
+                "nothing changed",
+                Some(member(active, &["t1", "t2"], Some((Paid, 1)))),
+                member(active, &["t1", "t2"], Some((Paid, 1))),
+                vec![],
+            ),
+            (
+                "tier order doesn't matter",
+                Some(member(active, &["t1", "t2"], None)),
+                member(active, &["t2", "t1"], None),
+                vec![],
+            ),
+            (
+                "upgraded",
+                Some(member(active, &["t1"], None)),
+                member(active, &["t2"], None),
+                vec![TierChanged],
+            ),
+            (
+                "cancelled",
+                Some(member(active, &["t1"], Some((Paid, 1)))),
+                member(Some(FormerPatron), &[], Some((Paid, 1))),
+                vec![StatusChanged, TierChanged],
+            ),
+            (
+                "first decline",
+                Some(member(active, &["t1"], Some((Paid, 1)))),
+                member(Some(DeclinedPatron), &["t1"], Some((Declined, 2))),
+                vec![StatusChanged, ChargeDeclined],
+            ),
+            (
+                "declined again later",
+                Some(member(Some(DeclinedPatron), &["t1"], Some((Declined, 2)))),
+                member(Some(DeclinedPatron), &["t1"], Some((Declined, 3))),
+                vec![ChargeDeclined],
+            ),
+            (
+                "same decline seen again",
+                Some(member(Some(DeclinedPatron), &["t1"], Some((Declined, 2)))),
+                member(Some(DeclinedPatron), &["t1"], Some((Declined, 2))),
+                vec![],
+            ),
+            (
+                "paid after a decline",
+                Some(member(Some(DeclinedPatron), &["t1"], Some((Declined, 2)))),
+                member(active, &["t1"], Some((Paid, 3))),
+                vec![StatusChanged],
+            ),
+            (
+                "free member became a patron",
+                Some(member(None, &[], None)),
+                member(active, &["t1"], Some((Paid, 1))),
+                vec![StatusChanged, TierChanged],
+            ),
+        ];
+
+        for (name, previous, current, expected) in cases {
+            let changes = MemberChange::between(previous.as_ref(), &current);
+            let kinds: Vec<_> = changes.iter().map(|change| change.kind).collect();
+            assert_eq!(kinds, expected, "{name}");
+            for change in &changes {
+                assert_eq!(change.member_id, "m1", "{name}");
+                assert!(change.current.is_some(), "{name}");
+                assert_eq!(change.previous.is_some(), previous.is_some(), "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn left_keeps_the_last_known_member() {
+        let change = MemberChange::left(patron("m2", "m2@example.com"));
+        assert_eq!(change.kind, MemberChangeKind::Left);
+        assert_eq!(change.member_id, "m2");
+        assert!(change.current.is_none());
+        assert_eq!(change.previous.unwrap().patreon_email.as_deref(), Some("m2@example.com"));
+    }
+
+    #[tokio::test]
+    async fn run_history_fails_without_mongodb() {
+        // The tests never connect a database, like a debug run on in-memory storage
+        assert!(SyncRunDatabase::recent_runs(5).await.is_err());
+    }
+}
@@ -0,0 +1,28 @@
+//! Fixtures shared by the unit tests.
+
+use crate::database::PatreonUserData;
+use crate::repository::Repositories;
+
+/// Installs the in-memory repositories for the whole test binary. They are shared by
+/// every test, so each one works on its own ids.
+pub(crate) fn in_memory_repositories() -> &'static Repositories {
+    Repositories::install(Repositories::in_memory());
+    Repositories::get()
+}
+
+/// A free member (no status, no tier) of the campaign.
+pub(crate) fn patron(member_id: &str, email: &str) -> PatreonUserData {
+    PatreonUserData {
+        patreon_member_id: Some(member_id.to_string()),
+        patreon_user_id: Some(format!("user-{member_id}")),
+        patreon_email: Some(email.to_string()),
+        patreon_username: member_id.to_string(),
+        patreon_status: None,
+        last_charge_date: None,
+        last_charge_status: None,
+        next_charge_date: None,
+        lifetime_support_cents: 0,
+        currently_entitled_amount_cents: 0,
+        tier_ids: Vec::new(),
+    }
+}
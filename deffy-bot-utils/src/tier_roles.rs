@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+
+use deffy_bot_patreon_services::PatronStatus;
+
+use crate::database::PatreonUserData;
+use crate::guild_settings::GuildSettings;
+
+/// Key of `GuildSettings::tier_roles` holding the role of free members.
+pub const FREE_TIER: &str = "free";
+
+/// The tier roles to add to and remove from a Discord member so they match their membership.
+/// Roles not mapped in `tier_roles` are never touched.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RolePlan {
+    pub add: Vec<u64>,
+    pub remove: Vec<u64>,
+}
+
+impl RolePlan {
+    /// `member` is `None` once they left the campaign, `held` are the roles they have now.
+    pub fn between(
+        settings: &GuildSettings,
+        member: Option<&PatreonUserData>,
+        held: &HashSet<u64>,
+    ) -> RolePlan {
+        let wanted = Self::wanted(settings, member);
+        let managed: HashSet<u64> = settings.tier_roles.values().copied().collect();
+
+        let mut add: Vec<u64> = wanted.difference(held).copied().collect();
+        let mut remove: Vec<u64> = managed
+            .iter()
+            .filter(|role_id| held.contains(role_id) && !wanted.contains(role_id))
+            .copied()
+            .collect();
+        add.sort();
+        remove.sort();
+
+        RolePlan { add, remove }
+    }
+
+    /// Active patrons get the role of each tier they are entitled to, everyone else
+    /// still in the campaign the free role.
+    pub fn wanted(settings: &GuildSettings, member: Option<&PatreonUserData>) -> HashSet<u64> {
+        let Some(member) = member else {
+            return HashSet::new();
+        };
+
+        match member.patreon_status {
+            Some(PatronStatus::ActivePatron) => member
+                .tier_ids
+                .iter()
+                .filter_map(|tier_id| settings.tier_roles.get(tier_id))
+                .copied()
+                .collect(),
+            _ => settings.tier_roles.get(FREE_TIER).copied().into_iter().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::patron;
+
+    use super::*;
+
+    const FREE_ROLE: u64 = 10;
+    const GOLD_ROLE: u64 = 11;
+    const SILVER_ROLE: u64 = 12;
+    const UNMANAGED_ROLE: u64 = 99;
+
+    fn settings() -> GuildSettings {
+        let mut settings = GuildSettings::new(1);
+        settings.tier_roles.insert(FREE_TIER.to_string(), FREE_ROLE);
+        settings.tier_roles.insert("gold".to_string(), GOLD_ROLE);
+        settings.tier_roles.insert("silver".to_string(), SILVER_ROLE);
+        settings
+    }
+
+    fn member(status: Option<PatronStatus>, tier_ids: &[&str]) -> PatreonUserData {
+        PatreonUserData {
+            patreon_status: status,
+            tier_ids: tier_ids.iter().map(|id| id.to_string()).collect(),
+            ..patron("m1", "m1@example.com")
+        }
+    }
+
+    fn plan(member: Option<&PatreonUserData>, held: &[u64]) -> RolePlan {
+        RolePlan::between(&settings(), member, &held.iter().copied().collect())
+    }
+
+    #[test]
+    fn active_patrons_get_a_role_per_tier() {
+        let member = member(Some(PatronStatus::ActivePatron), &["gold", "silver", "unmapped"]);
+
+        assert_eq!(
+            plan(Some(&member), &[FREE_ROLE, UNMANAGED_ROLE]),
+            RolePlan { add: vec![GOLD_ROLE, SILVER_ROLE], remove: vec![FREE_ROLE] }
+        );
+        assert!(plan(Some(&member), &[GOLD_ROLE, SILVER_ROLE]).is_empty());
+    }
+
+    #[test]
+    fn former_patrons_fall_back_to_the_free_role() {
+        let member = member(Some(PatronStatus::FormerPatron), &["gold"]);
+
+        assert_eq!(
+            plan(Some(&member), &[GOLD_ROLE, UNMANAGED_ROLE]),
+            RolePlan { add: vec![FREE_ROLE], remove: vec![GOLD_ROLE] }
+        );
+    }
+
+    #[test]
+    fn members_who_left_lose_every_tier_role() {
+        assert_eq!(
+            plan(None, &[FREE_ROLE, GOLD_ROLE, SILVER_ROLE, UNMANAGED_ROLE]),
+            RolePlan { add: vec![], remove: vec![FREE_ROLE, GOLD_ROLE, SILVER_ROLE] }
+        );
+        assert!(plan(None, &[UNMANAGED_ROLE]).is_empty());
+    }
+
+    #[test]
+    fn without_a_free_role_free_members_get_nothing() {
+        let mut settings = settings();
+        settings.tier_roles.remove(FREE_TIER);
+
+        let member = member(None, &[]);
+        assert!(RolePlan::wanted(&settings, Some(&member)).is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::Error;
+use deffy_bot_encryption::TokenCipher;
+use deffy_bot_patreon_services::{PatreonError, PatreonOAuth, TokensResponse};
+use mongodb::{
+    Collection,
+    bson::{DateTime as BsonDateTime, doc},
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::database::DatabaseManager;
+use crate::link_database::PatreonLinkDatabase;
+
+/// Tokens are refreshed once they are this close to expiring.
+const REFRESH_MARGIN: Duration = Duration::from_secs(24 * 60 * 60);
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+static CIPHER: Lazy<Option<TokenCipher>> = Lazy::new(|| match TokenCipher::from_env() {
+    Ok(cipher) => Some(cipher),
+    Err(e) => {
+        tracing::warn!("Patreon tokens can't be stored: {}", e);
+        None
+    }
+});
+
+static OAUTH: Lazy<PatreonOAuth> = Lazy::new(PatreonOAuth::from_env);
+
+/// One lock per user, refresh tokens are single use so two refreshes at once would make
+/// the slower one fail.
+static REFRESH_LOCKS: Lazy<std::sync::Mutex<HashMap<u64, Arc<Mutex<()>>>>> =
+    Lazy::new(Default::default);
+
+/// A user's Patreon OAuth tokens, both encrypted with `TokenCipher`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredTokens {
+    pub discord_id: u64,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub scope: String,
+    pub expires_at: BsonDateTime,
+    pub updated_at: BsonDateTime,
+    /// Set when a refresh failed; the user has to link again.
+    #[serde(default)]
+    pub broken: bool,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+pub struct TokenDatabase {}
+
+impl TokenDatabase {
+    fn collection() -> Result<Collection<StoredTokens>, Error> {
+        Ok(DatabaseManager::require_db()?.collection("patreon_tokens"))
+    }
+
+    fn cipher() -> Result<&'static TokenCipher, Error> {
+        CIPHER
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("TOKEN_ENCRYPTION_KEY is not configured"))
+    }
+
+    pub async fn store_tokens(discord_id: u64, tokens: &TokensResponse) -> Result<(), Error> {
+        let cipher = Self::cipher()?;
+        let now = BsonDateTime::now();
+
+        let stored = StoredTokens {
+            discord_id,
+            access_token: cipher.encrypt(&tokens.access_token),
+            refresh_token: cipher.encrypt(&tokens.refresh_token),
+            scope: tokens.scope.clone(),
+            expires_at: BsonDateTime::from_millis(
+                now.timestamp_millis() + (tokens.expires_in as i64) * 1000,
+            ),
+            updated_at: now,
+            broken: false,
+            last_error: None,
+        };
+
+        Self::collection()?
+            .replace_one(doc! { "discord_id": discord_id as i64 }, stored)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    /// A decrypted access token for the user, refreshed first when it is about to expire.
+    /// `None` when the user never linked or the link is broken.
+    pub async fn get_valid_token(discord_id: u64) -> Result<Option<String>, Error> {
+        let Some(stored) = Self::collection()?
+            .find_one(doc! { "discord_id": discord_id as i64 })
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        if stored.broken {
+            return Ok(None);
+        }
+
+        if Self::needs_refresh(&stored) {
+            return match Self::refresh(discord_id).await {
+                Ok(access_token) => Ok(access_token),
+                // Refreshing early failed, the current token still works until it expires
+                Err(_) if !Self::is_expired(&stored) => {
+                    Ok(Some(Self::cipher()?.decrypt(&stored.access_token)?))
+                }
+                Err(_) => Ok(None),
+            };
+        }
+
+        Ok(Some(Self::cipher()?.decrypt(&stored.access_token)?))
+    }
+
+    fn needs_refresh(stored: &StoredTokens) -> bool {
+        let refresh_at = stored.expires_at.timestamp_millis() - REFRESH_MARGIN.as_millis() as i64;
+        BsonDateTime::now().timestamp_millis() >= refresh_at
+    }
+
+    fn is_expired(stored: &StoredTokens) -> bool {
+        BsonDateTime::now().timestamp_millis() >= stored.expires_at.timestamp_millis()
+    }
+
+    /// Swaps the refresh token for new tokens and returns the new access token, `None` when
+    /// the link is broken. Only one refresh runs per user, and one that waited for another
+    /// uses its result.
+    ///
+    /// The tokens and the link are marked broken only when Patreon rejects the refresh
+    /// token, other failures are recorded and retried later.
+    async fn refresh(discord_id: u64) -> Result<Option<String>, Error> {
+        let lock = REFRESH_LOCKS
+            .lock()
+            .unwrap()
+            .entry(discord_id)
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+
+        let Some(stored) = Self::collection()?
+            .find_one(doc! { "discord_id": discord_id as i64 })
+            .await?
+        else {
+            return Ok(None);
+        };
+        if stored.broken {
+            return Ok(None);
+        }
+        if !Self::needs_refresh(&stored) {
+            return Ok(Some(Self::cipher()?.decrypt(&stored.access_token)?));
+        }
+
+        let result = async {
+            let refresh_token = Self::cipher()?.decrypt(&stored.refresh_token)?;
+            let tokens = OAUTH.refresh_tokens(&refresh_token).await?;
+            Self::store_tokens(discord_id, &tokens).await?;
+            Ok::<_, Error>(tokens.access_token)
+        }
+        .await;
+
+        match result {
+            Ok(access_token) => Ok(Some(access_token)),
+            Err(e) => {
+                tracing::warn!("Failed to refresh Patreon tokens for {}: {}", discord_id, e);
+                if is_rejected_grant(&e) {
+                    Self::mark_broken(discord_id, &e.to_string()).await?;
+                } else {
+                    Self::record_error(discord_id, &e.to_string()).await?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn record_error(discord_id: u64, reason: &str) -> Result<(), Error> {
+        Self::collection()?
+            .update_one(
+                doc! { "discord_id": discord_id as i64 },
+                doc! { "$set": { "last_error": reason } },
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_broken(discord_id: u64, reason: &str) -> Result<(), Error> {
+        Self::collection()?
+            .update_one(
+                doc! { "discord_id": discord_id as i64 },
+                doc! { "$set": { "broken": true, "last_error": reason } },
+            )
+            .await?;
+        PatreonLinkDatabase::mark_broken(discord_id).await?;
+        Ok(())
+    }
+
+    /// Refreshes every token nearing expiry in the background.
+    pub fn start_refresh_task() {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = Self::refresh_expiring().await {
+                    tracing::error!("Patreon token refresh task error: {:?}", e);
+                }
+                tokio::time::sleep(REFRESH_INTERVAL).await;
+            }
+        });
+    }
+
+    async fn refresh_expiring() -> Result<(), Error> {
+        let refresh_before = BsonDateTime::from_millis(
+            BsonDateTime::now().timestamp_millis() + REFRESH_MARGIN.as_millis() as i64,
+        );
+
+        let mut cursor = Self::collection()?
+            .find(doc! { "broken": false, "expires_at": { "$lte": refresh_before } })
+            .await?;
+
+        while cursor.advance().await? {
+            let stored: StoredTokens = cursor.deserialize_current()?;
+            // Failures are recorded on the tokens, keep going with the rest
+            let _ = Self::refresh(stored.discord_id).await;
+        }
+        Ok(())
+    }
+}
+
+/// Whether Patreon refused the refresh token itself (`invalid_grant` and the like), which
+/// no retry can fix.
+fn is_rejected_grant(error: &Error) -> bool {
+    matches!(
+        error.downcast_ref::<PatreonError>(),
+        Some(PatreonError::PatreonOAuth(status, _))
+            if matches!(status.as_u16(), 400 | 401)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oauth_error(status: u16) -> Error {
+        PatreonError::PatreonOAuth(
+            status.try_into().unwrap(),
+            "invalid_grant".to_string(),
+        )
+        .into()
+    }
+
+    #[test]
+    fn only_a_refused_refresh_token_breaks_the_link() {
+        assert!(is_rejected_grant(&oauth_error(400)));
+        assert!(is_rejected_grant(&oauth_error(401)));
+        assert!(!is_rejected_grant(&oauth_error(500)));
+        assert!(!is_rejected_grant(&oauth_error(429)));
+        assert!(!is_rejected_grant(&PatreonError::Message("timeout".to_string()).into()));
+        assert!(!is_rejected_grant(&anyhow::anyhow!("TOKEN_ENCRYPTION_KEY is not configured")));
+    }
+}
@@ -0,0 +1,296 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use deffy_bot_patreon_services::Event;
+use mongodb::{
+    Collection,
+    bson::{DateTime as BsonDateTime, doc},
+    error::{ErrorKind, WriteFailure},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::database::DatabaseManager;
+use crate::event::manager::{EventManager, EventType};
+
+/// Patreon stops retrying a delivery well within this window. The TTL index is created by
+/// migration `0008_webhook_deliveries_ttl`, changing this needs a new migration.
+pub(crate) const DELIVERY_TTL: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Attempts before a delivery is moved to the dead-letter collection.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// How long a claimed delivery stays hidden from the worker while it is processed.
+const CLAIM_LEASE: Duration = Duration::from_secs(5 * 60);
+
+const IDLE_POLL: Duration = Duration::from_secs(30);
+
+static INBOX_NOTIFY: Notify = Notify::const_new();
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookDelivery {
+    #[serde(rename = "_id")]
+    pub fingerprint: String,
+    pub trigger: String,
+    pub resource_id: String,
+    pub received_at: BsonDateTime,
+}
+
+pub struct WebhookDeliveryDatabase {}
+
+impl WebhookDeliveryDatabase {
+    fn collection() -> Result<Collection<WebhookDelivery>, Error> {
+        Ok(DatabaseManager::require_db()?.collection("webhook_deliveries"))
+    }
+
+    /// Records the delivery and returns `false` if the same fingerprint was already seen.
+    pub async fn record_delivery(
+        fingerprint: &str,
+        trigger: &str,
+        resource_id: &str,
+    ) -> Result<bool, Error> {
+        let collection = Self::collection()?;
+
+        let delivery = WebhookDelivery {
+            fingerprint: fingerprint.to_string(),
+            trigger: trigger.to_string(),
+            resource_id: resource_id.to_string(),
+            received_at: BsonDateTime::now(),
+        };
+
+        match collection.insert_one(delivery).await {
+            Ok(_) => Ok(true),
+            Err(e) => match *e.kind {
+                ErrorKind::Write(WriteFailure::WriteError(ref write)) if write.code == DUPLICATE_KEY => {
+                    Ok(false)
+                }
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// Drops a recorded fingerprint so Patreon's retry of a delivery we failed to store is accepted.
+    pub async fn forget_delivery(fingerprint: &str) -> Result<(), Error> {
+        Self::collection()?.delete_one(doc! { "_id": fingerprint }).await?;
+        Ok(())
+    }
+}
+
+/// A verified webhook delivery waiting to be processed, or one that ran out of attempts.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InboxDelivery {
+    #[serde(rename = "_id")]
+    pub fingerprint: String,
+    pub trigger: String,
+    pub body: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub received_at: BsonDateTime,
+    pub next_attempt_at: BsonDateTime,
+    #[serde(default)]
+    pub dead_lettered_at: Option<BsonDateTime>,
+    /// Handlers that already succeeded, skipped when the delivery is retried.
+    #[serde(default)]
+    pub completed_handlers: Vec<String>,
+}
+
+impl InboxDelivery {
+    pub fn new(fingerprint: String, trigger: String, body: String) -> Self {
+        let now = BsonDateTime::now();
+        Self {
+            fingerprint,
+            trigger,
+            body,
+            attempts: 0,
+            last_error: None,
+            received_at: now,
+            next_attempt_at: now,
+            dead_lettered_at: None,
+            completed_handlers: Vec::new(),
+        }
+    }
+}
+
+pub struct WebhookInboxDatabase {}
+
+impl WebhookInboxDatabase {
+    fn inbox() -> Result<Collection<InboxDelivery>, Error> {
+        Ok(DatabaseManager::require_db()?.collection("webhook_inbox"))
+    }
+
+    fn dead_letter() -> Result<Collection<InboxDelivery>, Error> {
+        Ok(DatabaseManager::require_db()?.collection("webhook_dead_letter"))
+    }
+
+    pub async fn enqueue(delivery: InboxDelivery) -> Result<(), Error> {
+        Self::inbox()?.insert_one(delivery).await?;
+        INBOX_NOTIFY.notify_one();
+        Ok(())
+    }
+
+    pub async fn list_dead_letters(limit: i64) -> Result<Vec<InboxDelivery>, Error> {
+        let mut cursor = Self::dead_letter()?
+            .find(doc! {})
+            .sort(doc! { "dead_lettered_at": -1 })
+            .limit(limit)
+            .await?;
+
+        let mut deliveries = Vec::new();
+        while cursor.advance().await? {
+            deliveries.push(cursor.deserialize_current()?);
+        }
+        Ok(deliveries)
+    }
+
+    /// Moves dead-lettered deliveries back into the inbox with a fresh attempt count,
+    /// all of them when `fingerprint` is `None`. Returns how many were replayed.
+    pub async fn replay_dead_letters(fingerprint: Option<&str>) -> Result<usize, Error> {
+        let filter = match fingerprint {
+            Some(fingerprint) => doc! { "_id": fingerprint },
+            None => doc! {},
+        };
+
+        let mut cursor = Self::dead_letter()?.find(filter).await?;
+        let mut replayed = 0;
+        while cursor.advance().await? {
+            let dead: InboxDelivery = cursor.deserialize_current()?;
+            let delivery = InboxDelivery {
+                last_error: dead.last_error.clone(),
+                completed_handlers: dead.completed_handlers.clone(),
+                ..InboxDelivery::new(dead.fingerprint.clone(), dead.trigger, dead.body)
+            };
+            Self::inbox()?
+                .replace_one(doc! { "_id": &delivery.fingerprint }, &delivery)
+                .upsert(true)
+                .await?;
+            Self::dead_letter()?.delete_one(doc! { "_id": &dead.fingerprint }).await?;
+            replayed += 1;
+        }
+
+        if replayed > 0 {
+            INBOX_NOTIFY.notify_one();
+        }
+        Ok(replayed)
+    }
+
+    /// Drains the inbox in the background until the process exits.
+    pub fn start_worker() {
+        tokio::spawn(async move {
+            loop {
+                match Self::process_next().await {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => tracing::error!("Webhook inbox worker error: {:?}", e),
+                }
+                let _ = tokio::time::timeout(IDLE_POLL, INBOX_NOTIFY.notified()).await;
+            }
+        });
+    }
+
+    /// Claims one due delivery and processes it. Returns `false` when nothing was due.
+    async fn process_next() -> Result<bool, Error> {
+        let now = BsonDateTime::now();
+        let lease = BsonDateTime::from_millis(now.timestamp_millis() + CLAIM_LEASE.as_millis() as i64);
+
+        let claimed = Self::inbox()?
+            .find_one_and_update(
+                doc! { "next_attempt_at": { "$lte": now } },
+                doc! { "$set": { "next_attempt_at": lease }, "$inc": { "attempts": 1 } },
+            )
+            .with_options(
+                FindOneAndUpdateOptions::builder()
+                    .sort(doc! { "received_at": 1 })
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?;
+
+        let Some(mut delivery) = claimed else {
+            return Ok(false);
+        };
+
+        let event = match Event::parse(delivery.body.as_bytes(), &delivery.trigger) {
+            Ok(event) => event,
+            Err(e) => {
+                // Parsing again can't succeed, so it isn't retried
+                DatabaseManager::force_update_patreon_data();
+                Self::move_to_dead_letter(&delivery, e.to_string()).await?;
+                return Ok(true);
+            }
+        };
+
+        match Self::process(event, &mut delivery.completed_handlers).await {
+            Ok(()) => {
+                Self::inbox()?.delete_one(doc! { "_id": &delivery.fingerprint }).await?;
+            }
+            Err(e) if delivery.attempts >= MAX_ATTEMPTS => {
+                Self::move_to_dead_letter(&delivery, e.to_string()).await?;
+            }
+            Err(e) => {
+                let backoff = Duration::from_secs(10 * 2u64.pow(delivery.attempts));
+                let next = BsonDateTime::from_millis(
+                    BsonDateTime::now().timestamp_millis() + backoff.as_millis() as i64,
+                );
+                tracing::warn!(
+                    fingerprint = delivery.fingerprint,
+                    attempts = delivery.attempts,
+                    "Webhook delivery failed, retrying in {:?}: {:?}",
+                    backoff,
+                    e
+                );
+                Self::inbox()?
+                    .update_one(
+                        doc! { "_id": &delivery.fingerprint },
+                        doc! { "$set": {
+                            "next_attempt_at": next,
+                            "last_error": e.to_string(),
+                            "completed_handlers": &delivery.completed_handlers,
+                        } },
+                    )
+                    .await?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn move_to_dead_letter(
+        delivery: &InboxDelivery,
+        error: String,
+    ) -> Result<(), Error> {
+        tracing::error!(
+            fingerprint = delivery.fingerprint,
+            attempts = delivery.attempts,
+            "Moving webhook delivery to dead letter: {}",
+            error
+        );
+        let dead = InboxDelivery {
+            last_error: Some(error),
+            dead_lettered_at: Some(BsonDateTime::now()),
+            ..delivery.clone()
+        };
+        Self::dead_letter()?
+            .replace_one(doc! { "_id": &dead.fingerprint }, &dead)
+            .upsert(true)
+            .await?;
+        Self::inbox()?.delete_one(doc! { "_id": &delivery.fingerprint }).await?;
+        Ok(())
+    }
+
+    /// Emits the delivery to the event handlers not in `completed` and refreshes the
+    /// stored members.
+    pub async fn process(event: Event, completed: &mut Vec<String>) -> Result<(), Error> {
+        let (event_type, data) = EventType::from_webhook(event);
+
+        // `dispatch` waits for the handlers, so they compare against the member stored
+        // before this delivery and not the refreshed one
+        let result = EventManager::dispatch(event_type, data, completed).await;
+
+        DatabaseManager::force_update_patreon_data();
+
+        result
+    }
+}
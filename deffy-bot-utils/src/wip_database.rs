@@ -1,7 +1,7 @@
-use mongodb::{Collection, bson::doc};
+use anyhow::Error;
 use serde::{Deserialize, Serialize};
 
-use crate::database::DatabaseManager;
+use crate::repository::Repositories;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WipEntry {
@@ -13,53 +13,23 @@ pub struct WipEntry {
     pub state: u8, // 1-6
 }
 
+/// Goes through the installed `WipRepository`.
 pub struct WipDatabase {}
 
 impl WipDatabase {
-    pub async fn create_wip(entry: WipEntry) -> Result<(), mongodb::error::Error> {
-        let db = DatabaseManager::get_db();
-        let collection: Collection<WipEntry> = db.collection("wip_data");
-
-        collection.insert_one(entry).await?;
-        Ok(())
+    pub async fn create_wip(entry: WipEntry) -> Result<(), Error> {
+        Repositories::get().wip.create(entry).await
     }
 
-    pub async fn update_wip(entry: WipEntry) -> Result<(), mongodb::error::Error> {
-        let db = DatabaseManager::get_db();
-        let collection: Collection<WipEntry> = db.collection("wip_data");
-
-        let filter = doc! { "message_id": entry.message_id as i64 };
-
-        let update = doc! {
-            "$set": {
-                "state": entry.state as i64,
-            }
-        };
-
-        collection.update_one(filter, update).await?;
-
-        Ok(())
+    pub async fn update_wip(entry: WipEntry) -> Result<(), Error> {
+        Repositories::get().wip.update_state(entry.message_id, entry.state).await
     }
 
-    pub async fn get_wip(title: &str) -> Result<Option<WipEntry>, mongodb::error::Error> {
-        let db = DatabaseManager::get_db();
-        let collection: Collection<WipEntry> = db.collection("wip_data");
-
-        let filter = doc! { "title": title };
-
-        let wip = collection.find_one(filter).await?;
-
-        Ok(wip)
+    pub async fn get_wip(title: &str) -> Result<Option<WipEntry>, Error> {
+        Repositories::get().wip.get(title).await
     }
 
-    pub async fn remove_wip(title: &str) -> Result<(), mongodb::error::Error> {
-        let db = DatabaseManager::get_db();
-        let collection: Collection<WipEntry> = db.collection("wip_data");
-
-        let filter = doc! { "title": title };
-
-        collection.delete_one(filter).await?;
-
-        Ok(())
+    pub async fn remove_wip(title: &str) -> Result<(), Error> {
+        Repositories::get().wip.remove(title).await
     }
 }
@@ -2,7 +2,7 @@ use std::env;
 
 use anyhow::{Error, Ok};
 use deffy_bot_macro::command;
-use deffy_bot_patreon_services::PatreonApi;
+use deffy_bot_patreon_services::{CampaignSelector, PatreonApi};
 use serenity::{
     all::{
         CommandInteraction, Context, CreateCommand, CreateInteractionResponse,
@@ -22,12 +22,14 @@ impl CommandHandler for ClaimCommand {
         let content = format!("Key: ZX",);
 
         // Api Client
-        let api = PatreonApi {
+        let mut api = PatreonApi {
             access_token: env::var("PATREON_ACCESS_TOKEN")
                 .expect("PATREON_ACCESS_TOKEN must be set"),
             ..Default::default()
         };
 
+        api.resolve_campaign(&CampaignSelector::from_env()).await?;
+
         let data = api.all_members().await?;
 
         for mem in data {
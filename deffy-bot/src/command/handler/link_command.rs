@@ -0,0 +1,68 @@
+use std::env;
+
+use anyhow::Error;
+use deffy_bot_macro::command;
+use deffy_bot_patreon_services::OAuthState;
+use deffy_bot_utils::database::DatabaseManager;
+use serenity::{
+    all::{
+        CommandInteraction, Context, CreateActionRow, CreateButton, CreateCommand,
+        CreateInteractionResponse, CreateInteractionResponseMessage,
+    },
+    async_trait,
+};
+
+use crate::command::system::{
+    interaction_reply::InteractionExt,
+    manager::{CommandHandler, CommandInfo},
+};
+
+#[command(cmd = link_patreon, cooldown = 10)]
+pub struct LinkPatreonCommand;
+
+#[async_trait]
+impl CommandHandler for LinkPatreonCommand {
+    async fn execute(&self, ctx: Context, interaction: CommandInteraction) -> Result<(), Error> {
+        let (Ok(base_url), Ok(secret)) = (env::var("PUBLIC_BASE_URL"), env::var("OAUTH_STATE_SECRET"))
+        else {
+            interaction
+                .reply(&ctx, "Patreon account linking is not set up on this bot.", true)
+                .await?;
+            return Ok(());
+        };
+
+        // Links and tokens are only stored in MongoDB
+        if !DatabaseManager::is_initialized() {
+            interaction
+                .reply(&ctx, "Patreon account linking needs the database, it is not available on this bot.", true)
+                .await?;
+            return Ok(());
+        }
+
+        let discord_id = interaction.user.id.get();
+        let issued_at = chrono::Utc::now().timestamp();
+        let url = format!(
+            "{}/patreon/oauth/login?discord_id={}&issued_at={}&sig={}",
+            base_url.trim_end_matches('/'),
+            discord_id,
+            issued_at,
+            OAuthState::sign_login(secret.as_bytes(), discord_id, issued_at)
+        );
+
+        let response = CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content("Log in with Patreon to link it to your Discord account. This link only works for you and expires in 15 minutes.")
+                .components(vec![CreateActionRow::Buttons(vec![
+                    CreateButton::new_link(url).label("Link Patreon"),
+                ])])
+                .ephemeral(true),
+        );
+        interaction.create_response(&ctx.http, response).await?;
+
+        Ok(())
+    }
+
+    fn register(&self) -> CreateCommand {
+        CreateCommand::new(self.name()).description("Link your Patreon account")
+    }
+}
@@ -8,4 +8,6 @@ pub mod modal_command;
 pub mod verify_command;
 pub mod setup_command;
 pub mod moderator_command;
-pub mod wip_command;
\ No newline at end of file
+pub mod wip_command;
+pub mod link_command;
+pub mod sync_command;
\ No newline at end of file
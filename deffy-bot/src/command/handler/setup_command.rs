@@ -1,10 +1,15 @@
+use std::{collections::HashSet, env};
+
 use anyhow::{Error, Ok};
 use deffy_bot_macro::command;
+use deffy_bot_patreon_services::{CampaignSelector, PatreonApi, PatreonWebhook, WebhookTrigger, WebhookUpdate};
 use deffy_bot_utils::database::DiscordServerDatabaseManager;
+use deffy_bot_utils::tier_roles::FREE_TIER;
+use deffy_bot_utils::webhook_database::WebhookInboxDatabase;
 use serenity::{
     all::{
-        CommandDataOptionValue, CommandInteraction, CommandOptionType, Context, CreateCommand,
-        CreateCommandOption, Permissions,
+        ChannelId, Colour, CommandDataOptionValue, CommandInteraction, CommandOptionType,
+        Context, CreateCommand, CreateCommandOption, CreateEmbed, GuildId, Permissions, RoleId,
     },
     async_trait,
 };
@@ -29,14 +34,38 @@ impl CommandHandler for SetupCommand {
 
         match subcommand {
             "role_verify" => {
-                handle_set_role_verify(&interaction).await?;
+                handle_set_role_verify(&ctx, &interaction).await?;
                 tracing::debug!("set the verify role")
             }
             "setlogchannel" => {
-                handle_set_logging_channel(&interaction).await?;
+                handle_set_logging_channel(&ctx, &interaction).await?;
             }
             "set_webhook_channel" => {
-                handle_set_webhook_membercreated_channel(&interaction).await?;
+                handle_set_webhook_membercreated_channel(&ctx, &interaction).await?;
+            }
+            "campaign" => {
+                handle_set_campaign(&interaction).await?;
+            }
+            "tier_role" => {
+                handle_set_tier_role(&ctx, &interaction).await?;
+            }
+            "grace_period" => {
+                handle_set_grace_period(&interaction).await?;
+            }
+            "show" => {
+                let embed = handle_show(&ctx, &interaction).await?;
+                interaction.reply_embed(&ctx, embed, true).await?;
+                return Ok(());
+            }
+            "patreon_webhook" => {
+                let embed = handle_patreon_webhook(&interaction).await?;
+                interaction.reply_embed(&ctx, embed, true).await?;
+                return Ok(());
+            }
+            "dead_letter" => {
+                let embed = handle_dead_letter(&interaction).await?;
+                interaction.reply_embed(&ctx, embed, true).await?;
+                return Ok(());
             }
             _ => {}
         }
@@ -98,7 +127,11 @@ impl CommandHandler for SetupCommand {
                     .add_string_choice("webhook_event_updated", "webhook_event_updated")
                     .required(true)
                     .add_string_choice("webhook_event_deleted", "webhook_event_deleted")
-                    .required(true),
+                    .required(true)
+                    .add_string_choice("webhook_event_pledge_created", "webhook_event_pledge_created")
+                    .add_string_choice("webhook_event_pledge_upgraded", "webhook_event_pledge_upgraded")
+                    .add_string_choice("webhook_event_pledge_downgraded", "webhook_event_pledge_downgraded")
+                    .add_string_choice("webhook_event_pledge_canceled", "webhook_event_pledge_canceled"),
                 )
                 .add_sub_option(
                     CreateCommandOption::new(
@@ -109,23 +142,132 @@ impl CommandHandler for SetupCommand {
                     .required(true),
                 ),
             )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "patreon_webhook",
+                    "manage the patreon webhook of this bot",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::String, "action", "what to do")
+                        .add_string_choice("register", "register")
+                        .add_string_choice("list", "list")
+                        .add_string_choice("unpause", "unpause")
+                        .add_string_choice("delete", "delete")
+                        .required(true),
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "url",
+                        "public base url of the bot, default PUBLIC_BASE_URL",
+                    )
+                    .required(false),
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "webhook_id",
+                        "webhook id for unpause and delete",
+                    )
+                    .required(false),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "dead_letter",
+                    "list or replay webhook deliveries that failed processing",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::String, "action", "what to do")
+                        .add_string_choice("list", "list")
+                        .add_string_choice("replay", "replay")
+                        .required(true),
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "delivery_id",
+                        "delivery to replay, all of them if empty",
+                    )
+                    .required(false),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "campaign",
+                    "only receive webhook events of this campaign",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "campaign_id",
+                        "patreon campaign id, every campaign if empty",
+                    )
+                    .required(false),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "tier_role",
+                    "give a role to patrons of a tier",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "tier",
+                        "patreon tier id, or free for free members",
+                    )
+                    .required(true),
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Role,
+                        "role",
+                        "role of the tier, removes the mapping if empty",
+                    )
+                    .required(false),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "grace_period",
+                    "how long lapsed patrons keep their roles",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::Integer, "hours", "grace period in hours")
+                        .min_int_value(0)
+                        .max_int_value(24 * 90)
+                        .required(true),
+                ),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "show",
+                "show the setup of this server",
+            ))
     }
 }
 
-pub async fn handle_set_role_verify(interaction: &CommandInteraction) -> Result<(), Error> {
+pub async fn handle_set_role_verify(ctx: &Context, interaction: &CommandInteraction) -> Result<(), Error> {
     let Some(CommandDataOptionValue::Role(role_id)) = get_sub_option_value(interaction, "role")
     else {
         return Err(anyhow::anyhow!("No role ID found"));
     };
 
     if let Some(sv_id) = interaction.guild_id {
+        ensure_role(ctx, sv_id, *role_id).await?;
         return DiscordServerDatabaseManager::set_verify_roles(sv_id.get(), role_id.get()).await;
     }
 
     Err(anyhow::anyhow!("Guild ID not found"))
 }
 
-pub async fn handle_set_logging_channel(interaction: &CommandInteraction) -> Result<(), Error> {
+pub async fn handle_set_logging_channel(ctx: &Context, interaction: &CommandInteraction) -> Result<(), Error> {
     let Some(CommandDataOptionValue::Channel(channel_id)) =
         get_sub_option_value(interaction, "logchannel")
     else {
@@ -133,14 +275,164 @@ pub async fn handle_set_logging_channel(interaction: &CommandInteraction) -> Res
     };
 
     if let Some(sv_id) = interaction.guild_id {
+        ensure_channel(ctx, sv_id, *channel_id).await?;
         return DiscordServerDatabaseManager::set_logging_channel(sv_id.get(), channel_id.get())
             .await;
     }
     Err(anyhow::anyhow!("Guild ID not found"))
 }
 
+pub async fn handle_set_campaign(interaction: &CommandInteraction) -> Result<(), Error> {
+    let campaign_id = match get_sub_option_value(interaction, "campaign_id") {
+        Some(CommandDataOptionValue::String(campaign_id)) => Some(campaign_id.trim().to_string()),
+        _ => None,
+    };
+
+    if let Some(sv_id) = interaction.guild_id {
+        return DiscordServerDatabaseManager::set_campaign(sv_id.get(), campaign_id).await;
+    }
+    Err(anyhow::anyhow!("Guild ID not found"))
+}
+
+pub async fn handle_set_tier_role(ctx: &Context, interaction: &CommandInteraction) -> Result<(), Error> {
+    let Some(CommandDataOptionValue::String(tier_id)) = get_sub_option_value(interaction, "tier")
+    else {
+        return Err(anyhow::anyhow!("No tier found"));
+    };
+
+    let role_id = match get_sub_option_value(interaction, "role") {
+        Some(CommandDataOptionValue::Role(role_id)) => Some(role_id.get()),
+        _ => None,
+    };
+
+    let tier_id = tier_id.trim().to_lowercase();
+
+    if let Some(sv_id) = interaction.guild_id {
+        // Removing a mapping works for tiers deleted on Patreon too
+        if let Some(role_id) = role_id {
+            ensure_role(ctx, sv_id, RoleId::new(role_id)).await?;
+            ensure_tier(sv_id.get(), &tier_id).await?;
+        }
+        return DiscordServerDatabaseManager::set_tier_role(sv_id.get(), tier_id, role_id).await;
+    }
+    Err(anyhow::anyhow!("Guild ID not found"))
+}
+
+/// The tier has to belong to the campaign of the server, any campaign of the token when
+/// the server follows every campaign.
+async fn ensure_tier(sv_id: u64, tier_id: &str) -> Result<(), Error> {
+    if tier_id == FREE_TIER {
+        return Ok(());
+    }
+
+    let campaign_id = DiscordServerDatabaseManager::get_settings(sv_id).await?.campaign_id;
+    let api = PatreonApi::new(
+        env::var("PATREON_ACCESS_TOKEN")
+            .map_err(|_| anyhow::anyhow!("PATREON_ACCESS_TOKEN is not set, tiers can't be checked"))?,
+    );
+
+    let tiers: Vec<_> = api
+        .campaigns()
+        .await?
+        .into_iter()
+        .filter(|details| campaign_id.as_deref().is_none_or(|id| id == details.campaign.id))
+        .flat_map(|details| details.tiers)
+        .collect();
+
+    if tiers.iter().any(|tier| tier.id == tier_id) {
+        return Ok(());
+    }
+
+    let known = tiers
+        .iter()
+        .map(|tier| format!("{} ({})", tier.id, tier.attributes.title))
+        .chain([FREE_TIER.to_string()])
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(anyhow::anyhow!("Unknown tier {}, expected one of: {}", tier_id, known))
+}
+
+pub async fn handle_set_grace_period(interaction: &CommandInteraction) -> Result<(), Error> {
+    let Some(CommandDataOptionValue::Integer(hours)) = get_sub_option_value(interaction, "hours")
+    else {
+        return Err(anyhow::anyhow!("No grace period found"));
+    };
+
+    if let Some(sv_id) = interaction.guild_id {
+        return DiscordServerDatabaseManager::set_grace_period(sv_id.get(), (*hours).max(0) as u32)
+            .await;
+    }
+    Err(anyhow::anyhow!("Guild ID not found"))
+}
+
+pub async fn handle_show(ctx: &Context, interaction: &CommandInteraction) -> Result<CreateEmbed, Error> {
+    let Some(guild_id) = interaction.guild_id else {
+        return Err(anyhow::anyhow!("Guild ID not found"));
+    };
+
+    let settings = DiscordServerDatabaseManager::get_settings(guild_id.get()).await?;
+
+    let channels: HashSet<u64> = guild_id.channels(&ctx.http).await?.keys().map(|id| id.get()).collect();
+    let roles: HashSet<u64> = guild_id.roles(&ctx.http).await?.keys().map(|id| id.get()).collect();
+    let issues = settings.validate(&channels, &roles);
+
+    let channel_list = settings
+        .channels()
+        .into_iter()
+        .map(|(name, id)| match id {
+            Some(id) => format!("*{}:* <#{}>", name, id),
+            None => format!("*{}:* -", name),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let role_list = settings
+        .roles()
+        .into_iter()
+        .map(|(name, id)| match id {
+            Some(id) => format!("*{}:* <@&{}>", name, id),
+            None => format!("*{}:* -", name),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let tier_role_list = match settings.tier_roles.is_empty() {
+        true => "-".to_string(),
+        false => settings
+            .tier_roles
+            .iter()
+            .map(|(tier_id, role_id)| format!("*{}:* <@&{}>", tier_id, role_id))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+
+    let mut embed = CreateEmbed::default()
+        .title("🛠️ Server setup")
+        .description(format!(
+            "*campaign:* {}\n*grace period:* {}h",
+            settings.campaign_id.as_deref().unwrap_or("every campaign"),
+            settings.grace_period_hours
+        ))
+        .field("Roles", role_list, false)
+        .field("Tier roles", tier_role_list, false)
+        .field("Channels", channel_list, false)
+        .color(Colour::new(0x00ff04));
+
+    if !issues.is_empty() {
+        let issue_list = issues
+            .iter()
+            .map(|issue| format!("⚠️ {}", issue))
+            .collect::<Vec<_>>()
+            .join("\n");
+        embed = embed.field("Needs attention", issue_list, false).color(Colour::new(0xf5d400));
+    }
+
+    Ok(embed)
+}
+
 // TODO: event subvalue
 pub async fn handle_set_webhook_membercreated_channel(
+    ctx: &Context,
     interaction: &CommandInteraction,
 ) -> Result<(), Error> {
     let event_action = get_sub_option_value(&interaction, "webhook_event");
@@ -153,6 +445,10 @@ pub async fn handle_set_webhook_membercreated_channel(
         return Err(anyhow::anyhow!("No channel ID found"));
     };
 
+    if let Some(guild_id) = guild_id {
+        ensure_channel(ctx, *guild_id, *channel_id).await?;
+    }
+
     Ok(if let Some(guild_id) = guild_id {
         if let Some(CommandDataOptionValue::String(event_action)) = event_action {
             match event_action.as_str() {
@@ -180,6 +476,38 @@ pub async fn handle_set_webhook_membercreated_channel(
                     )
                     .await;
                 }
+                "webhook_event_pledge_created" => {
+                    return DiscordServerDatabaseManager::set_webhook_channel(
+                        guild_id.get(),
+                        channel_id.get(),
+                        deffy_bot_utils::database::DatabaseWebHookEvent::CreatePledge,
+                    )
+                    .await;
+                }
+                "webhook_event_pledge_upgraded" => {
+                    return DiscordServerDatabaseManager::set_webhook_channel(
+                        guild_id.get(),
+                        channel_id.get(),
+                        deffy_bot_utils::database::DatabaseWebHookEvent::UpgradePledge,
+                    )
+                    .await;
+                }
+                "webhook_event_pledge_downgraded" => {
+                    return DiscordServerDatabaseManager::set_webhook_channel(
+                        guild_id.get(),
+                        channel_id.get(),
+                        deffy_bot_utils::database::DatabaseWebHookEvent::DowngradePledge,
+                    )
+                    .await;
+                }
+                "webhook_event_pledge_canceled" => {
+                    return DiscordServerDatabaseManager::set_webhook_channel(
+                        guild_id.get(),
+                        channel_id.get(),
+                        deffy_bot_utils::database::DatabaseWebHookEvent::DeletePledge,
+                    )
+                    .await;
+                }
                 _ => return Err(anyhow::anyhow!("Guild ID not found")),
             }
         } else {
@@ -188,6 +516,181 @@ pub async fn handle_set_webhook_membercreated_channel(
     })
 }
 
+pub async fn handle_patreon_webhook(interaction: &CommandInteraction) -> Result<CreateEmbed, Error> {
+    let Some(CommandDataOptionValue::String(action)) = get_sub_option_value(interaction, "action")
+    else {
+        return Err(anyhow::anyhow!("No action found"));
+    };
+
+    let mut api = PatreonApi {
+        access_token: env::var("PATREON_ACCESS_TOKEN")
+            .expect("PATREON_ACCESS_TOKEN must be set"),
+        ..Default::default()
+    };
+    api.resolve_campaign(&CampaignSelector::from_env()).await?;
+
+    let webhook_id = match get_sub_option_value(interaction, "webhook_id") {
+        Some(CommandDataOptionValue::String(id)) => Some(id.as_str()),
+        _ => None,
+    };
+
+    match action.as_str() {
+        "register" => {
+            let base_url = match get_sub_option_value(interaction, "url") {
+                Some(CommandDataOptionValue::String(url)) => url.clone(),
+                _ => env::var("PUBLIC_BASE_URL")
+                    .map_err(|_| anyhow::anyhow!("No url given and PUBLIC_BASE_URL is not set"))?,
+            };
+            let uri = format!("{}/patreon/webhook", base_url.trim_end_matches('/'));
+            let triggers = WebhookTrigger::member_triggers();
+
+            let existing = api
+                .webhooks()
+                .await?
+                .into_iter()
+                .find(|webhook| webhook.attributes.uri == uri);
+
+            let webhook = match existing {
+                Some(webhook) => {
+                    let update = WebhookUpdate {
+                        triggers: Some(triggers),
+                        paused: Some(false),
+                        ..Default::default()
+                    };
+                    api.update_webhook(&webhook.id, &update).await?
+                }
+                None => api.create_webhook(&uri, &triggers).await?,
+            };
+
+            Ok(webhook_embed("✅ Patreon webhook registered", &[webhook])
+                .field(
+                    "Secret",
+                    "Set the secret above as `PATREON_WEBHOOK_SECRET` and restart the bot.",
+                    false,
+                ))
+        }
+        "list" => {
+            let webhooks = api.webhooks().await?;
+            Ok(webhook_embed("📋 Patreon webhooks", &webhooks))
+        }
+        "unpause" => {
+            let id = webhook_id.ok_or_else(|| anyhow::anyhow!("webhook_id is required"))?;
+            let webhook = api.unpause_webhook(id).await?;
+            Ok(webhook_embed("▶️ Patreon webhook unpaused", &[webhook]))
+        }
+        "delete" => {
+            let id = webhook_id.ok_or_else(|| anyhow::anyhow!("webhook_id is required"))?;
+            api.delete_webhook(id).await?;
+            Ok(CreateEmbed::default()
+                .title("🗑️ Patreon webhook deleted")
+                .description(format!("*{}*", id))
+                .color(Colour::new(0xff0026)))
+        }
+        _ => Err(anyhow::anyhow!("Invalid action")),
+    }
+}
+
+pub async fn handle_dead_letter(interaction: &CommandInteraction) -> Result<CreateEmbed, Error> {
+    let Some(CommandDataOptionValue::String(action)) = get_sub_option_value(interaction, "action")
+    else {
+        return Err(anyhow::anyhow!("No action found"));
+    };
+
+    let delivery_id = match get_sub_option_value(interaction, "delivery_id") {
+        Some(CommandDataOptionValue::String(id)) => Some(id.as_str()),
+        _ => None,
+    };
+
+    match action.as_str() {
+        "list" => {
+            let deliveries = WebhookInboxDatabase::list_dead_letters(10).await?;
+
+            let mut embed = CreateEmbed::default()
+                .title("📭 Dead-lettered webhook deliveries")
+                .color(Colour::new(0xff0026));
+
+            if deliveries.is_empty() {
+                embed = embed.description("*Nothing failed*");
+            }
+
+            for delivery in deliveries {
+                // Embed field values are capped at 1024 characters
+                let error: String = delivery
+                    .last_error
+                    .as_deref()
+                    .unwrap_or("-")
+                    .chars()
+                    .take(500)
+                    .collect();
+                embed = embed.field(
+                    delivery.trigger,
+                    format!(
+                        "*id:* `{}`\n*attempts:* {}\n*error:* {}",
+                        delivery.fingerprint,
+                        delivery.attempts, error,
+                    ),
+                    false,
+                );
+            }
+
+            Ok(embed)
+        }
+        "replay" => {
+            let replayed = WebhookInboxDatabase::replay_dead_letters(delivery_id).await?;
+            Ok(CreateEmbed::default()
+                .title("🔁 Webhook deliveries replayed")
+                .description(format!("*{} moved back to the inbox*", replayed))
+                .color(Colour::new(0x00ff04)))
+        }
+        _ => Err(anyhow::anyhow!("Invalid action")),
+    }
+}
+
+/// The picked channel can have been deleted since the command was opened.
+async fn ensure_channel(ctx: &Context, guild_id: GuildId, channel_id: ChannelId) -> Result<(), Error> {
+    if !guild_id.channels(&ctx.http).await?.contains_key(&channel_id) {
+        return Err(anyhow::anyhow!("Channel {} doesn't exist in this server", channel_id));
+    }
+    Ok(())
+}
+
+async fn ensure_role(ctx: &Context, guild_id: GuildId, role_id: RoleId) -> Result<(), Error> {
+    if !guild_id.roles(&ctx.http).await?.contains_key(&role_id) {
+        return Err(anyhow::anyhow!("Role {} doesn't exist in this server", role_id));
+    }
+    Ok(())
+}
+
+fn webhook_embed(title: &str, webhooks: &[PatreonWebhook]) -> CreateEmbed {
+    let mut embed = CreateEmbed::default()
+        .title(title)
+        .color(Colour::new(0xf5b400));
+
+    if webhooks.is_empty() {
+        embed = embed.description("*No webhook registered*");
+    }
+
+    for webhook in webhooks {
+        let attr = &webhook.attributes;
+        let triggers = attr
+            .triggers
+            .iter()
+            .map(|t| t.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        embed = embed.field(
+            format!("#{}", webhook.id),
+            format!(
+                "*uri:* {}\n*triggers:* {}\n*paused:* {}\n*failed:* {}\n*secret:* ||{}||",
+                attr.uri, triggers, attr.paused, attr.num_consecutive_times_failed, attr.secret
+            ),
+            false,
+        );
+    }
+
+    embed
+}
+
 pub fn get_sub_option_value<'a>(
     interaction: &'a CommandInteraction,
     option_name: &str,
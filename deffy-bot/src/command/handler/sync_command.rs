@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::Utc;
+use deffy_bot_macro::command;
+use deffy_bot_utils::database::DatabaseManager;
+use deffy_bot_utils::scheduler::{Scheduler, SchedulerConfig, SyncSchedule};
+use deffy_bot_utils::sync::{SyncProgress, SyncRunDatabase, SyncTrigger};
+use serenity::{
+    all::{
+        Colour, CommandDataOptionValue, CommandInteraction, CommandOptionType, Context,
+        CreateCommand, CreateCommandOption, CreateEmbed, CreateInteractionResponse,
+        CreateInteractionResponseMessage, EditInteractionResponse, Permissions,
+    },
+    async_trait,
+};
+use tokio::time::{Instant, timeout};
+
+use crate::command::{
+    handler::setup_command::get_sub_option_value,
+    system::{
+        interaction_reply::InteractionExt,
+        manager::{CommandHandler, CommandInfo},
+    },
+};
+
+/// Give up waiting for the run after this long; it keeps going in the background.
+const WAIT_LIMIT: Duration = Duration::from_secs(10 * 60);
+
+/// Discord rate limits edits, so progress is shown at most this often.
+const EDIT_INTERVAL: Duration = Duration::from_secs(2);
+
+#[command(cmd = sync, cooldown = 30)]
+pub struct SyncCommand;
+
+#[async_trait]
+impl CommandHandler for SyncCommand {
+    async fn execute(&self, ctx: Context, interaction: CommandInteraction) -> Result<(), Error> {
+        let subcommand = interaction
+            .data
+            .options
+            .first()
+            .map(|opt| opt.name.as_str())
+            .ok_or_else(|| anyhow::anyhow!("No subcommand provided"))?;
+
+        match subcommand {
+            "run" => handle_sync_run(&ctx, &interaction).await,
+            "history" => {
+                let count = match get_sub_option_value(&interaction, "count") {
+                    Some(CommandDataOptionValue::Integer(count)) => (*count).clamp(1, 10),
+                    _ => 5,
+                };
+                let embed = history_embed(count).await?;
+                interaction.reply_embed(&ctx, embed, true).await?;
+                Ok(())
+            }
+            "schedule" => handle_sync_schedule(&ctx, &interaction).await,
+            _ => Err(anyhow::anyhow!("Invalid subcommand")),
+        }
+    }
+
+    fn register(&self) -> CreateCommand {
+        CreateCommand::new(self.name())
+            .description("Sync Patreon members")
+            .default_member_permissions(Permissions::ADMINISTRATOR)
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "run",
+                "start a sync now and follow its progress",
+            ))
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "history",
+                    "show the last sync runs",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::Integer, "count", "how many runs, up to 10")
+                        .min_int_value(1)
+                        .max_int_value(10)
+                        .required(false),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "schedule",
+                    "show or change when the sync runs",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "every",
+                        "an interval like 6h or 1d, or a cron expression in UTC",
+                    )
+                    .required(false),
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::Integer, "jitter", "random delay added to each run, in seconds")
+                        .min_int_value(0)
+                        .max_int_value(3600)
+                        .required(false),
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Integer,
+                        "debounce",
+                        "webhook syncs within this many seconds run once",
+                    )
+                    .min_int_value(0)
+                    .max_int_value(600)
+                    .required(false),
+                ),
+            )
+    }
+}
+
+async fn handle_sync_schedule(ctx: &Context, interaction: &CommandInteraction) -> Result<(), Error> {
+    let mut config = Scheduler::current();
+    let mut changed = false;
+
+    if let Some(CommandDataOptionValue::String(every)) = get_sub_option_value(interaction, "every") {
+        config.schedule = match SyncSchedule::parse(every) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                interaction.reply(ctx, format!("❌ {}", e), true).await?;
+                return Ok(());
+            }
+        };
+        changed = true;
+    }
+    if let Some(CommandDataOptionValue::Integer(jitter)) = get_sub_option_value(interaction, "jitter") {
+        config.jitter_seconds = (*jitter).max(0) as u64;
+        changed = true;
+    }
+    if let Some(CommandDataOptionValue::Integer(debounce)) = get_sub_option_value(interaction, "debounce") {
+        config.debounce_seconds = (*debounce).max(0) as u64;
+        changed = true;
+    }
+
+    if changed {
+        Scheduler::update(config.clone()).await?;
+    }
+
+    let title = if changed { "✅ Sync schedule updated" } else { "🗓️ Sync schedule" };
+    interaction.reply_embed(ctx, schedule_embed(title, &config), true).await?;
+    Ok(())
+}
+
+fn schedule_embed(title: &str, config: &SchedulerConfig) -> CreateEmbed {
+    let next = Utc::now().timestamp() + config.schedule.next_delay().as_secs() as i64;
+    CreateEmbed::default()
+        .title(title)
+        .color(Colour::new(0xf5b400))
+        .description(format!(
+            "*runs:* {}\n*jitter:* up to {}s\n*debounce:* {}s\n*next run:* <t:{}:R>",
+            config.schedule, config.jitter_seconds, config.debounce_seconds, next
+        ))
+}
+
+async fn handle_sync_run(ctx: &Context, interaction: &CommandInteraction) -> Result<(), Error> {
+    let mut progress = SyncProgress::subscribe();
+    let requested_at = Utc::now();
+
+    if !DatabaseManager::request_sync(SyncTrigger::Manual) {
+        interaction
+            .reply(ctx, "Patreon sync is not running on this bot.", true)
+            .await?;
+        return Ok(());
+    }
+
+    interaction
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Defer(CreateInteractionResponseMessage::new().ephemeral(true)),
+        )
+        .await?;
+
+    let deadline = Instant::now() + WAIT_LIMIT;
+    let mut last_edit: Option<Instant> = None;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if timeout(remaining, progress.changed()).await.is_err() {
+            interaction
+                .edit_response(
+                    &ctx.http,
+                    EditInteractionResponse::new()
+                        .content("⏳ Sync is still running, check `/sync history` later."),
+                )
+                .await?;
+            return Ok(());
+        }
+
+        let current = progress.borrow_and_update().clone();
+        // Skip whatever run was already going before this request
+        if current.started_at < requested_at {
+            continue;
+        }
+
+        let throttled = last_edit.is_some_and(|at| at.elapsed() < EDIT_INTERVAL);
+        if throttled && !current.finished {
+            continue;
+        }
+
+        interaction
+            .edit_response(&ctx.http, EditInteractionResponse::new().content(progress_text(&current)))
+            .await?;
+        last_edit = Some(Instant::now());
+
+        if current.finished {
+            return Ok(());
+        }
+    }
+}
+
+fn progress_text(progress: &SyncProgress) -> String {
+    let state = match (&progress.error, progress.finished) {
+        (Some(error), _) => format!("❌ Sync failed: {}", error),
+        (None, true) => "✅ Sync finished".to_string(),
+        (None, false) => "🔄 Syncing…".to_string(),
+    };
+    format!(
+        "{}\n*pages:* {}\n*members:* {}\n*changes:* {}",
+        state, progress.pages, progress.members, progress.changes
+    )
+}
+
+async fn history_embed(count: i64) -> Result<CreateEmbed, Error> {
+    let runs = SyncRunDatabase::recent_runs(count).await?;
+
+    let mut embed = CreateEmbed::default()
+        .title("📜 Patreon sync runs")
+        .color(Colour::new(0xf5b400));
+
+    if runs.is_empty() {
+        embed = embed.description("*No sync has run yet*");
+    }
+
+    for run in runs {
+        let started = run.started_at.timestamp_millis() / 1000;
+        let took = (run.ended_at.timestamp_millis() - run.started_at.timestamp_millis()) as f64 / 1000.0;
+        let diff = &run.diff;
+        // Embed field values are capped at 1024 characters
+        let error: String = run.error.as_deref().unwrap_or("-").chars().take(300).collect();
+        embed = embed.field(
+            format!("{} · <t:{}:R>", run.trigger.as_str(), started),
+            format!(
+                "*took:* {:.1}s\n*pages:* {} *members:* {}\n*joined:* {} *left:* {} *status:* {} *tier:* {} *declined:* {}\n*error:* {}",
+                took,
+                run.pages,
+                run.members,
+                diff.joined,
+                diff.left,
+                diff.status_changed,
+                diff.tier_changed,
+                diff.charge_declined,
+                error,
+            ),
+            false,
+        );
+    }
+
+    Ok(embed)
+}
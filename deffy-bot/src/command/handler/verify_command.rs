@@ -1,14 +1,25 @@
 use anyhow::Error;
 use deffy_bot_macro::command;
-use deffy_bot_utils::builder_utils::ModalBuilder;
+use deffy_bot_utils::{
+    builder_utils::ModalBuilder,
+    database::{DatabaseManager, DiscordServerDatabaseManager},
+    member_binding::{MemberBinding, MemberBindingDatabase},
+};
 use serenity::{
     all::{
-        CommandInteraction, Context, CreateCommand,
+        Colour, CommandDataOptionValue, CommandInteraction, CommandOptionType, Context,
+        CreateCommand, CreateCommandOption, CreateEmbed, GuildId, Http, RoleId, UserId,
     },
     async_trait,
 };
 
-use crate::command::system::manager::{CommandHandler, CommandInfo};
+use crate::command::{
+    handler::setup_command::get_sub_option_value,
+    system::{
+        interaction_reply::InteractionExt,
+        manager::{CommandHandler, CommandInfo},
+    },
+};
 
 #[command(cmd = verify, cooldown = 10)]
 pub struct VerifyCommand;
@@ -16,11 +27,30 @@ pub struct VerifyCommand;
 #[async_trait]
 impl CommandHandler for VerifyCommand {
     async fn execute(&self, ctx: Context, interaction: CommandInteraction) -> Result<(), Error> {
-        let modal = ModalBuilder::new("verify_patreon", "Verify your email")
-            .add_text_input("email", "Email", serenity::all::InputTextStyle::Paragraph)
-            .build();
+        let subcommand = interaction
+            .data
+            .options
+            .first()
+            .map(|opt| opt.name.as_str())
+            .ok_or_else(|| anyhow::anyhow!("No subcommand provided"))?;
 
-        interaction.create_response(ctx.http, modal).await?;
+        match subcommand {
+            "unlink" => {
+                let embed = handle_unlink(&ctx, &interaction).await?;
+                interaction.reply_embed(&ctx, embed, true).await?;
+            }
+            "lookup" => {
+                let embed = handle_lookup(&interaction).await?;
+                interaction.reply_embed(&ctx, embed, true).await?;
+            }
+            _ => {
+                let modal = ModalBuilder::new("verify_patreon", "Verify your email")
+                    .add_text_input("email", "Email", serenity::all::InputTextStyle::Paragraph)
+                    .build();
+
+                interaction.create_response(ctx.http, modal).await?;
+            }
+        }
 
         Ok(())
     }
@@ -29,5 +59,157 @@ impl CommandHandler for VerifyCommand {
         CreateCommand::new(self.name())
             .description("Verify your email")
             .default_member_permissions(serenity::all::Permissions::ADMINISTRATOR)
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "email",
+                "verify your email",
+            ))
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "unlink",
+                    "free the patreon memberships bound to a user",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::User, "user", "bound user")
+                        .required(true),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "lookup",
+                    "show who a patreon membership is bound to",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::User, "user", "bound user")
+                        .required(false),
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::String, "email", "patreon email")
+                        .required(false),
+                ),
+            )
+    }
+}
+
+async fn handle_unlink(ctx: &Context, interaction: &CommandInteraction) -> Result<CreateEmbed, Error> {
+    let Some(CommandDataOptionValue::User(user_id)) = get_sub_option_value(interaction, "user")
+    else {
+        return Err(anyhow::anyhow!("No user found"));
+    };
+
+    let removed = MemberBindingDatabase::unlink(user_id.get()).await?;
+
+    tracing::info!(
+        "{} unlinked {} membership(s) from {}",
+        interaction.user.name,
+        removed.len(),
+        user_id
+    );
+
+    let mut embed = bindings_embed("🔓 Unlinked", &removed);
+    if !removed.is_empty() {
+        let revoked = revoke_patron_roles(&ctx.http, *user_id).await?;
+        embed = embed.field("Roles removed", revoked.join("\n"), false);
+    }
+
+    Ok(embed)
+}
+
+/// Nothing keeps the roles of an unlinked account in line anymore, so they go with the
+/// binding in every configured server. Returns one line per server that had some.
+async fn revoke_patron_roles(http: &Http, user_id: UserId) -> Result<Vec<String>, Error> {
+    let mut lines = Vec::new();
+
+    for settings in DiscordServerDatabaseManager::get_campaign_servers(None).await? {
+        let guild_id = GuildId::new(settings.server_id);
+        let Ok(discord_member) = http.get_member(guild_id, user_id).await else {
+            // Not in this server
+            continue;
+        };
+
+        let patron_roles = settings.patron_roles();
+        let held: Vec<RoleId> = discord_member
+            .roles
+            .iter()
+            .filter(|role_id| patron_roles.contains(&role_id.get()))
+            .copied()
+            .collect();
+        if held.is_empty() {
+            continue;
+        }
+
+        let mut removed = Vec::new();
+        for role_id in held {
+            match http
+                .remove_member_role(guild_id, user_id, role_id, Some("Patreon membership unlinked"))
+                .await
+            {
+                Ok(()) => removed.push(format!("<@&{}>", role_id)),
+                Err(e) => {
+                    tracing::error!("Failed to remove role {} of {} in {}: {}", role_id, user_id, guild_id, e);
+                    removed.push(format!("⚠️ <@&{}> *still held*", role_id));
+                }
+            }
+        }
+        lines.push(format!("*{}:* {}", guild_id, removed.join(" ")));
+    }
+
+    if lines.is_empty() {
+        lines.push("*None held*".to_string());
     }
-}
\ No newline at end of file
+    Ok(lines)
+}
+
+async fn handle_lookup(interaction: &CommandInteraction) -> Result<CreateEmbed, Error> {
+    let bindings = match (
+        get_sub_option_value(interaction, "user"),
+        get_sub_option_value(interaction, "email"),
+    ) {
+        (Some(CommandDataOptionValue::User(user_id)), _) => {
+            MemberBindingDatabase::get_by_discord_id(user_id.get()).await?
+        }
+        (_, Some(CommandDataOptionValue::String(email))) => {
+            let member_id = DatabaseManager::get_patreon_member_by_email(email.trim())
+                .await?
+                .and_then(|patron| patron.patreon_member_id);
+
+            match member_id {
+                Some(member_id) => MemberBindingDatabase::get_by_member_id(&member_id)
+                    .await?
+                    .into_iter()
+                    .collect(),
+                None => Vec::new(),
+            }
+        }
+        _ => return Err(anyhow::anyhow!("Give a user or an email to look up")),
+    };
+
+    Ok(bindings_embed("🔎 Bindings", &bindings))
+}
+
+fn bindings_embed(title: &str, bindings: &[MemberBinding]) -> CreateEmbed {
+    let description = if bindings.is_empty() {
+        "*No membership bound*".to_string()
+    } else {
+        bindings
+            .iter()
+            .map(|binding| {
+                format!(
+                    "<@{}> *member* `{}`\n*{}* - <t:{}:f>",
+                    binding.discord_id,
+                    binding.patreon_member_id,
+                    binding.email,
+                    binding.bound_at.timestamp_millis() / 1000
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    };
+
+    CreateEmbed::default()
+        .title(title)
+        .description(description)
+        .color(Colour::new(0x00ff04))
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    sync::Arc,
+};
+
+use deffy_bot_localization::tr;
+use deffy_bot_utils::database::DiscordServerDatabaseManager;
+use deffy_bot_utils::grace_period::{GracePeriod, PendingRevocation, SWEEP_INTERVAL};
+use deffy_bot_utils::guild_settings::GuildSettings;
+use serenity::all::{
+    ChannelId, Colour, CreateEmbed, CreateEmbedFooter, CreateMessage, GuildId, Http, RoleId,
+    Timestamp, UserId,
+};
+
+/// Checks the pending revocations every `SWEEP_INTERVAL` for the whole life of the bot.
+pub fn spawn_revocation_sweeper(http: Arc<Http>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sweep(&http).await {
+                tracing::error!("Failed to sweep pending revocations: {}", e);
+            }
+        }
+    });
+}
+
+/// Revokes the roles of every lapsed member whose grace period ended in a server following
+/// their campaign. A revocation is forgotten once no server is left to handle it.
+async fn sweep(http: &Http) -> Result<(), anyhow::Error> {
+    let pending = GracePeriod::pending().await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let mut servers_of: HashMap<Option<String>, Vec<GuildSettings>> = HashMap::new();
+
+    for revocation in pending {
+        let servers = match servers_of.entry(revocation.campaign_id.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let servers =
+                    DiscordServerDatabaseManager::get_campaign_servers(revocation.campaign_id.as_deref()).await?;
+                entry.insert(servers)
+            }
+        };
+        let mut waiting = false;
+
+        for server in servers.iter() {
+            if revocation.revoked_in.contains(&server.server_id) {
+                continue;
+            }
+            if !revocation.is_due(server) {
+                waiting = true;
+                continue;
+            }
+
+            match revoke_roles(http, server, &revocation).await {
+                Ok(verify_role_revoked) => {
+                    GracePeriod::mark_revoked(
+                        &revocation.patreon_member_id,
+                        server.server_id,
+                        verify_role_revoked,
+                    )
+                    .await?
+                }
+                Err(e) => {
+                    // Tried again on the next sweep
+                    waiting = true;
+                    tracing::error!(
+                        "Failed to revoke roles of {} in server {}: {}",
+                        revocation.discord_id,
+                        server.server_id,
+                        e
+                    );
+                }
+            }
+        }
+
+        if !waiting {
+            GracePeriod::finish(&revocation.patreon_member_id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns whether the verify role was among the revoked roles.
+async fn revoke_roles(
+    http: &Http,
+    settings: &GuildSettings,
+    revocation: &PendingRevocation,
+) -> Result<bool, anyhow::Error> {
+    let guild_id = GuildId::new(settings.server_id);
+    let user_id = UserId::new(revocation.discord_id);
+
+    let Ok(discord_member) = http.get_member(guild_id, user_id).await else {
+        // Not in this server
+        return Ok(false);
+    };
+
+    let patron_roles = settings.patron_roles();
+
+    let revoked: Vec<u64> = discord_member
+        .roles
+        .iter()
+        .map(|role_id| role_id.get())
+        .filter(|role_id| patron_roles.contains(role_id))
+        .collect();
+    if revoked.is_empty() {
+        return Ok(false);
+    }
+
+    for role_id in &revoked {
+        http.remove_member_role(
+            guild_id,
+            user_id,
+            RoleId::new(*role_id),
+            Some("Patreon grace period ended"),
+        )
+        .await?;
+    }
+
+    notify_member(http, guild_id, user_id, revocation).await;
+
+    if let Some(channel_id) = settings.log_channel_id {
+        let changes = revoked
+            .iter()
+            .map(|role_id| format!("➖ <@&{}>", role_id))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let embed = CreateEmbed::default()
+            .title("⌛ Roles Revoked")
+            .description(format!(
+                "*User <@{}>*\n*reason:{:?}*\n{}",
+                user_id, revocation.reason, changes
+            ))
+            .color(Colour::new(0xff0026))
+            .timestamp(Timestamp::now())
+            .footer(CreateEmbedFooter::new("Grace period ended"));
+
+        ChannelId::new(channel_id)
+            .send_message(http, CreateMessage::new().embed(embed))
+            .await?;
+    }
+
+    Ok(settings
+        .verify_role_id
+        .is_some_and(|role_id| revoked.contains(&role_id)))
+}
+
+/// Members with closed DMs still lose their roles.
+async fn notify_member(
+    http: &Http,
+    guild_id: GuildId,
+    user_id: UserId,
+    revocation: &PendingRevocation,
+) {
+    let locale = revocation.locale.as_deref().unwrap_or("en-US");
+    let server_name = match guild_id.to_partial_guild(http).await {
+        Ok(guild) => guild.name,
+        Err(_) => guild_id.to_string(),
+    };
+
+    let content = format!(
+        "**{}**\n{}",
+        server_name,
+        tr!(locale, revocation.reason.message_key())
+    );
+
+    let sent = match user_id.create_dm_channel(http).await {
+        Ok(channel) => channel.send_message(http, CreateMessage::new().content(content)).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = sent {
+        tracing::warn!("Failed to DM {} about revoked roles: {}", user_id, e);
+    }
+}
@@ -1 +1,38 @@
-pub mod webhook_event;
\ No newline at end of file
+pub mod webhook_event;
+pub mod pledge_event;
+pub mod grace_period;
+pub mod role_event;
+
+use deffy_bot_utils::database::{DatabaseWebHookEvent, DiscordServerDatabaseManager};
+use deffy_bot_utils::event::manager::EventTypeData;
+use serenity::all::{ChannelId, CreateEmbed, CreateMessage};
+
+use crate::event::start_event::BOT_HTTP;
+
+/// Sends `embed` to the channel set for `event` in every server following the
+/// campaign of `data`. A server failing doesn't stop the others.
+pub async fn broadcast(
+    data: &EventTypeData,
+    event: DatabaseWebHookEvent,
+    embed: CreateEmbed,
+) -> Result<(), anyhow::Error> {
+    let http = BOT_HTTP.get().expect("BOT_HTTP not initialized");
+
+    let campaign_id = data.campaign_id();
+    let servers = DiscordServerDatabaseManager::get_campaign_servers(campaign_id.as_deref()).await?;
+
+    for server in servers {
+        let Some(channel_id) = server.webhook_channels.get(event) else {
+            continue;
+        };
+
+        if let Err(e) = ChannelId::new(channel_id)
+            .send_message(&http, CreateMessage::new().embed(embed.clone()))
+            .await
+        {
+            tracing::error!("Failed to send webhook event to server {}: {}", server.server_id, e);
+        }
+    }
+
+    Ok(())
+}
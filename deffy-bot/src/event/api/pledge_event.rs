@@ -0,0 +1,157 @@
+use deffy_bot_macro::event_handle;
+use deffy_bot_utils::database::{DatabaseManager, DatabaseWebHookEvent, PatreonUserData};
+use deffy_bot_utils::event::manager::EventType::{
+    PatreonWebhookMemberPledgeCreated, PatreonWebhookMemberPledgeDeleted,
+    PatreonWebhookMemberPledgeUpdated, PatreonWebhookPledgeCreated, PatreonWebhookPledgeDeleted,
+    PatreonWebhookPledgeUpdated,
+};
+use deffy_bot_utils::event::manager::{EventInfo, EventTypeData};
+use serenity::all::{Colour, CreateEmbed, CreateEmbedFooter, Timestamp};
+
+use crate::event::api::broadcast;
+
+enum PledgeAction {
+    Created,
+    Upgraded,
+    Downgraded,
+    /// Same amount as before, or no amount stored to compare with.
+    Updated,
+    Canceled,
+}
+
+struct PledgeInfo {
+    name: String,
+    amount_cents: i64,
+    previous: Option<PatreonUserData>,
+}
+
+#[event_handle(e = PatreonWebhookPledgeCreated)]
+pub async fn handle_patreon_webhook_pledge_created(data: EventTypeData) -> Result<(), anyhow::Error> {
+    send_pledge_embed(data, Some(PledgeAction::Created)).await
+}
+
+#[event_handle(e = PatreonWebhookPledgeUpdated)]
+pub async fn handle_patreon_webhook_pledge_updated(data: EventTypeData) -> Result<(), anyhow::Error> {
+    send_pledge_embed(data, None).await
+}
+
+#[event_handle(e = PatreonWebhookPledgeDeleted)]
+pub async fn handle_patreon_webhook_pledge_deleted(data: EventTypeData) -> Result<(), anyhow::Error> {
+    send_pledge_embed(data, Some(PledgeAction::Canceled)).await
+}
+
+#[event_handle(e = PatreonWebhookMemberPledgeCreated)]
+pub async fn handle_patreon_webhook_member_pledge_created(data: EventTypeData) -> Result<(), anyhow::Error> {
+    send_pledge_embed(data, Some(PledgeAction::Created)).await
+}
+
+#[event_handle(e = PatreonWebhookMemberPledgeUpdated)]
+pub async fn handle_patreon_webhook_member_pledge_updated(data: EventTypeData) -> Result<(), anyhow::Error> {
+    send_pledge_embed(data, None).await
+}
+
+#[event_handle(e = PatreonWebhookMemberPledgeDeleted)]
+pub async fn handle_patreon_webhook_member_pledge_deleted(data: EventTypeData) -> Result<(), anyhow::Error> {
+    send_pledge_embed(data, Some(PledgeAction::Canceled)).await
+}
+
+/// `action` is `None` for updates, which are sorted into upgrade, downgrade or plain
+/// update by comparing with the amount stored at the last sync.
+async fn send_pledge_embed(
+    data: EventTypeData,
+    action: Option<PledgeAction>,
+) -> Result<(), anyhow::Error> {
+    let info = pledge_info(&data).await?;
+
+    let previous_cents = info
+        .previous
+        .as_ref()
+        .map(|previous| previous.currently_entitled_amount_cents);
+
+    let action = action.unwrap_or(match previous_cents {
+        Some(previous) if info.amount_cents < previous => PledgeAction::Downgraded,
+        Some(previous) if info.amount_cents > previous => PledgeAction::Upgraded,
+        _ => PledgeAction::Updated,
+    });
+
+    let (event, title, colour, footer) = match action {
+        PledgeAction::Created => (
+            DatabaseWebHookEvent::CreatePledge,
+            "💸 NEW PLEDGE",
+            0x53d0b1,
+            "Pledge date",
+        ),
+        PledgeAction::Upgraded => (
+            DatabaseWebHookEvent::UpgradePledge,
+            "⬆️ PLEDGE UPGRADED",
+            0x00ff04,
+            "Upgrade time",
+        ),
+        PledgeAction::Downgraded => (
+            DatabaseWebHookEvent::DowngradePledge,
+            "⬇️ PLEDGE DOWNGRADED",
+            0xf5d400,
+            "Downgrade time",
+        ),
+        PledgeAction::Updated => (
+            DatabaseWebHookEvent::UpdateMember,
+            "🔄 PLEDGE UPDATED",
+            0x53d0b1,
+            "Update time",
+        ),
+        PledgeAction::Canceled => (
+            DatabaseWebHookEvent::DeletePledge,
+            "❌ PLEDGE CANCELED",
+            0xff0026,
+            "Cancel time",
+        ),
+    };
+
+    let embed = CreateEmbed::default()
+        .title(title)
+        .description(format!(
+            "*{}*\n*amount:{}*\n*previous amount:{}*",
+            info.name,
+            format_cents(info.amount_cents),
+            previous_cents.map(format_cents).unwrap_or_else(|| "-".to_string()),
+        ))
+        .color(Colour::new(colour))
+        .timestamp(Timestamp::now())
+        .footer(CreateEmbedFooter::new(footer));
+
+    broadcast(&data, event, embed).await
+}
+
+async fn pledge_info(data: &EventTypeData) -> Result<PledgeInfo, anyhow::Error> {
+    match data {
+        EventTypeData::PatreonMemberData(member) => {
+            let previous = DatabaseManager::get_patreon_member(&member.id).await?;
+            Ok(PledgeInfo {
+                name: member.attributes.full_name.clone(),
+                amount_cents: member.attributes.currently_entitled_amount_cents,
+                previous,
+            })
+        }
+        EventTypeData::PatreonPledgeData(pledge) => {
+            let previous = match pledge.patron_id() {
+                Some(user_id) => DatabaseManager::get_patreon_member_by_user(user_id).await?,
+                None => None,
+            };
+            Ok(PledgeInfo {
+                name: previous
+                    .as_ref()
+                    .map(|previous| previous.patreon_username.clone())
+                    .unwrap_or_else(|| format!("pledge {}", pledge.id)),
+                amount_cents: pledge.attributes.amount_cents,
+                previous,
+            })
+        }
+        EventTypeData::PatreonMemberChange(_) => {
+            Err(anyhow::anyhow!("Pledge events carry member or pledge data"))
+        }
+    }
+}
+
+fn format_cents(cents: i64) -> String {
+    format!("${}.{:02}", cents / 100, cents % 100)
+}
@@ -0,0 +1,224 @@
+use std::collections::HashSet;
+
+use deffy_bot_macro::event_handle;
+use deffy_bot_utils::database::{DatabaseManager, DiscordServerDatabaseManager, PatreonUserData};
+use deffy_bot_utils::event::manager::EventType::{
+    PatreonSyncChargeDeclined, PatreonSyncMemberJoined, PatreonSyncMemberLeft,
+    PatreonSyncMemberStatusChanged, PatreonSyncMemberTierChanged, PatreonWebhookMemberPledgeCreated,
+    PatreonWebhookMemberPledgeDeleted, PatreonWebhookMemberPledgeUpdated,
+    PatreonWebhookUserCreated, PatreonWebhookUserDeleted, PatreonWebhookUserUpdated,
+};
+use deffy_bot_utils::event::manager::{EventInfo, EventTypeData};
+use deffy_bot_utils::grace_period::{GracePeriod, RevocationReason, recovered};
+use deffy_bot_utils::guild_settings::GuildSettings;
+use deffy_bot_utils::member_binding::MemberBindingDatabase;
+use deffy_bot_utils::tier_roles::RolePlan;
+use serenity::all::{
+    ChannelId, Colour, CreateEmbed, CreateEmbedFooter, CreateMessage, GuildId, Http, RoleId,
+    Timestamp, UserId,
+};
+
+use crate::event::start_event::BOT_HTTP;
+
+#[event_handle(e = PatreonWebhookUserCreated)]
+pub async fn sync_roles_on_member_created(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, false).await
+}
+
+#[event_handle(e = PatreonWebhookUserUpdated)]
+pub async fn sync_roles_on_member_updated(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, false).await
+}
+
+#[event_handle(e = PatreonWebhookUserDeleted)]
+pub async fn sync_roles_on_member_deleted(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, true).await
+}
+
+#[event_handle(e = PatreonWebhookMemberPledgeCreated)]
+pub async fn sync_roles_on_member_pledge_created(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, false).await
+}
+
+#[event_handle(e = PatreonWebhookMemberPledgeUpdated)]
+pub async fn sync_roles_on_member_pledge_updated(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, false).await
+}
+
+#[event_handle(e = PatreonWebhookMemberPledgeDeleted)]
+pub async fn sync_roles_on_member_pledge_deleted(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, false).await
+}
+
+#[event_handle(e = PatreonSyncMemberJoined)]
+pub async fn sync_roles_on_sync_joined(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, false).await
+}
+
+#[event_handle(e = PatreonSyncMemberLeft)]
+pub async fn sync_roles_on_sync_left(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, true).await
+}
+
+#[event_handle(e = PatreonSyncMemberStatusChanged)]
+pub async fn sync_roles_on_sync_status_changed(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, false).await
+}
+
+#[event_handle(e = PatreonSyncMemberTierChanged)]
+pub async fn sync_roles_on_sync_tier_changed(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, false).await
+}
+
+#[event_handle(e = PatreonSyncChargeDeclined)]
+pub async fn sync_roles_on_sync_charge_declined(data: EventTypeData) -> Result<(), anyhow::Error> {
+    sync_roles_from_event(&data, false).await
+}
+
+/// `left` when the member is no longer part of the campaign, whatever the payload says.
+async fn sync_roles_from_event(data: &EventTypeData, left: bool) -> Result<(), anyhow::Error> {
+    // Webhooks are handled before the sync stores the member, so the stored one is the previous state
+    let (member_id, previous, member, source) = match data {
+        EventTypeData::PatreonMemberData(member) => (
+            member.id.clone(),
+            DatabaseManager::get_patreon_member(&member.id).await?,
+            Some(PatreonUserData::from_member(member)),
+            "webhook",
+        ),
+        EventTypeData::PatreonMemberChange(change) => (
+            change.member_id.clone(),
+            change.previous.clone(),
+            change.current.clone(),
+            "sync",
+        ),
+        // Pledge payloads don't carry tiers, the member events that come with them do
+        EventTypeData::PatreonPledgeData(_) => return Ok(()),
+    };
+    let member = member.filter(|_| !left);
+
+    // Lapsed members keep their roles until the grace period ends, see `grace_period`
+    if let Some(reason) = RevocationReason::of(member.as_ref()) {
+        GracePeriod::start(&member_id, data.campaign_id().as_deref(), reason).await?;
+        return Ok(());
+    }
+
+    let mut restore_verify_in = Vec::new();
+    if member.as_ref().is_some_and(|member| recovered(previous.as_ref(), member)) {
+        if let Some(revocation) = GracePeriod::cancel(&member_id).await? {
+            restore_verify_in = revocation.verify_role_revoked_in;
+        }
+    }
+
+    sync_member_roles(
+        &member_id,
+        member.as_ref(),
+        data.campaign_id().as_deref(),
+        &restore_verify_in,
+        source,
+    )
+    .await
+}
+
+/// Brings the tier roles of the Discord account bound to `member_id` in line with the
+/// membership, in every server following the campaign. Members nobody verified as are skipped.
+/// The verify role is given back in `restore_verify_in`, the servers a finished grace period
+/// took it from.
+pub async fn sync_member_roles(
+    member_id: &str,
+    member: Option<&PatreonUserData>,
+    campaign_id: Option<&str>,
+    restore_verify_in: &[u64],
+    source: &str,
+) -> Result<(), anyhow::Error> {
+    let Some(binding) = MemberBindingDatabase::get_by_member_id(member_id).await? else {
+        return Ok(());
+    };
+
+    let http = BOT_HTTP.get().expect("BOT_HTTP not initialized");
+    let user_id = UserId::new(binding.discord_id);
+
+    let servers = DiscordServerDatabaseManager::get_campaign_servers(campaign_id).await?;
+    for server in servers.iter() {
+        let restore_verify = restore_verify_in.contains(&server.server_id);
+        if server.tier_roles.is_empty() && !restore_verify {
+            continue;
+        }
+
+        if let Err(e) = apply_role_plan(http, server, user_id, member, restore_verify, source).await {
+            tracing::error!(
+                "Failed to sync roles of {} in server {}: {}",
+                user_id,
+                server.server_id,
+                e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply_role_plan(
+    http: &Http,
+    settings: &GuildSettings,
+    user_id: UserId,
+    member: Option<&PatreonUserData>,
+    restore_verify: bool,
+    source: &str,
+) -> Result<(), anyhow::Error> {
+    let guild_id = GuildId::new(settings.server_id);
+
+    let Ok(discord_member) = http.get_member(guild_id, user_id).await else {
+        // Not in this server
+        return Ok(());
+    };
+
+    let held: HashSet<u64> = discord_member.roles.iter().map(|role_id| role_id.get()).collect();
+    let mut plan = RolePlan::between(settings, member, &held);
+    let verify_role = settings
+        .verify_role_id
+        .filter(|role_id| restore_verify && member.is_some() && !held.contains(role_id));
+    plan.add.extend(verify_role);
+    if plan.is_empty() {
+        return Ok(());
+    }
+
+    let reason = format!("Patreon membership changed ({})", source);
+    for role_id in &plan.add {
+        http.add_member_role(guild_id, user_id, RoleId::new(*role_id), Some(&reason))
+            .await?;
+    }
+    for role_id in &plan.remove {
+        http.remove_member_role(guild_id, user_id, RoleId::new(*role_id), Some(&reason))
+            .await?;
+    }
+
+    if let Some(channel_id) = settings.log_channel_id {
+        let changes = plan
+            .add
+            .iter()
+            .map(|role_id| format!("➕ <@&{}>", role_id))
+            .chain(plan.remove.iter().map(|role_id| format!("➖ <@&{}>", role_id)))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let embed = CreateEmbed::default()
+            .title("🎭 Roles Updated")
+            .description(format!(
+                "*User <@{}>*\n*{}*\n{}",
+                user_id,
+                member
+                    .map(|member| member.patreon_username.as_str())
+                    .unwrap_or("left the campaign"),
+                changes
+            ))
+            .color(Colour::new(0x53d0b1))
+            .timestamp(Timestamp::now())
+            .footer(CreateEmbedFooter::new(format!("Updated by {}", source)));
+
+        ChannelId::new(channel_id)
+            .send_message(http, CreateMessage::new().embed(embed))
+            .await?;
+    }
+
+    Ok(())
+}
@@ -28,6 +28,14 @@ async fn on_message(ctx: Context, data: EventData) -> Result<(), anyhow::Error>
                 btn.create_response(&ctx.http, modal).await?;
             }
 
+            if btn.data.custom_id == "btn:verify:code" {
+                let modal = ModalBuilder::new("verify_patreon_code", "Enter your code")
+                    .add_text_input("code", "Code", InputTextStyle::Short)
+                    .build();
+
+                btn.create_response(&ctx.http, modal).await?;
+            }
+
             if btn.data.custom_id == "btn:tutorial:verify" {
 
                 if let Err(_) = response_cooldown(&btn, &ctx).await {
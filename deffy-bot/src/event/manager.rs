@@ -11,6 +11,7 @@ pub enum EventData {
     Ready(serenity::model::prelude::Ready),
     Interaction(serenity::model::prelude::Interaction),
     Message(serenity::model::prelude::Message),
+    GuildCreate(serenity::model::prelude::Guild, Option<bool>),
 }
 
 #[async_trait]
@@ -59,6 +60,16 @@ impl serenity::prelude::EventHandler for MasterHandler {
         }
     }
 
+    async fn guild_create(&self, ctx: Context, guild: serenity::model::prelude::Guild, is_new: Option<bool>) {
+        if let Err(e) = self
+            .tx
+            .send(("guild_create".into(), ctx, EventData::GuildCreate(guild, is_new)))
+            .await
+        {
+            tracing::error!("Send error: {}", e);
+        }
+    }
+
     async fn interaction_create(&self, ctx: Context, data: serenity::model::prelude::Interaction) {
         if let Err(e) = self
             .tx
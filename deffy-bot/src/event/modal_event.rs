@@ -2,102 +2,63 @@ use deffy_bot_localization::tr;
 use deffy_bot_macro::event;
 use deffy_bot_utils::{
     builder_utils::ModalBuilder,
-    database::{DiscordServerDatabaseManager, PatreonVerification},
+    database::{
+        DatabaseManager, DiscordServerDatabaseManager, PatreonVerification, VerificationOutcome,
+    },
+    email_verification::{CodeCheck, EmailVerification},
 };
 use serenity::all::{
-    ChannelId, Colour, Context, CreateEmbed, CreateEmbedFooter, CreateInteractionResponse,
-    CreateInteractionResponseMessage, CreateMessage,
-    ModalInteraction, RoleId, Timestamp,
+    ButtonStyle, ChannelId, Colour, Context, CreateActionRow, CreateButton, CreateEmbed,
+    CreateEmbedFooter, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage,
+    EditInteractionResponse, GuildId, ModalInteraction, RoleId, Timestamp,
 };
 
-use crate::event::manager::EventData;
+use crate::event::{api::role_event::sync_member_roles, manager::EventData};
 
 #[event(e = interaction_create)]
 async fn on_message(ctx: Context, data: EventData) -> Result<(), anyhow::Error> {
     if let EventData::Interaction(interaction) = data {
         if let Some(modal) = &interaction.modal_submit() {
+            // Every submission gets a reply, an unanswered modal shows the user an error.
+            // Deferred first, the lookups and the mail can take longer than Discord waits.
             match modal.data.custom_id.as_str() {
                 "verify_patreon" => {
-                    // Collect all input text values from the modal
-                    let input_values = ModalBuilder::extract_modal_inputs(modal);
-
-                    tracing::debug!("{:?}", input_values);
-
-                    let patreon_email = input_values
-                        .iter()
-                        .find(|(key, _)| key == "email")
-                        .map(|(_, value)| value.clone())
-                        .unwrap_or_default();
-
-                    let patreon_verification = PatreonVerification::new(patreon_email.clone());
-                    let is_verified = patreon_verification
-                        .verify()
-                        .await;
-
-                    if let Err(e) = &is_verified {
-                        if e.to_string().contains("409") {
-                            send_modal_response("verify_already_active_member_error", &ctx, &modal)
-                                .await?;
-                        }
-                    }
+                    defer_response(&ctx, modal).await?;
+                    let patreon_email = modal_input(modal, "email");
 
-                    if let Ok(verify) = &is_verified {
-                        // Add roles
-
-                        let email = verify.1;
-
-                        if verify.0 {
-                            let role_id = DiscordServerDatabaseManager::get_verify_roles().await;
-
-                            if let Some(role_id) = role_id {
-                                let role_id = RoleId::new(role_id);
-
-                                if let Ok(has_role) = modal
-                                    .user
-                                    .has_role(&ctx.http, modal.guild_id.unwrap(), role_id)
-                                    .await
-                                {
-                                    if !has_role {
-                                        modal
-                                            .member
-                                            .as_ref()
-                                            .unwrap()
-                                            .add_role(&ctx.http, role_id)
-                                            .await?;
-                                        let embed = CreateEmbed::default()
-                                            .title("✅ User Verified")
-                                            .description(format!(
-                                                "*User {} has been verified*\n*{}*",
-                                                &modal.user.name,
-                                                &email,
-                                                ))
-                                            .color(Colour::new(0x00ff04))
-                                            .timestamp(Timestamp::now())
-                                            .thumbnail(modal.user.avatar_url().unwrap())
-                                            .footer(CreateEmbedFooter::new("Verify date"));
-
-                                        if let Some(channel_id) =
-                                            DiscordServerDatabaseManager::get_logging_channel()
-                                                .await
-                                        {
-                                            let channel_id = ChannelId::new(channel_id);
-                                            channel_id
-                                                .send_message(
-                                                    &ctx.http,
-                                                    CreateMessage::new().embed(embed),
-                                                )
-                                                .await?;
-
-                                            let response = CreateInteractionResponse::Acknowledge;
-
-                                            modal.create_response(&ctx.http, response).await?;
-                                        }
-                                    }
-                                }
-                            }
+                    let outcome = submit_email(&ctx, modal, patreon_email.trim()).await;
+                    send_outcome_response(outcome, &ctx, modal).await?;
+                }
+                "verify_patreon_code" => {
+                    defer_response(&ctx, modal).await?;
+                    let code = modal_input(modal, "code");
+
+                    match EmailVerification::check_code(modal.user.id.get(), &code).await {
+                        Ok(CodeCheck::Verified { email, guild_id }) => {
+                            let outcome = confirm_email(&ctx, modal, &email, GuildId::new(guild_id)).await;
+                            send_outcome_response(outcome, &ctx, modal).await?;
+                        }
+                        Ok(CodeCheck::Wrong { attempts_left }) => {
+                            let content = format!(
+                                "{} {}",
+                                tr!(&modal.locale, "verify_code_wrong"),
+                                attempts_left
+                            );
+                            send_modal_message(content, &ctx, modal).await?;
+                        }
+                        Ok(CodeCheck::Expired) => {
+                            send_modal_response("verify_code_expired", &ctx, modal).await?;
+                        }
+                        Ok(CodeCheck::TooManyAttempts) => {
+                            send_modal_response("verify_code_too_many_attempts", &ctx, modal).await?;
+                        }
+                        Ok(CodeCheck::NotRequested) => {
+                            send_modal_response("verify_code_not_requested", &ctx, modal).await?;
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to check verification code: {}", e);
+                            send_modal_response("verify_failed", &ctx, modal).await?;
                         }
-                    } else {
-                        send_modal_response("404_db_error", &ctx, &modal).await?;
                     }
                 }
                 _ => {
@@ -109,6 +70,165 @@ async fn on_message(ctx: Context, data: EventData) -> Result<(), anyhow::Error>
     Ok(())
 }
 
+/// First step of the verification, mails the code when the email can be used.
+async fn submit_email(
+    ctx: &Context,
+    modal: &ModalInteraction,
+    email: &str,
+) -> Result<VerificationOutcome, anyhow::Error> {
+    let guild_id = modal
+        .guild_id
+        .ok_or_else(|| anyhow::anyhow!("Verify modal submitted outside a server"))?;
+
+    let outcome = PatreonVerification::new(email.to_string())
+        .verify(modal.user.id.get(), guild_id.get())
+        .await?;
+
+    if outcome == VerificationOutcome::AlreadyVerified {
+        // Ownership was proven before, give back the roles lost since
+        grant_roles(ctx, modal, guild_id, email).await?;
+    }
+
+    Ok(outcome)
+}
+
+/// Second step, the code mailed to `email` came back.
+async fn confirm_email(
+    ctx: &Context,
+    modal: &ModalInteraction,
+    email: &str,
+    guild_id: GuildId,
+) -> Result<VerificationOutcome, anyhow::Error> {
+    let outcome = PatreonVerification::new(email.to_string())
+        .confirm(modal.user.id.get(), guild_id.get(), Some(modal.locale.clone()))
+        .await?;
+
+    if matches!(
+        outcome,
+        VerificationOutcome::Verified | VerificationOutcome::AlreadyVerified
+    ) {
+        grant_roles(ctx, modal, guild_id, email).await?;
+    }
+
+    Ok(outcome)
+}
+
+async fn send_outcome_response(
+    outcome: Result<VerificationOutcome, anyhow::Error>,
+    ctx: &Context,
+    modal: &ModalInteraction,
+) -> Result<(), anyhow::Error> {
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            tracing::error!("Verification of {} failed: {}", modal.user.id, e);
+            return send_modal_response("verify_failed", ctx, modal).await;
+        }
+    };
+
+    let msg = tr!(&modal.locale, outcome.message_key());
+
+    match outcome {
+        VerificationOutcome::CodeSent => {
+            // A modal can't be opened from a modal submission, the button opens the code one
+            let button = CreateButton::new("btn:verify:code")
+                .label(tr!(&modal.locale, "verify_code_button"))
+                .style(ButtonStyle::Primary);
+
+            let response = EditInteractionResponse::new()
+                .content(format!("```{}```", msg))
+                .components(vec![CreateActionRow::Buttons(vec![button])]);
+            modal.edit_response(&ctx.http, response).await?;
+            Ok(())
+        }
+        VerificationOutcome::RateLimited { retry_after } => {
+            let content = format!("{} {}s", msg, retry_after.as_secs().max(1));
+            send_modal_message(content, ctx, modal).await
+        }
+        _ => send_modal_message(msg, ctx, modal).await,
+    }
+}
+
+fn modal_input(modal: &ModalInteraction, key: &str) -> String {
+    // Not logged, the inputs hold emails and verification codes
+    let input_values = ModalBuilder::extract_modal_inputs(modal);
+
+    input_values
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.clone())
+        .unwrap_or_default()
+}
+
+async fn grant_roles(
+    ctx: &Context,
+    modal: &ModalInteraction,
+    guild_id: GuildId,
+    email: &str,
+) -> Result<(), anyhow::Error> {
+    grant_verify_role(ctx, modal, guild_id, email).await?;
+
+    // The account is linked now, so it gets its tier roles right away
+    if let Some(patron) = DatabaseManager::get_patreon_member_by_email(email).await? {
+        if let Some(member_id) = &patron.patreon_member_id {
+            sync_member_roles(member_id, Some(&patron), None, &[], "verification").await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn grant_verify_role(
+    ctx: &Context,
+    modal: &ModalInteraction,
+    guild_id: GuildId,
+    email: &str,
+) -> Result<(), anyhow::Error> {
+    let Some(role_id) = DiscordServerDatabaseManager::get_verify_roles(guild_id.get()).await else {
+        return Ok(());
+    };
+    let role_id = RoleId::new(role_id);
+
+    if modal.user.has_role(&ctx.http, guild_id, role_id).await? {
+        return Ok(());
+    }
+
+    ctx.http
+        .add_member_role(guild_id, modal.user.id, role_id, Some("Patreon email verified"))
+        .await?;
+
+    let embed = CreateEmbed::default()
+        .title("✅ User Verified")
+        .description(format!(
+            "*User {} has been verified*\n*{}*",
+            &modal.user.name,
+            email,
+        ))
+        .color(Colour::new(0x00ff04))
+        .timestamp(Timestamp::now())
+        .thumbnail(modal.user.face())
+        .footer(CreateEmbedFooter::new("Verify date"));
+
+    if let Some(channel_id) = DiscordServerDatabaseManager::get_logging_channel(guild_id.get()).await {
+        ChannelId::new(channel_id)
+            .send_message(&ctx.http, CreateMessage::new().embed(embed))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Acknowledges the submission, the replies then edit this response.
+async fn defer_response(ctx: &Context, modal: &ModalInteraction) -> Result<(), anyhow::Error> {
+    modal
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Defer(CreateInteractionResponseMessage::new().ephemeral(true)),
+        )
+        .await?;
+    Ok(())
+}
+
 async fn send_modal_response(
     msg_code: &str,
     ctx: &Context,
@@ -116,12 +236,16 @@ async fn send_modal_response(
 ) -> Result<(), anyhow::Error> {
     let msg = tr!(&modal.locale, msg_code);
 
-    let response = CreateInteractionResponse::Message(
-        CreateInteractionResponseMessage::new()
-            .content(format!("```{}```", msg))
-            .ephemeral(true),
-    );
-    modal.create_response(&ctx.http, response).await?;
+    send_modal_message(msg, ctx, modal).await
+}
+
+async fn send_modal_message(
+    msg: String,
+    ctx: &Context,
+    modal: &ModalInteraction,
+) -> Result<(), anyhow::Error> {
+    let response = EditInteractionResponse::new().content(format!("```{}```", msg));
+    modal.edit_response(&ctx.http, response).await?;
 
     Ok(())
 }
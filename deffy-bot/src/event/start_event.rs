@@ -1,26 +1,23 @@
 use deffy_bot_macro::event;
 use once_cell::sync::OnceCell;
-use serenity::all::{Context, GuildId, Http};
-use std::{collections::HashMap, env, sync::Arc};
+use serenity::all::{Context, CreateCommand, GuildId, Http};
+use std::{collections::HashMap, sync::Arc};
 use tokio::sync::{Mutex, mpsc};
 
 pub static BOT_HTTP: OnceCell<Arc<Http>> = OnceCell::new();
 
 use crate::{
     command::{handler::moderator_command::BanSession, system::manager::{spawn_command_worker, CommandJob, CommandManager}},
-    event::manager::EventData,
+    event::{api::grace_period::spawn_revocation_sweeper, manager::EventData},
 };
 
 pub static COMMAND_MANAGER: OnceCell<Arc<Mutex<CommandManager>>> = OnceCell::new();
 
 #[event(e = ready)]
-async fn on_ready(ctx: Context, _data: EventData) -> Result<(), Error> {
-    let guild_id = GuildId::new(
-        env::var("GUILD_ID")
-            .expect("Expected GUILD_ID in environment")
-            .parse()
-            .expect("GUILD_ID must be an integer"),
-    );
+async fn on_ready(ctx: Context, data: EventData) -> Result<(), Error> {
+    let EventData::Ready(ready) = data else {
+        return Ok(());
+    };
 
     let (tx, rx) = mpsc::channel::<CommandJob>(100);
 
@@ -38,14 +35,14 @@ async fn on_ready(ctx: Context, _data: EventData) -> Result<(), Error> {
         tracing::error!("Failed to set command manager");
     }
 
-    let commands = guild_id.set_commands(&ctx.http, commands).await;
-
-    match commands {
-        Ok(_) => tracing::trace!("Commands registered successfully"),
-        Err(e) => tracing::error!("Failed to register commands: {}", e),
+    for guild in &ready.guilds {
+        register_guild_commands(&ctx, guild.id, commands.clone()).await;
     }
 
-    BOT_HTTP.set(ctx.http.clone()).ok();
+    // Ready fires again on reconnects, the sweeper only has to start once
+    if BOT_HTTP.set(ctx.http.clone()).is_ok() {
+        spawn_revocation_sweeper(ctx.http.clone());
+    }
 
     {
         let mut data = ctx.data.write().await;
@@ -55,4 +52,33 @@ async fn on_ready(ctx: Context, _data: EventData) -> Result<(), Error> {
     tracing::info!("Logged in as {}", &ctx.cache.current_user().name);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Registers the commands in a server the bot joined after starting.
+#[event(e = guild_create)]
+async fn on_guild_create(ctx: Context, data: EventData) -> Result<(), Error> {
+    let EventData::GuildCreate(guild, is_new) = data else {
+        return Ok(());
+    };
+
+    // Servers known at startup were already handled in `on_ready`
+    if is_new != Some(true) {
+        return Ok(());
+    }
+
+    let Some(manager) = COMMAND_MANAGER.get() else {
+        return Ok(());
+    };
+    let commands = manager.lock().await.get_commands();
+
+    register_guild_commands(&ctx, guild.id, commands).await;
+
+    Ok(())
+}
+
+async fn register_guild_commands(ctx: &Context, guild_id: GuildId, commands: Vec<CreateCommand>) {
+    match guild_id.set_commands(&ctx.http, commands).await {
+        Ok(_) => tracing::trace!("Commands registered successfully in {}", guild_id),
+        Err(e) => tracing::error!("Failed to register commands in {}: {}", guild_id, e),
+    }
+}
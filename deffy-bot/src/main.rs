@@ -1,5 +1,9 @@
 use deffy_bot_http::http_init;
 use deffy_bot_utils::event::manager::EVENT_MANAGER;
+use deffy_bot_utils::migration::MigrationRunner;
+use deffy_bot_utils::repository::Repositories;
+use deffy_bot_utils::token_database::TokenDatabase;
+use deffy_bot_utils::webhook_database::WebhookInboxDatabase;
 use dotenv::dotenv;
 use std::env;
 
@@ -24,8 +28,10 @@ async fn main() {
         tracing::error!("Failed to initialize HTTP server: {:?}", e);
     }
 
+    // Every feature reads through the repositories, there is nothing to run without them
     if let Err(e) = init_database().await {
         tracing::error!("Failed to initialize database: {:?}", e);
+        return;
     }
 
     match init_discord_client().await {
@@ -69,23 +75,26 @@ fn init_logging() {
 
 async fn init_database() -> Result<(), anyhow::Error> {
     if cfg!(debug_assertions) {
-        tracing::info!("Database initialization skipped in debug mode");
+        Repositories::install(Repositories::in_memory());
+        tracing::info!("Using in-memory storage in debug mode, the Patreon sync is not started");
         return Ok(());
     }
 
-    let db = deffy_bot_utils::database::DatabaseManager::init_db().await;
+    let db = deffy_bot_utils::database::DatabaseManager::init_db().await?;
 
-    match db {
-        Ok(db) => {
-            if let Err(e) = db.start_collect().await {
-                tracing::error!("{:?}", e)
-            }
-        }
-        Err(err) => {
-            tracing::error!("Error connect with database {}", err)
-        }
+    // The collector and the worker rely on the indexes, don't run them on a half migrated database
+    let applied = MigrationRunner::run().await?;
+    if !applied.is_empty() {
+        tracing::info!("Applied migrations {:?}", applied);
     }
 
+    if let Err(e) = db.start_collect().await {
+        tracing::error!("{:?}", e)
+    }
+
+    WebhookInboxDatabase::start_worker();
+    TokenDatabase::start_refresh_task();
+
     Ok(())
 }
 